@@ -0,0 +1,370 @@
+use crate::filesystem::Filesystem;
+use crate::fs_trace;
+use crate::report::{Change, Outcome};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use log::trace;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+const BLOCK: usize = 512;
+
+/// A regular file or directory stored in a tar archive.
+pub struct Entry {
+    /// Relative to the directory the archive is extracted into
+    pub path: PathBuf,
+    pub mode: u32,
+    /// None for directories
+    pub contents: Option<Vec<u8>>,
+}
+
+pub struct Archive;
+
+impl Archive {
+    /// Reads the entries of a tar archive, gzip compressed or not. Only
+    /// regular files and directories are kept, links and other special
+    /// entries are skipped.
+    pub fn entries(path: &Path) -> Result<Vec<Entry>> {
+        let raw = fs::read(path).with_context(|| format!("reading archive {path:?}"))?;
+        let data = if raw.starts_with(&[0x1f, 0x8b]) {
+            let mut data = vec![];
+            GzDecoder::new(raw.as_slice())
+                .read_to_end(&mut data)
+                .context("decompress archive")?;
+            data
+        } else {
+            raw
+        };
+        parse(&data).with_context(|| format!("reading archive {path:?}"))
+    }
+
+    /// Extracts the files of the archive at `from` below `to`. Files that
+    /// differ from the archived ones are only overwritten when `force` is set.
+    /// Nothing is written through a symlinked directory below `to`.
+    pub fn extract(from: &Path, to: &Path, force: bool) -> Result<Vec<(PathBuf, Change)>> {
+        let mut changes = vec![];
+        for entry in Self::entries(from)? {
+            ensure_no_symlinked_parent(to, &entry.path)?;
+            let target = to.join(&entry.path);
+            let Some(contents) = entry.contents else {
+                fs_trace::create_dir_all(&target).context("create directory")?;
+                continue;
+            };
+            let exists = target.exists() || target.is_symlink();
+            let differs = "target differs from archived file";
+            let change = match fs::read(&target) {
+                _ if !exists => Change::new("target missing", Outcome::Created),
+                Ok(current) if current == contents => {
+                    trace!("{target:?} equals the archived file");
+                    let change = Change::new("target equals archived file", Outcome::Skipped);
+                    changes.push((target, change));
+                    continue;
+                }
+                _ if force => Change::new(differs, Outcome::Updated),
+                _ => {
+                    let change = Change::new(differs, Outcome::Skipped).with_warning(format!(
+                        "{target:?} differs from the archived file, use --force to overwrite it"
+                    ));
+                    changes.push((target, change));
+                    continue;
+                }
+            };
+
+            fs_trace::create_dir_all(target.parent().unwrap()).context("create dir all")?;
+            if target.is_symlink() {
+                fs_trace::remove_file(&target).context("remove symlink")?;
+            } else if exists {
+                Filesystem::set_read_only(&target, false)?;
+            }
+            fs_trace::write(&target, contents).context("write extracted file")?;
+            fs::set_permissions(&target, fs::Permissions::from_mode(entry.mode))
+                .context("set permissions")?;
+            changes.push((target, change));
+        }
+
+        Ok(changes)
+    }
+}
+
+/// Fails when a directory between `to` and the entry at `path` below it is a
+/// symlink, as the entry would be written wherever that link points.
+fn ensure_no_symlinked_parent(to: &Path, path: &Path) -> Result<()> {
+    let mut dir = to.to_path_buf();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        let metadata = fs::symlink_metadata(&dir);
+        anyhow::ensure!(
+            !metadata.is_ok_and(|metadata| metadata.file_type().is_symlink()),
+            "{dir:?} is a symlink, not extracting {path:?} through it"
+        );
+    }
+    Ok(())
+}
+
+/// Reads the entries of an uncompressed ustar, GNU or PAX tar archive.
+fn parse(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut long_name = None;
+    let mut offset = 0;
+    while offset + BLOCK <= data.len() {
+        let header = &data[offset..offset + BLOCK];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let size = octal(&header[124..136]).context("entry size")? as usize;
+        let start = offset + BLOCK;
+        let body = data
+            .get(start..start + size)
+            .context("archive is truncated")?;
+        offset = start + size.div_ceil(BLOCK) * BLOCK;
+
+        let kind = header[156];
+        match kind {
+            // GNU stores names too long for the header in an entry of their own
+            b'L' => {
+                long_name = Some(text(body));
+                continue;
+            }
+            // and PAX in an extended header, along with other records
+            b'x' => {
+                if let Some(path) = pax_path(body)? {
+                    long_name = Some(path);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let name = long_name.take().unwrap_or_else(|| header_name(header));
+        let Some(path) = relative_path(&name)? else {
+            continue;
+        };
+        let mode = octal(&header[100..108]).context("entry mode")? as u32 & 0o7777;
+        let contents = match kind {
+            b'0' | 0 => Some(body.to_vec()),
+            b'5' => None,
+            kind => {
+                trace!("skipping archive entry {name} of type {}", kind as char);
+                continue;
+            }
+        };
+        entries.push(Entry {
+            path,
+            mode,
+            contents,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// The `path` record of a PAX extended header, if it has one. Records are
+/// `<length> <key>=<value>\n`, the length counting the whole record.
+fn pax_path(body: &[u8]) -> Result<Option<String>> {
+    let mut path = None;
+    let mut rest = body;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&byte| byte == b' ')
+            .context("PAX record without a length")?;
+        let length = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|&length| length > space)
+            .context("invalid PAX record length")?;
+        let record = rest
+            .get(space + 1..length)
+            .and_then(|record| record.strip_suffix(b"\n"))
+            .context("PAX record is truncated")?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        rest = &rest[length..];
+    }
+    Ok(path)
+}
+
+/// The entry's name, joined to the ustar prefix when there is one.
+fn header_name(header: &[u8]) -> String {
+    let name = text(&header[..100]);
+    // GNU archives use the prefix field for other things
+    if &header[257..263] != b"ustar\0" {
+        return name;
+    }
+    match text(&header[345..500]) {
+        prefix if prefix.is_empty() => name,
+        prefix => format!("{prefix}/{name}"),
+    }
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn octal(field: &[u8]) -> Result<u64> {
+    let digits = text(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).with_context(|| format!("invalid octal number {digits:?}"))
+}
+
+/// Checks an entry's name stays inside the directory it's extracted into,
+/// returning None for the directory itself.
+fn relative_path(name: &str) -> Result<Option<PathBuf>> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => anyhow::bail!("archive entry {name:?} points outside of the target"),
+        }
+    }
+    Ok((!path.as_os_str().is_empty()).then_some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    fn tarball(dir: &Path) -> Result<PathBuf> {
+        let contents = dir.join("contents");
+        fs::create_dir_all(contents.join("nested"))?;
+        fs::write(contents.join("top.conf"), "top")?;
+        fs::write(contents.join("nested").join("inner.conf"), "inner")?;
+        let archive = dir.join("configs.tar.gz");
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&contents)
+            .arg(".")
+            .status()?;
+        anyhow::ensure!(status.success(), "tar failed");
+        Ok(archive)
+    }
+
+    #[test]
+    fn should_extract_tarball_into_target() -> Result<()> {
+        let dir = TempDir::new("archive")?;
+        let archive = tarball(dir.path())?;
+        let target = dir.path().join("target");
+
+        let changes = Archive::extract(&archive, &target, false)?;
+
+        assert_eq!(changes.len(), 2);
+        assert!(changes
+            .iter()
+            .all(|(_, change)| change.outcome == Outcome::Created));
+        assert_eq!(fs::read_to_string(target.join("top.conf"))?, "top");
+        assert_eq!(
+            fs::read_to_string(target.join("nested").join("inner.conf"))?,
+            "inner"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_overwrite_extracted_files_when_forced() -> Result<()> {
+        let dir = TempDir::new("archive")?;
+        let archive = tarball(dir.path())?;
+        let target = dir.path().join("target");
+        fs::create_dir_all(&target)?;
+        fs::write(target.join("top.conf"), "local edits")?;
+
+        Archive::extract(&archive, &target, false)?;
+        assert_eq!(fs::read_to_string(target.join("top.conf"))?, "local edits");
+
+        let changes = Archive::extract(&archive, &target, true)?;
+        assert_eq!(fs::read_to_string(target.join("top.conf"))?, "top");
+        let outcome = |name: &str| {
+            changes
+                .iter()
+                .find(|(path, _)| path.ends_with(name))
+                .map(|(_, change)| change.outcome)
+        };
+        assert_eq!(outcome("top.conf"), Some(Outcome::Updated));
+        assert_eq!(outcome("inner.conf"), Some(Outcome::Skipped));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_long_names_from_pax_headers() -> Result<()> {
+        let dir = TempDir::new("archive")?;
+        let contents = dir.path().join("contents");
+        let long = PathBuf::from("a".repeat(60)).join("b".repeat(60));
+        fs::create_dir_all(contents.join(&long))?;
+        fs::write(contents.join(&long).join("file.conf"), "long")?;
+        let archive = dir.path().join("long.tar");
+        let status = Command::new("tar")
+            .arg("--format=pax")
+            .arg("-cf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&contents)
+            .arg(&long)
+            .status()?;
+        anyhow::ensure!(status.success(), "tar failed");
+
+        let entries = Archive::entries(&archive)?;
+
+        let file = entries
+            .iter()
+            .find(|entry| entry.contents.is_some())
+            .context("no file entry")?;
+        assert_eq!(file.path, long.join("file.conf"));
+        assert_eq!(file.contents.as_deref(), Some(&b"long"[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_pax_records() -> Result<()> {
+        assert_eq!(
+            pax_path(b"12 uid=1000\n14 path=a/b/c\n")?,
+            Some("a/b/c".to_owned())
+        );
+        assert_eq!(pax_path(b"12 uid=1000\n")?, None);
+        assert!(pax_path(b"99 path=a\n").is_err());
+        assert!(pax_path(b"path=a\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_extract_through_symlinked_directories() -> Result<()> {
+        let dir = TempDir::new("archive")?;
+        let archive = tarball(dir.path())?;
+        let target = dir.path().join("target");
+        let elsewhere = dir.path().join("elsewhere");
+        fs::create_dir_all(&target)?;
+        fs::create_dir_all(&elsewhere)?;
+        std::os::unix::fs::symlink(&elsewhere, target.join("nested"))?;
+
+        let error = Archive::extract(&archive, &target, true).unwrap_err();
+
+        assert!(error.to_string().contains("is a symlink"), "{error:#}");
+        assert!(!elsewhere.join("inner.conf").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_entries_outside_of_target() {
+        assert!(relative_path("../escape").is_err());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert_eq!(relative_path("./").unwrap(), None);
+        assert_eq!(relative_path("./a/b").unwrap(), Some(PathBuf::from("a/b")));
+    }
+}
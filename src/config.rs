@@ -1,17 +1,129 @@
+use crate::retry::RetryPolicy;
 use anyhow::{Context, Result};
-use log::trace;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use log::{trace, warn};
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeOwned, Error as _, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
 use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Destination {
+    Single(PathBuf),
+    Multiple(Vec<PathBuf>),
+}
+
+impl Destination {
+    pub fn paths(&self) -> &[PathBuf] {
+        match self {
+            Destination::Single(path) => std::slice::from_ref(path),
+            Destination::Multiple(paths) => paths,
+        }
+    }
+}
+
+impl Default for Destination {
+    fn default() -> Self {
+        Destination::Single(PathBuf::new())
+    }
+}
+
+impl From<PathBuf> for Destination {
+    fn from(path: PathBuf) -> Self {
+        Destination::Single(path)
+    }
+}
+
+impl From<&str> for Destination {
+    fn from(path: &str) -> Self {
+        Destination::Single(path.into())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
 pub struct TargetSpec {
-    pub to: PathBuf,
+    pub to: Destination,
     pub symlink: bool,
+    /// Overrides handlebars strict mode for this template
+    #[serde(default)]
+    pub strict: Option<bool>,
+    /// Symlinks each file of a source directory instead of the directory
+    #[serde(default)]
+    pub link_contents: bool,
+    /// Deploys through the privilege escalation command, for targets owned by root
+    #[serde(default)]
+    pub sudo: bool,
+    /// Renders the source byte-wise, keeping bytes that aren't valid UTF-8.
+    /// Each UTF-8 run is rendered on its own, so blocks can't span those bytes
+    #[serde(default)]
+    pub binary_template: bool,
+    /// Clears the write bits of copied and rendered targets
+    #[serde(default)]
+    pub read_only: bool,
+    /// User, by name or id, to own copied and rendered targets
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Group, by name or id, to own copied and rendered targets
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Condition rendered with the target's variables; the target is only
+    /// deployed when it holds
+    #[serde(default)]
+    pub when: Option<String>,
+    /// Extracts a tar archive, gzipped or not, into the target directory
+    #[serde(default)]
+    pub extract: bool,
+    /// Hard links the target to the source instead of symlinking or copying
+    /// it. Both have to be on the same filesystem
+    #[serde(default)]
+    pub hardlink: bool,
+    /// Decrypts the source with age into the target, as sources ending in
+    /// `.age` are
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Shell command run after the target's contents change
+    #[serde(default)]
+    pub on_change: Option<String>,
+    /// Targets of other files of the package to deploy before this one
+    #[serde(default)]
+    pub after: Vec<PathBuf>,
+    /// Globs of files left out when a directory's contents are deployed, on
+    /// top of [`DEFAULT_IGNORE`]
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Deploys the files [`DEFAULT_IGNORE`] would leave out
+    #[serde(default)]
+    pub no_default_ignore: bool,
+    /// Renders the names of a directory's files when linking its contents,
+    /// so it needs `link_contents`
+    #[serde(default)]
+    pub template_names: bool,
+    /// How many directory levels linking a directory's contents descends,
+    /// 1 being only the files right in it
+    #[serde(default)]
+    pub max_depth: Option<usize>,
 }
 
+impl TargetSpec {
+    /// Globs of files left out when deploying a directory's contents.
+    pub fn ignored(&self) -> Vec<String> {
+        let defaults = DEFAULT_IGNORE.iter().filter(|_| !self.no_default_ignore);
+        defaults
+            .map(|pattern| pattern.to_string())
+            .chain(self.ignore.iter().cloned())
+            .collect()
+    }
+}
+
+/// Repository only files never deployed from a directory's contents.
+pub const DEFAULT_IGNORE: &[&str] = &[".gitkeep", ".gitignore", ".git"];
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum FileTarget {
@@ -19,70 +131,633 @@ pub enum FileTarget {
     WithSpec(TargetSpec),
 }
 
-pub type Files = HashMap<PathBuf, FileTarget>;
-pub type Variables = HashMap<String, String>;
+impl FileTarget {
+    /// Every path this file gets deployed to.
+    pub fn targets(&self) -> &[PathBuf] {
+        match self {
+            FileTarget::Simple(to) => std::slice::from_ref(to),
+            FileTarget::WithSpec(spec) => spec.to.paths(),
+        }
+    }
+
+    /// Globs of files left out when deploying a directory's contents.
+    pub fn ignored(&self) -> Vec<String> {
+        match self {
+            FileTarget::Simple(_) => DEFAULT_IGNORE.iter().map(|p| p.to_string()).collect(),
+            FileTarget::WithSpec(spec) => spec.ignored(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, if it names one. JSON is
+    /// read as the YAML it also is.
+    fn detect(path: &Path) -> Option<ConfigFormat> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Some(ConfigFormat::Toml),
+            Some("yaml" | "yml" | "json") => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Sources and their targets, in the order they're declared and deployed.
+pub type Files = IndexMap<PathBuf, FileTarget>;
+/// Variable values are plain YAML values, so besides strings templates can
+/// iterate over lists and look into maps.
+pub type Variables = HashMap<String, Value>;
 
 #[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
-#[serde(deny_unknown_fields)]
+#[serde(from = "PackageSpec")]
 pub struct Package {
-    #[serde(default)]
+    /// Directory relative sources of this package are resolved against
+    pub root: Option<PathBuf>,
     pub depends: Vec<String>,
-    #[serde(default)]
+    /// Packages to deploy before this one when they're present, without
+    /// depending on them
+    pub after: Vec<String>,
+    /// Packages to deploy after this one when they're present
+    pub before: Vec<String>,
+    /// Either a map of sources to targets or a list of `from`/`to` entries
     pub files: Files,
-    #[serde(default)]
     pub variables: Variables,
+    /// Whether `files` was given as a list, so must deploy one by one
+    #[serde(skip)]
+    pub listed: bool,
+}
+
+/// A package as written in the config.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackageSpec {
+    #[serde(default)]
+    root: Option<PathBuf>,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    after: Vec<String>,
+    #[serde(default)]
+    before: Vec<String>,
+    #[serde(default)]
+    files: DeclaredFiles,
+    #[serde(default)]
+    variables: Variables,
+}
+
+impl From<PackageSpec> for Package {
+    fn from(spec: PackageSpec) -> Self {
+        Package {
+            root: spec.root,
+            depends: spec.depends,
+            after: spec.after,
+            before: spec.before,
+            files: spec.files.files,
+            variables: spec.variables,
+            listed: spec.files.listed,
+        }
+    }
+}
+
+/// Files in the order they're declared, as a map or a list.
+#[derive(Debug, Default)]
+struct DeclaredFiles {
+    files: Files,
+    listed: bool,
+}
+
+/// An entry of the list form of `files`: the source under `from` next to
+/// what a target would hold in the map form.
+#[derive(Deserialize)]
+struct FileEntry {
+    from: PathBuf,
+    #[serde(flatten)]
+    target: Mapping,
+}
+
+impl FileEntry {
+    fn into_file<E: serde::de::Error>(self) -> Result<(PathBuf, FileTarget), E> {
+        let target = match self.target.get("to") {
+            Some(Value::String(to)) if self.target.len() == 1 => FileTarget::Simple(to.into()),
+            _ => FileTarget::WithSpec(
+                serde_yaml::from_value(Value::Mapping(self.target)).map_err(E::custom)?,
+            ),
+        };
+        Ok((self.from, target))
+    }
+}
+
+/// Reads `files` as either a map or a list of entries, keeping their order.
+impl<'de> Deserialize<'de> for DeclaredFiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FilesVisitor;
+
+        impl<'de> Visitor<'de> for FilesVisitor {
+            type Value = DeclaredFiles;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of sources to targets or a list of from/to entries")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<DeclaredFiles, A::Error> {
+                let files = Files::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(DeclaredFiles {
+                    files,
+                    listed: false,
+                })
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DeclaredFiles, A::Error> {
+                let mut files = Files::new();
+                while let Some(entry) = seq.next_element::<FileEntry>()? {
+                    let (from, target) = entry.into_file()?;
+                    if files.contains_key(&from) {
+                        return Err(A::Error::custom(format!("source {from:?} is listed twice")));
+                    }
+                    files.insert(from, target);
+                }
+                Ok(DeclaredFiles {
+                    files,
+                    listed: true,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(FilesVisitor)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct InnerConfig {
     #[serde(flatten)]
     packages: HashMap<String, Package>,
+    /// Files that belong to no package, deployed as `DEFAULT_PACKAGE`
+    #[serde(default, skip_serializing)]
+    files: DeclaredFiles,
     #[serde(default)]
     variables: Variables,
+    #[serde(default)]
+    secret_variables: Vec<String>,
+    #[serde(default)]
+    template_extension: Option<String>,
+    #[serde(default)]
+    partials_dir: Option<PathBuf>,
+    #[serde(default)]
+    helpers: IndexMap<String, PathBuf>,
+    #[serde(default)]
+    defaults: Defaults,
 }
 
-#[derive(Debug)]
+/// Name of the package holding the top level `files`, which no other package
+/// can have since `files` is taken by them.
+pub const DEFAULT_PACKAGE: &str = "files";
+
+#[derive(Debug, Default)]
 pub struct Configuration {
     pub packages: HashMap<String, Package>,
     pub variables: Variables,
+    /// Names of variables that are never logged nor passed on to hooks
+    pub secret_variables: Vec<String>,
+    /// When set, sources are detected as templates by this extension instead
+    /// of by their contents, and it is stripped from their targets
+    pub template_extension: Option<String>,
+    /// Directory whose files are registered as handlebars partials, named
+    /// after their file stem
+    pub partials_dir: Option<PathBuf>,
+    /// Handlebars helpers backed by scripts, by name
+    pub helpers: IndexMap<String, PathBuf>,
+    pub defaults: Defaults,
+}
+
+/// How deploys treat targets in the way of what they deploy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Leave them alone and warn
+    #[default]
+    Skip,
+    /// Move them aside, or overwrite them, same as `--force`
+    Backup,
+}
+
+/// Options a config can set for every deploy of it, which the command line
+/// takes precedence over.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    #[serde(default)]
+    pub conflict_policy: Option<ConflictPolicy>,
+    #[serde(default)]
+    pub force: bool,
 }
 
 impl Configuration {
-    pub fn ordered_by_dependencies(&self) -> Vec<(String, Package)> {
+    /// Orders packages so each comes after the ones it depends on. Package
+    /// names may be namespaced as `namespace/package`; see `resolve_package`
+    /// for how `depends` entries refer to them.
+    pub fn ordered_by_dependencies(&self) -> Result<Vec<(String, Package)>> {
         let mut packages = self.packages.clone();
+        for (name, package) in packages.iter_mut() {
+            package.depends = package
+                .depends
+                .iter()
+                .map(|dep| self.resolve_package(name, dep))
+                .collect::<Result<_>>()?;
+        }
         let mut ordered = Vec::new();
 
         while !packages.is_empty() {
             let mut next = None;
             for (name, package) in &packages {
+                // ordering hints only hold back a package while the package
+                // they name is still waiting to be ordered; the top level
+                // files go before every package
+                let after_pending = package.after.iter().any(|a| packages.contains_key(a))
+                    || (name != DEFAULT_PACKAGE && packages.contains_key(DEFAULT_PACKAGE));
+                let before_pending = packages
+                    .iter()
+                    .any(|(other, o)| other != name && o.before.contains(name));
                 if package
                     .depends
                     .iter()
                     .all(|dep| ordered.iter().any(|(n, _)| n == dep))
+                    && !after_pending
+                    && !before_pending
                 {
                     next = Some((name.to_owned(), package.to_owned()));
                     break;
                 }
             }
-            let (name, package) = next.expect("circular dependency");
+            let (name, mut package) = next.ok_or_else(|| {
+                let mut names = packages.keys().collect::<Vec<_>>();
+                names.sort();
+                anyhow::anyhow!("circular dependency between packages {names:?}")
+            })?;
             packages.remove(&name);
+            package.files = ordered_files(&name, package.files)?;
             ordered.push((name, package));
         }
 
-        ordered
+        Ok(ordered)
     }
+
+    /// Limits how deep linking directory contents goes for every target that
+    /// doesn't set a `max_depth` of its own.
+    pub fn default_max_depth(&mut self, max_depth: usize) {
+        let specs = self
+            .packages
+            .values_mut()
+            .flat_map(|package| package.files.values_mut());
+        for target in specs {
+            if let FileTarget::WithSpec(spec) = target {
+                spec.max_depth.get_or_insert(max_depth);
+            }
+        }
+    }
+
+    /// Drops the named packages. Packages depending on a dropped one are
+    /// dropped along with it when `cascade` is set, and are an error otherwise.
+    pub fn exclude_packages(&mut self, names: &[String], cascade: bool) -> Result<()> {
+        let mut excluded = BTreeSet::new();
+        for name in names {
+            anyhow::ensure!(
+                self.packages.contains_key(name),
+                "can't exclude {name:?}, there's no such package"
+            );
+            excluded.insert(name.to_owned());
+        }
+        loop {
+            let mut dependents = vec![];
+            for (name, package) in &self.packages {
+                if excluded.contains(name) {
+                    continue;
+                }
+                for dep in &package.depends {
+                    let dep = self.resolve_package(name, dep)?;
+                    if excluded.contains(&dep) {
+                        dependents.push((name.to_owned(), dep));
+                    }
+                }
+            }
+            let Some((name, dep)) = dependents.first() else {
+                break;
+            };
+            anyhow::ensure!(
+                cascade,
+                "package {name:?} depends on excluded package {dep:?}, exclude it too or pass --exclude-dependents"
+            );
+            for (name, dep) in dependents {
+                warn!("excluding {name:?} too, it depends on excluded package {dep:?}");
+                excluded.insert(name);
+            }
+        }
+        self.packages.retain(|name, _| !excluded.contains(name));
+
+        Ok(())
+    }
+
+    /// Resolves a `depends` entry of package `from` to a package name. Exact
+    /// names win, then an unqualified name in `from`'s own namespace, then
+    /// the only package with that name in any namespace.
+    fn resolve_package(&self, from: &str, reference: &str) -> Result<String> {
+        if self.packages.contains_key(reference) {
+            return Ok(reference.to_owned());
+        }
+        anyhow::ensure!(
+            !reference.contains('/'),
+            "package {from} depends on unknown package {reference}"
+        );
+
+        if let Some((namespace, _)) = from.rsplit_once('/') {
+            let sibling = format!("{namespace}/{reference}");
+            if self.packages.contains_key(&sibling) {
+                return Ok(sibling);
+            }
+        }
+
+        let mut candidates = self
+            .packages
+            .keys()
+            .filter(|name| name.rsplit_once('/').is_some_and(|(_, n)| n == reference))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        match candidates.as_slice() {
+            [name] => Ok(name.to_string()),
+            [] => anyhow::bail!("package {from} depends on unknown package {reference}"),
+            _ => anyhow::bail!(
+                "package {from} depends on {reference}, which is ambiguous between {candidates:?}; qualify it as namespace/{reference}"
+            ),
+        }
+    }
+
+    /// Adds `data` to the variables, globally and in every package, without
+    /// replacing any the config already defines.
+    pub fn add_data(&mut self, data: &Variables) {
+        for variables in std::iter::once(&mut self.variables)
+            .chain(self.packages.values_mut().map(|p| &mut p.variables))
+        {
+            for (key, value) in data {
+                variables
+                    .entry(key.to_owned())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    /// Replaces variables, globally and in every package, with `overrides`.
+    pub fn override_variables(&mut self, overrides: &Variables) {
+        for variables in std::iter::once(&mut self.variables)
+            .chain(self.packages.values_mut().map(|p| &mut p.variables))
+        {
+            variables.extend(overrides.clone());
+        }
+    }
+}
+
+/// Prefix of environment variables that override config variables.
+pub const VARIABLE_ENV_PREFIX: &str = "PONTO_VAR_";
+
+/// Variables set through `PONTO_VAR_<KEY>` environment variables. `<KEY>`
+/// names the variable as written, or else the one variable of `known` that
+/// only differs from it in case, so `PONTO_VAR_EDITOR` sets `editor`.
+pub fn env_variables<'a>(
+    vars: impl IntoIterator<Item = (String, String)>,
+    known: impl IntoIterator<Item = &'a String>,
+) -> Variables {
+    let known = known.into_iter().collect::<BTreeSet<_>>();
+    vars.into_iter()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(VARIABLE_ENV_PREFIX)?;
+            if key.is_empty() {
+                return None;
+            }
+            let mut same_but_case = known.iter().filter(|name| name.eq_ignore_ascii_case(key));
+            let key = match (same_but_case.next(), same_but_case.next()) {
+                _ if known.contains(&key.to_owned()) => key.to_owned(),
+                (Some(name), None) => name.to_string(),
+                _ => key.to_owned(),
+            };
+            Some((key, value.into()))
+        })
+        .collect()
 }
 
-pub fn load_config(config_path: &Path) -> Result<Configuration> {
-    let config: InnerConfig = load_file(config_path)
-        .and_then(|c| c.ok_or_else(|| anyhow::anyhow!("config.yaml not found")))?;
+/// Loads the config, expanding `~` in paths to `home` when given instead of
+/// the process's home directory. A directory is loaded as fragments, see
+/// [`load_fragments`]. With `template` set, the raw text is first rendered as
+/// a handlebars template; see [`render_config`] for what it can use.
+pub fn load_config(
+    config_path: &Path,
+    format: Option<ConfigFormat>,
+    home: Option<&Path>,
+    template: bool,
+) -> Result<Configuration> {
+    effective_config(load_source(config_path, format, template)?, home)
+}
+
+/// Loads several configs like [`load_config`] and merges them in order, the
+/// way [`load_fragments`] merges a directory's files.
+pub fn load_configs(
+    config_paths: &[PathBuf],
+    format: Option<ConfigFormat>,
+    home: Option<&Path>,
+    template: bool,
+) -> Result<Configuration> {
+    if let [path] = config_paths {
+        return load_config(path, format, home, template);
+    }
+    let mut config = InnerConfig::default();
+    let mut declared_by = HashMap::new();
+    for path in config_paths {
+        let source = load_source(path, format, template)?;
+        merge_config(&mut config, source, path, &mut declared_by)?;
+    }
+
+    effective_config(config, home)
+}
+
+/// Re-writes the config file at `path` in its current canonical form, in the
+/// same format: settings left at their defaults are dropped and keys are
+/// sorted, except files, which keep their deploy order. Paths are kept as
+/// written, and simple targets are expanded into the spec they're short
+/// for. With `template` set, the rendered config is migrated.
+pub fn migrate_config(path: &Path, format: Option<ConfigFormat>, template: bool) -> Result<String> {
+    anyhow::ensure!(
+        !path.is_dir(),
+        "{path:?} is a config directory, migrate its files one by one"
+    );
+    let config = load_inner(path, format, template)?
+        .with_context(|| format!("config file {path:?} not found"))?;
+
+    let mut canonical = Mapping::new();
+    let mut insert = |key: &str, value: Value| {
+        if !is_unset(&value) {
+            canonical.insert(key.into(), value);
+        }
+    };
+    insert("variables", sorted_variables(&config.variables)?);
+    insert(
+        "secret_variables",
+        serde_yaml::to_value(config.secret_variables)?,
+    );
+    insert(
+        "template_extension",
+        serde_yaml::to_value(config.template_extension)?,
+    );
+    insert("partials_dir", serde_yaml::to_value(config.partials_dir)?);
+    insert("helpers", serde_yaml::to_value(config.helpers)?);
+    if config.defaults != Defaults::default() {
+        insert("defaults", serde_yaml::to_value(config.defaults)?);
+    }
+    insert(
+        "files",
+        canonical_files(&config.files.files, config.files.listed)?,
+    );
+    let mut packages = config.packages.into_iter().collect::<Vec<_>>();
+    packages.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, package) in packages {
+        let mut spec = Mapping::new();
+        for (key, value) in [
+            ("root", serde_yaml::to_value(package.root)?),
+            ("depends", serde_yaml::to_value(package.depends)?),
+            ("after", serde_yaml::to_value(package.after)?),
+            ("before", serde_yaml::to_value(package.before)?),
+            ("files", canonical_files(&package.files, package.listed)?),
+            ("variables", sorted_variables(&package.variables)?),
+        ] {
+            if !is_unset(&value) {
+                spec.insert(key.into(), value);
+            }
+        }
+        insert(&name, Value::Mapping(spec));
+    }
+
+    match format.or_else(|| ConfigFormat::detect(path)) {
+        Some(ConfigFormat::Toml) => toml::to_string(&canonical).context("serializing as TOML"),
+        _ => serde_yaml::to_string(&canonical).context("serializing as YAML"),
+    }
+}
+
+/// `files` with every spec holding only the settings that differ from their
+/// defaults, as a map or, when `listed`, a list.
+fn canonical_files(files: &Files, listed: bool) -> Result<Value> {
+    let defaults = match serde_yaml::to_value(TargetSpec::default())? {
+        Value::Mapping(defaults) => defaults,
+        _ => unreachable!("specs serialize to maps"),
+    };
+    let mut map = Mapping::new();
+    let mut list = vec![];
+    for (from, target) in files {
+        // simple targets are symlinked unless rendered, as are specs by default
+        let spec = match target {
+            FileTarget::Simple(to) => &TargetSpec {
+                to: to.clone().into(),
+                symlink: true,
+                ..Default::default()
+            },
+            FileTarget::WithSpec(spec) => spec,
+        };
+        let Value::Mapping(spec) = serde_yaml::to_value(spec)? else {
+            unreachable!("specs serialize to maps")
+        };
+        let mut settings = spec
+            .into_iter()
+            .filter(|(key, value)| {
+                key == "to" || key == "symlink" || defaults.get(key) != Some(value)
+            })
+            .collect::<Vec<_>>();
+        settings.sort_by_key(|(key, _)| (key != "to", key.as_str().map(str::to_owned)));
+        let mut spec = settings.into_iter().collect::<Mapping>();
+        match listed {
+            true => {
+                let mut entry = Mapping::new();
+                entry.insert("from".into(), serde_yaml::to_value(from)?);
+                entry.extend(std::mem::take(&mut spec));
+                list.push(Value::Mapping(entry));
+            }
+            false => {
+                map.insert(serde_yaml::to_value(from)?, Value::Mapping(spec));
+            }
+        }
+    }
+    Ok(match listed {
+        true => Value::Sequence(list),
+        false => Value::Mapping(map),
+    })
+}
+
+/// Whether a setting is null or empty, so can be left out.
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Sequence(seq) => seq.is_empty(),
+        Value::Mapping(map) => map.is_empty(),
+        _ => false,
+    }
+}
+
+/// `variables` with their names sorted.
+fn sorted_variables(variables: &Variables) -> Result<Value> {
+    let sorted = variables
+        .iter()
+        .collect::<std::collections::BTreeMap<_, _>>();
+    Ok(serde_yaml::to_value(sorted)?)
+}
+
+/// Loads a config file, or the fragments of a config directory.
+fn load_source(
+    config_path: &Path,
+    format: Option<ConfigFormat>,
+    template: bool,
+) -> Result<InnerConfig> {
+    if config_path.is_dir() {
+        return load_fragments(config_path, format, template);
+    }
+    load_inner(config_path, format, template)?
+        .with_context(|| format!("config file {config_path:?} not found"))
+}
+
+/// Resolves a config as loaded into the one deploys use, expanding `~` in
+/// paths to `home` when given.
+fn effective_config(config: InnerConfig, home: Option<&Path>) -> Result<Configuration> {
+    let mut packages = config.packages;
+    if !config.files.files.is_empty() {
+        let package = Package {
+            files: config.files.files,
+            listed: config.files.listed,
+            variables: config.variables.clone(),
+            ..Default::default()
+        };
+        packages.insert(DEFAULT_PACKAGE.to_owned(), package);
+    }
 
     // expand paths
-    let packages = config
-        .packages
+    let packages = packages
         .into_iter()
         .map(|(name, mut package)| -> Result<_, anyhow::Error> {
-            package.files = expand_paths(package.files)?;
+            for (from, target) in &package.files {
+                if let FileTarget::WithSpec(spec) = target {
+                    anyhow::ensure!(
+                        spec.link_contents || !spec.template_names,
+                        "{from:?} in package {name} sets template_names without link_contents, \
+                         names are only rendered when linking a directory's contents"
+                    );
+                }
+            }
+            package.files = expand_paths(package.files, home)?;
+            if let Some(root) = &package.root {
+                let root = expand_path(root, home)?;
+                package.files = package
+                    .files
+                    .into_iter()
+                    .map(|(from, to)| (root.join(from), to))
+                    .collect();
+            }
             Ok((name, package))
         })
         .collect::<Result<HashMap<_, _>, _>>()?;
@@ -96,23 +771,149 @@ pub fn load_config(config_path: &Path) -> Result<Configuration> {
         })
         .into_iter();
 
-    let variables = merge_variables(config.variables.into_iter(), package_variables);
+    let variables = merge_variables(config.variables.clone().into_iter(), package_variables);
+    // every target renders with the global variables, overlaid by its package's
+    let packages = packages
+        .into_iter()
+        .map(|(name, mut package)| {
+            let own = std::mem::take(&mut package.variables).into_iter();
+            package.variables = merge_variables(config.variables.clone().into_iter(), own);
+            (name, package)
+        })
+        .collect::<HashMap<_, _>>();
 
-    trace!("variables: {:?}", variables);
-    trace!("packages: {:?}", packages);
+    let secret_variables = config.secret_variables;
+    trace!("variables: {:?}", redact(&variables, &secret_variables));
+    trace!(
+        "packages: {:?}",
+        packages
+            .iter()
+            .map(|(name, package)| {
+                let variables = redact(&package.variables, &secret_variables);
+                (
+                    name,
+                    Package {
+                        variables,
+                        ..package.to_owned()
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>()
+    );
 
     let effective_config = Configuration {
         packages,
         variables,
+        secret_variables,
+        template_extension: config.template_extension,
+        partials_dir: config
+            .partials_dir
+            .map(|dir| expand_path(&dir, home))
+            .transpose()?,
+        helpers: config
+            .helpers
+            .into_iter()
+            .map(|(name, script)| Ok((name, expand_path(&script, home)?)))
+            .collect::<Result<_>>()?,
+        defaults: config.defaults,
     };
 
     Ok(effective_config)
 }
 
-pub fn load_file<T>(filename: &Path) -> Result<Option<T>>
+fn load_inner(
+    config_path: &Path,
+    format: Option<ConfigFormat>,
+    template: bool,
+) -> Result<Option<InnerConfig>> {
+    if !template {
+        return load_file(config_path, format);
+    }
+    read_file(config_path)?
+        .map(|contents| {
+            let rendered = render_config(&contents).context("templating config")?;
+            parse_file(&rendered, config_path, format)
+        })
+        .transpose()
+}
+
+/// Loads every `*.yaml`, `*.yml` and `*.toml` file in `dir`, sorted by name,
+/// and merges them into one config. A package or top level file may only be
+/// declared by one fragment; variables of later fragments win.
+fn load_fragments(dir: &Path, format: Option<ConfigFormat>, template: bool) -> Result<InnerConfig> {
+    let mut fragments = fs::read_dir(dir)
+        .with_context(|| format!("reading config directory {dir:?}"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    fragments.retain(|path| {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml" || ext == "toml")
+    });
+    fragments.sort();
+
+    let mut config = InnerConfig::default();
+    let mut declared_by = HashMap::new();
+    for path in fragments {
+        trace!("loading config fragment {path:?}");
+        let fragment = load_inner(&path, format, template)?
+            .with_context(|| format!("config fragment {path:?} vanished"))?;
+        merge_config(&mut config, fragment, &path, &mut declared_by)?;
+    }
+
+    Ok(config)
+}
+
+/// Merges the config loaded from `path` into `config`. A package or top
+/// level file may only be declared once; the later of other settings wins.
+fn merge_config(
+    config: &mut InnerConfig,
+    other: InnerConfig,
+    path: &Path,
+    declared_by: &mut HashMap<String, PathBuf>,
+) -> Result<()> {
+    for (name, package) in other.packages {
+        if let Some(first) = declared_by.insert(name.clone(), path.to_owned()) {
+            anyhow::bail!("package {name} is declared in both {first:?} and {path:?}");
+        }
+        config.packages.insert(name, package);
+    }
+    for (from, target) in other.files.files {
+        anyhow::ensure!(
+            !config.files.files.contains_key(&from),
+            "source {from:?} is declared again in {path:?}"
+        );
+        config.files.files.insert(from, target);
+    }
+    config.files.listed |= other.files.listed;
+    config.variables.extend(other.variables);
+    config.secret_variables.extend(other.secret_variables);
+    if other.template_extension.is_some() {
+        config.template_extension = other.template_extension;
+    }
+    if other.partials_dir.is_some() {
+        config.partials_dir = other.partials_dir;
+    }
+    config.helpers.extend(other.helpers);
+    config.defaults.force |= other.defaults.force;
+    if other.defaults.conflict_policy.is_some() {
+        config.defaults.conflict_policy = other.defaults.conflict_policy;
+    }
+
+    Ok(())
+}
+
+pub fn load_file<T>(filename: &Path, format: Option<ConfigFormat>) -> Result<Option<T>>
 where
     T: DeserializeOwned,
 {
+    read_file(filename)?
+        .map(|buf| parse_file(&buf, filename, format))
+        .transpose()
+}
+
+fn read_file(filename: &Path) -> Result<Option<String>> {
     let mut buf = String::new();
     let mut f = match File::open(filename) {
         Ok(f) => Ok(f),
@@ -121,67 +922,499 @@ where
     }
     .context("open file")?;
     f.read_to_string(&mut buf).context("read file")?;
-    let data = serde_yaml::from_str::<T>(&buf).context("deserialize file contents")?;
-    Ok(Some(data))
+    Ok(Some(buf))
 }
 
-fn expand_path(path: &Path) -> Result<PathBuf> {
-    let expanded = shellexpand::full(&path.to_string_lossy())?.to_string();
+fn parse_file<T>(buf: &str, filename: &Path, format: Option<ConfigFormat>) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let data = match format.or_else(|| ConfigFormat::detect(filename)) {
+        Some(ConfigFormat::Yaml) => {
+            serde_yaml::from_str::<T>(buf).context("deserialize file contents")?
+        }
+        Some(ConfigFormat::Toml) => {
+            toml::from_str::<T>(buf).context("deserialize file contents")?
+        }
+        // no telling from the name, so whichever parses
+        None => match serde_yaml::from_str::<T>(buf) {
+            Ok(data) => data,
+            Err(yaml) => toml::from_str::<T>(buf).map_err(|toml| {
+                anyhow::anyhow!("{filename:?} is neither YAML ({yaml}) nor TOML ({toml})")
+            })?,
+        },
+    };
+    Ok(data)
+}
+
+/// Renders the raw config text before it's parsed. Variables come from the
+/// config itself, so they can't be used here: templates only get `os`, `arch`,
+/// the environment as `env` and the usual helpers, `command_output` included.
+fn render_config(contents: &str) -> Result<String> {
+    let handlebars = crate::handlebars::init(RetryPolicy::default())?;
+    let context = serde_json::json!({
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "env": std::env::vars().collect::<HashMap<_, _>>(),
+    });
+    handlebars
+        .render_template(contents, &context)
+        .context("render config template")
+}
+
+/// Loads a YAML, JSON or TOML file whose top level keys become variables,
+/// keeping nested values so templates can reach e.g. `{{ git.user.name }}`.
+pub fn load_data(path: &Path) -> Result<Variables> {
+    load_file(path, None)
+        .and_then(|data| data.ok_or_else(|| anyhow::anyhow!("data file {path:?} not found")))
+        .with_context(|| format!("loading data file {path:?}"))
+}
+
+fn expand_path(path: &Path, home: Option<&Path>) -> Result<PathBuf> {
+    expand_path_in(path, home, &std::env::vars().collect())
+}
+
+/// [`expand_path`] with the environment given. `~` is `home`, falling back
+/// to `HOME` in `env`.
+fn expand_path_in(
+    path: &Path,
+    home: Option<&Path>,
+    env: &HashMap<String, String>,
+) -> Result<PathBuf> {
+    let path = expand_defaults(&path.to_string_lossy(), home, env)?;
+    let home = || match home {
+        Some(home) => Some(home.to_string_lossy().into_owned()),
+        None => env.get("HOME").cloned(),
+    };
+    let expanded = shellexpand::full_with_context(&path, home, |var| {
+        env.get(var).map(Some).ok_or(std::env::VarError::NotPresent)
+    })?
+    .to_string();
 
-    Ok(PathBuf::from(expanded))
+    // rebuilt from its components to drop trailing slashes, which would make
+    // a link at the path resolve to the directory it points at
+    Ok(Path::new(&expanded).components().collect())
 }
 
-fn expand_paths(files: Files) -> Result<Files> {
+/// Replaces each `${VAR:-default}` whose variable is unset or empty with its
+/// default, itself expanded, since shellexpand would insert it verbatim. Set
+/// variables are left as `${VAR}` for shellexpand.
+fn expand_defaults(
+    path: &str,
+    home: Option<&Path>,
+    env: &HashMap<String, String>,
+) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find("${") {
+        let inner = &rest[start + 2..];
+        let Some(end) = closing_brace(inner) else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        match inner[..end].split_once(":-") {
+            Some((name, default)) if !name.is_empty() => match env.get(name) {
+                Some(value) if !value.is_empty() => expanded.push_str(&format!("${{{name}}}")),
+                _ => expanded
+                    .push_str(&expand_path_in(Path::new(default), home, env)?.to_string_lossy()),
+            },
+            _ => expanded.push_str(&rest[start..start + 2 + end + 1]),
+        }
+        rest = &inner[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Finds the `}` closing a `${`, skipping nested braces.
+fn closing_brace(inner: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn expand_paths(files: Files, home: Option<&Path>) -> Result<Files> {
     files
         .into_iter()
         .map(|(k, v)| -> Result<_, anyhow::Error> {
             let updated_v = match v {
-                FileTarget::Simple(path) => FileTarget::Simple(expand_path(&path)?),
+                FileTarget::Simple(path) => FileTarget::Simple(expand_path(&path, home)?),
                 FileTarget::WithSpec(target) => {
-                    let expanded_to = expand_path(&target.to)?;
+                    let expanded_to = match &target.to {
+                        Destination::Single(to) => Destination::Single(expand_path(to, home)?),
+                        Destination::Multiple(to) => Destination::Multiple(
+                            to.iter()
+                                .map(|to| expand_path(to, home))
+                                .collect::<Result<_>>()?,
+                        ),
+                    };
+                    let after = target
+                        .after
+                        .iter()
+                        .map(|after| expand_path(after, home))
+                        .collect::<Result<_>>()?;
                     FileTarget::WithSpec(TargetSpec {
                         to: expanded_to,
-                        symlink: target.symlink,
+                        after,
+                        ..target
                     })
                 }
             };
 
-            Ok((k, updated_v))
+            // absolute sources are used verbatim, relative ones stay relative
+            // to the working directory
+            Ok((expand_path(&k, home)?, updated_v))
         })
         .collect()
 }
 
+/// Orders a package's files so each comes after the files deploying to the
+/// targets its `after` names, otherwise keeping their declared order.
+fn ordered_files(package: &str, mut files: Files) -> Result<Files> {
+    let waits_for = |target: &FileTarget| match target {
+        FileTarget::WithSpec(spec) => spec.after.clone(),
+        FileTarget::Simple(_) => vec![],
+    };
+    for (from, target) in &files {
+        for after in waits_for(target) {
+            anyhow::ensure!(
+                files.values().any(|other| other.targets().contains(&after)),
+                "{from:?} in package {package} waits for {after:?}, which none of its files deploys to"
+            );
+        }
+    }
+
+    let mut ordered = Files::new();
+    while !files.is_empty() {
+        let next = files.iter().position(|(_, target)| {
+            waits_for(target).iter().all(|after| {
+                !files
+                    .values()
+                    .any(|other| !std::ptr::eq(other, target) && other.targets().contains(after))
+            })
+        });
+        let Some(next) = next else {
+            let sources = files.keys().collect::<Vec<_>>();
+            anyhow::bail!("circular dependency between files {sources:?} in package {package}");
+        };
+        let (from, target) = files.shift_remove_index(next).expect("found above");
+        ordered.insert(from, target);
+    }
+
+    Ok(ordered)
+}
+
 fn merge_variables(
-    variables: impl Iterator<Item = (String, String)>,
-    package_variables: impl Iterator<Item = (String, String)>,
+    variables: impl Iterator<Item = (String, Value)>,
+    package_variables: impl Iterator<Item = (String, Value)>,
 ) -> Variables {
     variables.into_iter().chain(package_variables).collect()
 }
 
+/// Masks the values of secret variables so they can be safely logged.
+pub fn redact(variables: &Variables, secrets: &[String]) -> Variables {
+    variables
+        .iter()
+        .map(|(k, v)| {
+            if secrets.contains(k) {
+                (k.to_owned(), Value::from("***"))
+            } else {
+                (k.to_owned(), v.to_owned())
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::{collections::HashMap, fs::File, io::Write};
     use tempdir::TempDir;
 
+    #[test]
+    fn should_migrate_to_canonical_form() -> anyhow::Result<()> {
+        use super::{FileTarget, InnerConfig};
+
+        let dir = TempDir::new("config")?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            "bash:\n  files:\n    bash/.bashrc: ~/.bashrc\n    bash/env:\n      to: ~/.env\n      symlink: false\n      read_only: true\n  variables:\n    shell: bash\n    editor: vim\n",
+        )?;
+
+        let migrated = super::migrate_config(&path, None, false)?;
+
+        assert_eq!(
+            migrated,
+            "bash:\n  files:\n    bash/.bashrc:\n      to: ~/.bashrc\n      symlink: true\n    bash/env:\n      to: ~/.env\n      read_only: true\n      symlink: false\n  variables:\n    editor: vim\n    shell: bash\n"
+        );
+        let config: InnerConfig = serde_yaml::from_str(&migrated)?;
+        assert!(matches!(
+            &config.packages["bash"].files[std::path::Path::new("bash/.bashrc")],
+            FileTarget::WithSpec(spec) if spec.symlink
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_variables_from_environment() {
+        let known = ["editor", "XDG_DIR", "gitUser", "name", "NAME"].map(String::from);
+        let env = [
+            ("PONTO_VAR_EDITOR", "nvim"),
+            ("PONTO_VAR_XDG_DIR", "/xdg"),
+            ("PONTO_VAR_GITUSER", "me"),
+            ("PONTO_VAR_NAME", "laptop"),
+            ("PONTO_VAR_new_one", "1"),
+            ("PONTO_VAR_", "ignored"),
+            ("EDITOR", "vi"),
+        ]
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+
+        let variables = super::env_variables(env, &known);
+
+        let mut variables = variables
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str().unwrap()))
+            .collect::<Vec<_>>();
+        variables.sort();
+        assert_eq!(
+            variables,
+            [
+                ("NAME", "laptop"),
+                ("XDG_DIR", "/xdg"),
+                ("editor", "nvim"),
+                ("gitUser", "me"),
+                ("new_one", "1")
+            ]
+        );
+    }
+
     #[test]
     fn should_merge_variables() {
-        let variables = vec![("a".to_string(), "1".to_string())]
+        let variables = vec![("a".to_string(), "1".into())]
             .into_iter()
             .collect::<HashMap<_, _>>();
-        let package_variables = vec![("b".to_string(), "2".to_string())]
+        let package_variables = vec![("b".to_string(), "2".into())]
             .into_iter()
             .collect::<HashMap<_, _>>();
 
         let merged = super::merge_variables(variables.into_iter(), package_variables.into_iter());
 
-        let expected = vec![
-            ("a".to_string(), "1".to_string()),
-            ("b".to_string(), "2".to_string()),
+        let expected = vec![("a".to_string(), "1".into()), ("b".to_string(), "2".into())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn should_redact_secret_variables() {
+        let variables = vec![
+            ("user".to_string(), "me".into()),
+            ("token".to_string(), "hunter2".into()),
         ]
         .into_iter()
         .collect::<HashMap<_, _>>();
 
-        assert_eq!(merged, expected);
+        let redacted = super::redact(&variables, &["token".to_string()]);
+
+        assert_eq!(redacted["user"], "me");
+        assert_eq!(redacted["token"], "***");
+        assert!(!format!("{redacted:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn should_load_list_variables() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        File::create(&config_path)?.write_all(b"variables:\n  plugins: [fzf, zoxide]\n")?;
+
+        let config = super::load_config(&config_path, None, None, false)?;
+
+        assert_eq!(
+            config.variables["plugins"],
+            serde_yaml::Value::Sequence(vec!["fzf".into(), "zoxide".into()])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_load_files_in_declared_order() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        File::create(&config_path)?.write_all(
+            b"app:\n  files:\n    - from: zsh\n      to: .zshrc\n    - from: bash\n      to: [.bashrc, .profile]\n      symlink: false\n",
+        )?;
+
+        let config = super::load_config(&config_path, None, None, false)?;
+        let files = &config.packages["app"].files;
+        assert!(config.packages["app"].listed);
+
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [std::path::Path::new("zsh"), std::path::Path::new("bash")]
+        );
+        assert_eq!(files[0], super::FileTarget::Simple(".zshrc".into()));
+        assert_eq!(
+            files[1].targets(),
+            [std::path::PathBuf::from(".bashrc"), ".profile".into()]
+        );
+
+        File::create(&config_path)?
+            .write_all(b"app:\n  files:\n    - {from: a, to: b}\n    - {from: a, to: c}\n")?;
+        assert!(super::load_config(&config_path, None, None, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_order_files_after_the_ones_they_wait_for() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        let write = |files: &str| std::fs::write(&config_path, format!("app:\n  files:\n{files}"));
+        write(
+            "    profile:\n      to: /home/me/.profile\n      symlink: true\n      after: [/home/me/.includes]\n    \
+             includes: /home/me/.includes\n    zshrc: /home/me/.zshrc\n",
+        )?;
+
+        let config = super::load_config(&config_path, None, None, false)?;
+        let ordered = config.ordered_by_dependencies()?;
+
+        assert_eq!(
+            ordered[0].1.files.keys().collect::<Vec<_>>(),
+            [
+                std::path::Path::new("includes"),
+                std::path::Path::new("profile"),
+                std::path::Path::new("zshrc")
+            ]
+        );
+
+        write(
+            "    a:\n      to: /a\n      symlink: true\n      after: [/b]\n    \
+             b:\n      to: /b\n      symlink: true\n      after: [/a]\n",
+        )?;
+        let config = super::load_config(&config_path, None, None, false)?;
+        let error = config.ordered_by_dependencies().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("circular dependency between files"),
+            "{error}"
+        );
+
+        write("    a:\n      to: /a\n      symlink: true\n      after: [/missing]\n")?;
+        let config = super::load_config(&config_path, None, None, false)?;
+        let error = config.ordered_by_dependencies().unwrap_err();
+        assert!(
+            error.to_string().contains("none of its files deploys to"),
+            "{error}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_template_names_without_link_contents() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        File::create(&config_path)?.write_all(
+            b"ssh:\n  files:\n    ssh:\n      to: ~/.ssh\n      symlink: false\n      template_names: true\n",
+        )?;
+
+        let error = super::load_config(&config_path, None, None, false).unwrap_err();
+
+        assert!(
+            format!("{error:#}").contains("sets template_names without link_contents"),
+            "{error:#}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_expand_absolute_and_relative_sources() -> anyhow::Result<()> {
+        let home = std::env::var("HOME")?;
+        let files = vec![
+            (
+                "/etc/hosts".into(),
+                super::FileTarget::Simple("hosts".into()),
+            ),
+            (
+                "shell/.bashrc".into(),
+                super::FileTarget::Simple(".bashrc".into()),
+            ),
+            (
+                "~/.profile".into(),
+                super::FileTarget::Simple(".profile".into()),
+            ),
+        ]
+        .into_iter()
+        .collect::<super::Files>();
+
+        let expanded = super::expand_paths(files, None)?;
+
+        assert!(expanded.contains_key(std::path::Path::new("/etc/hosts")));
+        assert!(expanded.contains_key(std::path::Path::new("shell/.bashrc")));
+        assert!(expanded.contains_key(&std::path::Path::new(&home).join(".profile")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_expand_tilde_to_overridden_home() -> anyhow::Result<()> {
+        let home = std::path::Path::new("/sandbox/home");
+        let files = vec![(
+            "shell/.bashrc".into(),
+            super::FileTarget::Simple("~/.bashrc".into()),
+        )]
+        .into_iter()
+        .collect::<super::Files>();
+
+        let expanded = super::expand_paths(files, Some(home))?;
+
+        assert_eq!(
+            expanded[std::path::Path::new("shell/.bashrc")].targets(),
+            [home.join(".bashrc")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fall_back_to_default_of_unset_variables() -> anyhow::Result<()> {
+        let home = std::path::Path::new("/sandbox/home");
+        let env = [("PONTO_TEST_CONFIG_HOME", "/xdg"), ("PONTO_TEST_EMPTY", "")]
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        let expand =
+            |path: &str| super::expand_path_in(std::path::Path::new(path), Some(home), &env);
+
+        assert_eq!(
+            expand("${PONTO_TEST_CONFIG_HOME:-~/.config}/app")?,
+            std::path::Path::new("/xdg/app")
+        );
+        assert_eq!(
+            expand("${PONTO_TEST_UNSET:-~/.config}/app")?,
+            home.join(".config/app")
+        );
+        assert_eq!(
+            expand("${PONTO_TEST_EMPTY:-${PONTO_TEST_CONFIG_HOME}/fallback}/app")?,
+            std::path::Path::new("/xdg/fallback/app")
+        );
+        assert!(expand("${PONTO_TEST_UNSET}/app").is_err());
+        assert_eq!(expand("a/${b")?, std::path::Path::new("a/${b"));
+
+        Ok(())
     }
 
     #[test]
@@ -199,34 +1432,43 @@ mod tests {
         .to_string();
 
         let dir = TempDir::new("config")?;
-        let config_path = dir.path().join("config.toml");
+        let config_path = dir.path().join("config.yaml");
         let mut config = File::create(&config_path)?;
-        config.write(config_content.as_bytes())?;
+        config.write_all(config_content.as_bytes())?;
 
-        let config = super::load_config(&config_path).unwrap();
+        let config = super::load_config(&config_path, None, None, false).unwrap();
 
         let expected = super::Configuration {
+            secret_variables: vec![],
+            template_extension: None,
+            partials_dir: None,
+            helpers: Default::default(),
+            defaults: Default::default(),
             packages: vec![(
                 "shell".to_string(),
                 super::Package {
+                    root: None,
                     depends: vec![],
+                    after: vec![],
+                    before: vec![],
                     files: vec![(
                         ".bashrc".into(),
                         super::FileTarget::Simple(".bashrc".into()),
                     )]
                     .into_iter()
                     .collect(),
-                    variables: HashMap::new(),
+                    // the global ones, as the package has none of its own
+                    variables: vec![("a".to_string(), "1".into()), ("b".to_string(), "2".into())]
+                        .into_iter()
+                        .collect(),
+                    listed: false,
                 },
             )]
             .into_iter()
             .collect(),
-            variables: vec![
-                ("a".to_string(), "1".to_string()),
-                ("b".to_string(), "2".to_string()),
-            ]
-            .into_iter()
-            .collect(),
+            variables: vec![("a".to_string(), "1".into()), ("b".to_string(), "2".into())]
+                .into_iter()
+                .collect(),
         };
 
         assert_eq!(config.variables, expected.variables);
@@ -234,4 +1476,271 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_force_toml_format() -> anyhow::Result<()> {
+        let config_content = r#"
+        [variables]
+        a = "1"
+
+        [shell.files]
+        ".bashrc" = ".bashrc"
+        "#;
+
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("ponto.conf");
+        File::create(&config_path)?.write_all(config_content.as_bytes())?;
+
+        let yaml = Some(super::ConfigFormat::Yaml);
+        assert!(super::load_config(&config_path, yaml, None, false).is_err());
+
+        let config =
+            super::load_config(&config_path, Some(super::ConfigFormat::Toml), None, false)?;
+
+        assert_eq!(config.variables["a"], "1");
+        assert_eq!(
+            config.packages["shell"].files[std::path::Path::new(".bashrc")],
+            super::FileTarget::Simple(".bashrc".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_sniff_format_of_config_without_known_extension() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("ponto.conf");
+
+        File::create(&config_path)?.write_all(b"[variables]\na = \"toml\"\n")?;
+        let config = super::load_config(&config_path, None, None, false)?;
+        assert_eq!(config.variables["a"], "toml");
+
+        File::create(&config_path)?.write_all(b"variables:\n  a: yaml\n")?;
+        let config = super::load_config(&config_path, None, None, false)?;
+        assert_eq!(config.variables["a"], "yaml");
+
+        File::create(&config_path)?.write_all(b"variables: [\n")?;
+        let error = super::load_config(&config_path, None, None, false).unwrap_err();
+        assert!(format!("{error:#}").contains("is neither YAML"));
+
+        Ok(())
+    }
+
+    fn configuration(packages: &[(&str, super::Package)]) -> super::Configuration {
+        super::Configuration {
+            packages: packages
+                .iter()
+                .map(|(name, package)| (name.to_string(), package.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn names(config: &super::Configuration) -> Vec<String> {
+        config
+            .ordered_by_dependencies()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn should_merge_fragments_of_config_directory() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        File::create(dir.path().join("10-shell.yaml"))?.write_all(
+            b"variables:\n  editor: vi\n  shell: zsh\nshell:\n  files:\n    .zshrc: ~/.zshrc\n",
+        )?;
+        File::create(dir.path().join("20-editor.toml"))?.write_all(
+            b"[variables]\neditor = \"vim\"\n\n[vim.files]\n\".vimrc\" = \"~/.vimrc\"\n",
+        )?;
+        File::create(dir.path().join("notes.txt"))?.write_all(b"not a fragment")?;
+
+        let config = super::load_config(dir.path(), None, None, false)?;
+
+        let mut packages = config.packages.keys().collect::<Vec<_>>();
+        packages.sort();
+        assert_eq!(packages, ["shell", "vim"]);
+        assert_eq!(config.variables["editor"], "vim");
+        assert_eq!(config.variables["shell"], "zsh");
+
+        File::create(dir.path().join("30-again.yaml"))?
+            .write_all(b"vim:\n  files:\n    .gvimrc: ~/.gvimrc\n")?;
+        let error = super::load_config(dir.path(), None, None, false).unwrap_err();
+        assert!(format!("{error:#}").contains("package vim is declared in both"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_merge_configs_in_order() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let shell = dir.path().join("shell.yaml");
+        std::fs::write(
+            &shell,
+            "variables:\n  editor: vi\nshell:\n  files:\n    .zshrc: ~/.zshrc\n",
+        )?;
+        let editor = dir.path().join("editor.yaml");
+        std::fs::write(
+            &editor,
+            "variables:\n  editor: vim\nvim:\n  files:\n    .vimrc: ~/.vimrc\n",
+        )?;
+
+        let config = super::load_configs(&[shell.clone(), editor.clone()], None, None, false)?;
+
+        let mut packages = config.packages.keys().collect::<Vec<_>>();
+        packages.sort();
+        assert_eq!(packages, ["shell", "vim"]);
+        assert_eq!(config.variables["editor"], "vim");
+
+        let error = super::load_configs(&[shell.clone(), shell], None, None, false).unwrap_err();
+        assert!(format!("{error:#}").contains("package shell is declared in both"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_template_config_before_parsing() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        File::create(&config_path)?
+            .write_all(b"shell-{{ os }}:\n  files:\n    .bashrc: ~/.bashrc\n")?;
+
+        let config = super::load_config(&config_path, None, None, true)?;
+        let name = format!("shell-{}", std::env::consts::OS);
+        assert!(config.packages.contains_key(&name));
+
+        // without the flag braces are kept as they are
+        let config = super::load_config(&config_path, None, None, false)?;
+        assert!(config.packages.contains_key("shell-{{ os }}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_top_level_files_first() -> anyhow::Result<()> {
+        let dir = TempDir::new("config")?;
+        let config_path = dir.path().join("config.yaml");
+        File::create(&config_path)?.write_all(
+            b"variables:\n  editor: vim\nfiles:\n  .editorconfig: ~/.editorconfig\nalpha:\n  files:\n    .bashrc: ~/.bashrc\n",
+        )?;
+
+        let config = super::load_config(
+            &config_path,
+            None,
+            Some(std::path::Path::new("/home")),
+            false,
+        )?;
+
+        assert_eq!(names(&config), [super::DEFAULT_PACKAGE, "alpha"]);
+        let default = &config.packages[super::DEFAULT_PACKAGE];
+        assert_eq!(
+            default.files[std::path::Path::new(".editorconfig")].targets(),
+            [std::path::PathBuf::from("/home/.editorconfig")]
+        );
+        assert_eq!(default.variables["editor"], "vim");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_order_by_after_and_before_hints() {
+        let config = configuration(&[
+            (
+                "zsh",
+                super::Package {
+                    after: vec!["fonts".to_string()],
+                    ..Default::default()
+                },
+            ),
+            ("fonts", super::Package::default()),
+            (
+                "brew",
+                super::Package {
+                    before: vec!["fonts".to_string()],
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        assert_eq!(names(&config), ["brew", "fonts", "zsh"]);
+    }
+
+    #[test]
+    fn should_ignore_hints_naming_missing_packages() {
+        let config = configuration(&[(
+            "zsh",
+            super::Package {
+                after: vec!["missing".to_string()],
+                before: vec!["absent".to_string()],
+                ..Default::default()
+            },
+        )]);
+
+        assert_eq!(names(&config), ["zsh"]);
+    }
+
+    #[test]
+    fn should_resolve_dependencies_across_namespaces() -> anyhow::Result<()> {
+        let depending_on = |depends: &[&str]| super::Package {
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let config = configuration(&[
+            ("home/shell", super::Package::default()),
+            ("work/shell", depending_on(&["home/shell"])),
+            ("work/git", depending_on(&["shell"])),
+        ]);
+
+        assert_eq!(names(&config), ["home/shell", "work/shell", "work/git"]);
+        let ordered = config.ordered_by_dependencies()?;
+        assert_eq!(ordered[2].1.depends, ["work/shell"]);
+
+        let config = configuration(&[
+            ("home/shell", super::Package::default()),
+            ("work/shell", super::Package::default()),
+            ("tools", depending_on(&["shell"])),
+        ]);
+        let error = config.ordered_by_dependencies().unwrap_err().to_string();
+        assert!(error.contains("ambiguous"), "{error}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_exclude_packages_and_their_dependents() -> anyhow::Result<()> {
+        let depending_on = |depends: &[&str]| super::Package {
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let config = || {
+            configuration(&[
+                ("shell", super::Package::default()),
+                ("git", super::Package::default()),
+                ("zsh", depending_on(&["shell"])),
+            ])
+        };
+
+        let mut excluded = config();
+        excluded.exclude_packages(&["git".to_owned()], false)?;
+        let mut remaining = excluded.packages.keys().collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, ["shell", "zsh"]);
+
+        let mut excluded = config();
+        let error = excluded
+            .exclude_packages(&["shell".to_owned()], false)
+            .unwrap_err();
+        assert!(error.to_string().contains("\"zsh\" depends"), "{error}");
+
+        let mut excluded = config();
+        excluded.exclude_packages(&["shell".to_owned()], true)?;
+        assert_eq!(excluded.packages.keys().collect::<Vec<_>>(), ["git"]);
+
+        assert!(config()
+            .exclude_packages(&["vim".to_owned()], false)
+            .is_err());
+
+        Ok(())
+    }
 }
@@ -1,86 +1,3468 @@
-use super::handlebars::init;
-use crate::config::{Configuration, FileTarget, Variables};
-use crate::filesystem::{Filesystem, FilesystemExt};
-use crate::hook::{self, Hook};
+use super::handlebars::{condition_holds, init, register_script_helpers};
+use crate::archive::Archive;
+use crate::config::{self, Configuration, FileTarget, Files, Package, TargetSpec, Variables};
+use crate::diff;
+use crate::error::PontoError;
+use crate::file_type::FileType;
+use crate::filesystem::{Filesystem, FilesystemExt, Umask};
+use crate::glob;
+use crate::hook::{self, Flow, Hook, ABORT_EXIT_CODE};
 use crate::options::Options;
-use crate::symlink::Symlink;
-use crate::template::Template;
+use crate::plan::{Action, Plan, Step};
+use crate::privileged::Privileged;
+use crate::process;
+use crate::report::{Change, FileAction, Outcome, PackageTiming, Report};
+use crate::retry::RetryPolicy;
+use crate::rollback::Journal;
+use crate::secret::{has_encrypted_extension, strip_encrypted_extension, Decrypter};
+use crate::state;
+use crate::symlink::{normalize, Symlink, SymlinkState};
+use crate::template::{normalize_eols, Template, TemplateState};
 use anyhow::{Context, Result};
 use handlebars::Handlebars;
-use log::{debug, info};
-use std::path::PathBuf;
+use indexmap::IndexMap;
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{BufRead, ErrorKind, IsTerminal, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub fn deploy(config: Configuration, opts: Options) -> Result<()> {
-    let handlebars = init().context("initialize handlebars")?;
+pub fn deploy(mut config: Configuration, opts: Options) -> Result<Report> {
+    prepare_variables(&mut config, &opts)?;
+    if opts.fail_on_missing_source {
+        let missing = missing_sources(&config);
+        anyhow::ensure!(
+            missing.is_empty(),
+            "{} configured source(s) missing:\n{}",
+            missing.len(),
+            missing.join("\n")
+        );
+    }
+    let plan_hash = match opts.skip_if_unchanged && !opts.dry_run {
+        true => Some((
+            state::hash_file(&opts.state_dir(), &opts.configs(), opts.home.as_deref())?,
+            state::plan_hash(&config, &opts)?,
+        )),
+        false => None,
+    };
+    if let Some((file, hash)) = &plan_hash {
+        if state::stored_hash(file).as_ref() == Some(hash) {
+            info!("nothing changed since the last deploy, skipping it");
+            return Ok(Report::default());
+        }
+    }
+    // restored once the deploy returns
+    let _umask = opts.umask.map(Umask::set);
+    let retry = opts.retry_policy();
+    let registries = registries(&config, &opts)?;
+    let privileged = Privileged::new(opts.sudo_command.as_deref().unwrap_or("sudo"));
+    let decrypter = opts.decrypter();
+    let expected = Expected::default();
+    if opts.force && !opts.dry_run {
+        let overwrites = forced_overwrites(&config, &opts, &registries, &expected)?;
+        confirm_forced(
+            &overwrites,
+            &opts,
+            &mut std::io::stdin().lock(),
+            &mut std::io::stderr(),
+            std::io::stdin().is_terminal(),
+        )?;
+    }
 
     // pre hook
-    hook::Pre::run(&opts.pre, &handlebars, &config.variables)?;
+    let flow = hook::Pre::run(
+        &opts.pre_hook(),
+        &opts.templated_extension(),
+        registries.unspecified(),
+        &config.variables,
+        &config.secret_variables,
+        retry,
+        opts.dry_run,
+    )
+    .context(PontoError::Hook)?;
+    if flow == Flow::Abort {
+        info!("pre hook aborted the deploy, not deploying any file");
+        remove_rendered_hooks(&opts)?;
+        return Ok(Report::default());
+    }
 
     // deploy files
     info!(
         "deploying files{}",
         if opts.force { " (forced)" } else { "" }
     );
-    for (_, package) in config.ordered_by_dependencies() {
-        for (from, to) in package.files {
-            match to {
-                FileTarget::Simple(to) => {
-                    process_simple(&from, &to, &handlebars, &config.variables, opts.force)?
+    let deployer = Deployer {
+        registries: &registries,
+        privileged: &privileged,
+        decrypter: &decrypter,
+        expected: &expected,
+        template_extension: config.template_extension.as_deref(),
+        force: opts.force,
+        force_files: opts
+            .force_files
+            .iter()
+            .map(|path| normalize(path))
+            .collect::<Result<_>>()?,
+        canonicalize: !opts.no_canonicalize,
+        trim_blocks: opts.trim_blocks,
+        normalize_eol: opts.normalize_eol,
+        timeout: retry.timeout,
+    };
+    let mut report = Report::default();
+    let mut journal = Journal::default();
+    let mut unmatched = 0;
+    let mut failures = vec![];
+    let deploy_started = Instant::now();
+    // a closure, so the summary is written however deploying the files ends
+    let deployed = (|| -> Result<()> {
+        for (name, package) in config.ordered_by_dependencies()? {
+            let started = Instant::now();
+            let mut files = vec![];
+            for (from, to) in scoped_files(package.files.clone(), &opts, &mut unmatched) {
+                if opts.dry_run {
+                    for to in to.targets() {
+                        info!("would deploy {from:?} to {to:?}");
+                        report.actions.push(FileAction::new(
+                            &name,
+                            from.clone(),
+                            to.clone(),
+                            Change::new("dry run", Outcome::Skipped),
+                        ));
+                    }
+                    continue;
+                }
+                let from = if opts.no_dereference {
+                    from
+                } else {
+                    dereference(from)?
+                };
+                if opts.rollback_on_hook_failure && from.exists() {
+                    let variables = &package.variables;
+                    let template_extension = config.template_extension.as_deref();
+                    for path in
+                        touched_paths(&from, &to, template_extension, &registries, variables)?
+                    {
+                        journal.record(&path)?;
+                    }
+                }
+                files.push((from, to));
+            }
+
+            let deploy_file = |(from, to): &(PathBuf, FileTarget)| {
+                let results = deployer.deploy_file(&name, from, to, &package.variables);
+                (from.clone(), results)
+            };
+            let deployed: Box<dyn Iterator<Item = _>> =
+                if opts.parallel_files && can_run_in_parallel(&name, &package, &files) {
+                    // without --keep-going, no file is started after one fails
+                    let failed = |(_, results): &(PathBuf, Vec<(_, _, Result<_>)>)| {
+                        !opts.keep_going && results.iter().any(|(_, _, result)| result.is_err())
+                    };
+                    Box::new(parallel_map(&files, deploy_file, failed).into_iter())
+                } else {
+                    // lazily, so files after a failure aren't deployed
+                    Box::new(files.iter().map(deploy_file))
+                };
+            for (from, results) in deployed {
+                for (to, duration, result) in results {
+                    match result {
+                        Ok(changes) => {
+                            report
+                                .actions
+                                .extend(changes.into_iter().map(|(from, to, change)| {
+                                    FileAction::new(&name, from, to, change).with_duration(duration)
+                                }))
+                        }
+                        Err(e) => {
+                            let e = e
+                                .context(format!("deploying {from:?} to {to:?} in package {name}"));
+                            report.actions.push(
+                                FileAction::new(
+                                    &name,
+                                    from.clone(),
+                                    to,
+                                    Change::new(format!("{e:#}"), Outcome::Failed),
+                                )
+                                .with_duration(duration),
+                            );
+                            if !opts.keep_going {
+                                return Err(e);
+                            }
+                            warn!("{e:#}");
+                            failures.push(e);
+                        }
+                    }
+                }
+            }
+            let duration = started.elapsed();
+            info!("deployed package {name} in {duration:.2?}");
+            report.packages.push(PackageTiming {
+                package: name,
+                duration,
+            });
+        }
+        report.total = deploy_started.elapsed();
+        if opts.dry_run && !opts.quiet {
+            print!("{}", plan(&config, &opts, &registries)?);
+        }
+        if unmatched > 0 {
+            info!("skipped {unmatched} files not matching the given pattern");
+        }
+        anyhow::ensure!(
+            failures.is_empty(),
+            "{} file(s) failed to deploy:\n{}",
+            failures.len(),
+            failures
+                .iter()
+                .map(|e| format!("  {e:#}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        info!("files deployed in {:.2?}", report.total);
+
+        // post hook
+        let post = hook::Post::run(
+            &opts.post_hook(),
+            &opts.templated_extension(),
+            registries.unspecified(),
+            &config.variables,
+            &config.secret_variables,
+            retry,
+            opts.dry_run,
+        )
+        .and_then(|flow| match flow {
+            Flow::Continue => Ok(()),
+            Flow::Abort => Err(anyhow::anyhow!(
+                "post hook exited with {ABORT_EXIT_CODE}, only pre hooks can abort"
+            )),
+        })
+        .context(PontoError::Hook);
+        if let Err(e) = post {
+            if opts.rollback_on_hook_failure {
+                warn!("post hook failed, rolling back deployed files");
+                match journal.roll_back(&mut report) {
+                    Ok(()) => info!("deployed files rolled back"),
+                    Err(rollback) => warn!("{rollback:#}"),
                 }
-                FileTarget::WithSpec(spec) => process_with_spec(
-                    &from,
-                    &spec.to,
-                    spec.symlink,
-                    &handlebars,
-                    &package.variables,
-                    opts.force,
-                )?,
             }
+            return Err(e);
         }
+
+        Ok(())
+    })();
+    let summary = opts
+        .summary_json
+        .as_deref()
+        .map(|path| report.write_json(path).context("writing JSON summary"));
+    deployed?;
+    summary.transpose()?;
+    remove_rendered_hooks(&opts)?;
+
+    if opts.verify && !opts.dry_run {
+        verify(&config, &opts, &registries)?;
+    }
+    let warnings = report.warnings();
+    anyhow::ensure!(
+        !opts.warnings_as_errors || warnings.is_empty(),
+        "{} warning(s) while deploying:\n{}",
+        warnings.len(),
+        warnings
+            .iter()
+            .map(|warning| format!("  {warning}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    if let Some((file, hash)) = plan_hash {
+        state::store_hash(&file, &hash)?;
+    }
+
+    Ok(report)
+}
+
+/// Deletes the hook scripts rendered next to the hooks, unless asked to keep
+/// them.
+fn remove_rendered_hooks(opts: &Options) -> Result<()> {
+    let cwd = crate::cwd!();
+    let hook_dirs = [opts.pre_hook(), opts.post_hook()]
+        .into_iter()
+        .filter_map(|hook| hook.parent().map(|dir| cwd.join(dir)))
+        .filter(|dir| dir.is_dir())
+        .collect::<BTreeSet<_>>();
+    if opts.no_delete_templated {
+        info!("leaving rendered hook scripts in {hook_dirs:?}");
+        return Ok(());
+    }
+    for dir in hook_dirs {
+        hook::remove_templated_scripts(&dir, &opts.templated_extension())
+            .context("deleting templated files")?;
     }
-    info!("files deployed");
 
-    // post hook
-    hook::Post::run(&opts.post, &handlebars, &config.variables)?;
-    // delete templated files
-    hook::remove_templated_scripts().context("deleting templated files")?;
     Ok(())
 }
 
-fn process_simple(
-    from: &PathBuf,
-    to: &PathBuf,
-    handlebars: &Handlebars<'_>,
-    variables: &Variables,
-    force: bool,
-) -> Result<()> {
-    if from.is_template().context("check if template")? {
-        debug!("rendering template file from {from:?} to {to:?}");
-        Template::render(from, to, handlebars, variables, force).context("rendering template")?;
+/// Runs what the options ask for: printing targets, validating templates,
+/// listing drift, diffing or, by default, deploying.
+pub fn run(config: Configuration, opts: Options) -> Result<()> {
+    if opts.print_targets {
+        print_targets(config, opts)
+    } else if opts.validate_templates {
+        validate_templates(config, opts)
+    } else if opts.list_drift {
+        list_drift(config, opts)
+    } else if opts.diff_only {
+        diff(config, opts)
     } else {
-        debug!("creating symlink from {from:?} to {to:?}");
-        Symlink::create(from, to, force).context("creating symlink")?;
+        let (quiet, report_skipped) = (opts.quiet, opts.report_skipped);
+        let report = deploy(config, opts)?;
+        if report_skipped {
+            print!("{}", report.skipped());
+        }
+        if !quiet {
+            println!("{report}");
+        }
+        Ok(())
+    }
+}
+
+/// [`run`] once per `--homes` directory, loading the config with `load` for
+/// each so that `~` expands to it. Stops at the first home that fails.
+pub fn run_homes(
+    opts: Options,
+    load: impl Fn(Options) -> Result<(Configuration, Options)>,
+) -> Result<()> {
+    for home in &opts.homes {
+        info!("running for home {home:?}");
+        if !opts.quiet {
+            println!("{}:", home.display());
+        }
+        let opts = Options {
+            home: Some(home.clone()),
+            homes: vec![],
+            ..opts.clone()
+        };
+        let (config, opts) = load(opts)?;
+        run(config, opts).with_context(|| format!("running for home {home:?}"))?;
     }
+
     Ok(())
 }
 
-fn process_with_spec(
-    from: &PathBuf,
-    to: &PathBuf,
-    is_symlink: bool,
-    handlebars: &Handlebars<'_>,
+/// Sources listed in the config that don't exist, as "package: source",
+/// sorted.
+fn missing_sources(config: &Configuration) -> Vec<String> {
+    let mut missing = config
+        .packages
+        .iter()
+        .flat_map(|(name, package)| {
+            package
+                .files
+                .keys()
+                .filter(|from| !from.exists() && !from.is_symlink())
+                .map(move |from| format!("{name}: {}", from.display()))
+        })
+        .collect::<Vec<_>>();
+    missing.sort();
+    missing
+}
+
+/// Layers variables, from lowest to highest precedence: the `--data` file,
+/// the config's own, `PONTO_VAR_<KEY>` environment variables and `--set`.
+fn prepare_variables(config: &mut Configuration, opts: &Options) -> Result<()> {
+    layer_variables(config, opts, std::env::vars())
+}
+
+/// [`prepare_variables`] with the environment given.
+fn layer_variables(
+    config: &mut Configuration,
+    opts: &Options,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<()> {
+    if let Some(path) = &opts.data {
+        config.add_data(&config::load_data(path)?);
+    }
+    let known = std::iter::once(&config.variables)
+        .chain(config.packages.values().map(|package| &package.variables))
+        .flat_map(|variables| variables.keys());
+    let from_env = config::env_variables(env, known);
+    config.override_variables(&from_env);
+    config.override_variables(&opts.overrides());
+
+    Ok(())
+}
+
+/// Re-checks that every target reached the state deploying it should have
+/// left it in, catching anything a hook or another process changed since.
+fn verify(config: &Configuration, opts: &Options, registries: &Registries<'_>) -> Result<()> {
+    info!("verifying deployed files");
+
+    let failures = drifted(config, opts, registries, None)?;
+    for to in &failures {
+        warn!("{to:?} is not in the expected state after deploying");
+    }
+
+    anyhow::ensure!(
+        failures.is_empty(),
+        "verification failed for {} target(s): {failures:?}",
+        failures.len()
+    );
+    info!("deployed files verified");
+
+    Ok(())
+}
+
+/// Lists the targets, missing ones included, that differ from what deploying
+/// them would produce, keeping what was rendered or decrypted in `expected`.
+fn drifted(
+    config: &Configuration,
+    opts: &Options,
+    registries: &Registries<'_>,
+    expected: Option<&Expected>,
+) -> Result<Vec<PathBuf>> {
+    let template_extension = config.template_extension.as_deref();
+
+    let mut drifted = vec![];
+    let mut unmatched = 0;
+    for (_, package) in config.ordered_by_dependencies()? {
+        for (from, target) in scoped_files(package.files.clone(), opts, &mut unmatched) {
+            let variables = &package.variables;
+            if let FileTarget::WithSpec(spec) = &target {
+                if !applies(spec, registries, variables)? {
+                    continue;
+                }
+            }
+            let (action, targets) = resolve(&from, &target, template_extension)?;
+            for to in targets {
+                let deployed = is_deployed(
+                    action, &from, &to, &target, variables, registries, opts, expected,
+                )?;
+                if !deployed {
+                    drifted.push(to);
+                }
+            }
+        }
+    }
+
+    Ok(drifted)
+}
+
+/// Prints every target that drifted from the config without changing
+/// anything, failing with [`PontoError::Drift`] when there is any.
+pub fn list_drift(mut config: Configuration, opts: Options) -> Result<()> {
+    prepare_variables(&mut config, &opts)?;
+    let registries = registries(&config, &opts)?;
+
+    let drifted = drifted(&config, &opts, &registries, None)?;
+    for to in &drifted {
+        println!("{}", to.display());
+    }
+    if !drifted.is_empty() {
+        return Err(anyhow::anyhow!("{} target(s) drifted", drifted.len()))
+            .context(PontoError::Drift);
+    }
+    info!("no target drifted");
+
+    Ok(())
+}
+
+/// Whether `to` is already what deploying `from` with `action` produces.
+/// What had to be rendered or decrypted to tell is kept in `expected`.
+#[allow(clippy::too_many_arguments)]
+fn is_deployed(
+    action: Action,
+    from: &Path,
+    to: &Path,
+    target: &FileTarget,
     variables: &Variables,
-    force: bool,
+    registries: &Registries<'_>,
+    opts: &Options,
+    expected: Option<&Expected>,
+) -> Result<bool> {
+    let keep = |contents: &[u8]| {
+        if let Some(expected) = expected {
+            expected.insert(from, to, contents);
+        }
+    };
+    let canonicalize = !opts.no_canonicalize;
+    Ok(match action {
+        Action::Render => {
+            let handlebars = registries.for_target(target);
+            let rendered =
+                Template::render_to_string(from, handlebars, variables, opts.trim_blocks)
+                    .with_context(|| format!("rendering template {from:?}"))?;
+            keep(rendered.as_bytes());
+            fs::read_to_string(to).is_ok_and(|contents| match opts.normalize_eol {
+                true => normalize_eols(&contents) == normalize_eols(&rendered),
+                false => contents == rendered,
+            })
+        }
+        Action::RenderBytes => {
+            let handlebars = registries.for_target(target);
+            let rendered =
+                Template::render_bytes_to_vec(from, handlebars, variables, opts.trim_blocks)
+                    .with_context(|| format!("rendering template {from:?}"))?;
+            keep(&rendered);
+            fs::read(to).is_ok_and(|contents| contents == rendered)
+        }
+        Action::Copy if from.is_dir() => Filesystem::in_sync(from, to, &target.ignored())?,
+        Action::Copy => fs::read(to)
+            .is_ok_and(|contents| fs::read(from).is_ok_and(|expected| contents == expected)),
+        Action::Link => links_to(from, to, canonicalize)?,
+        Action::HardLink => Filesystem::is_hard_link(from, to)?,
+        Action::LinkContents => {
+            let FileTarget::WithSpec(spec) = target else {
+                unreachable!("only targets with a spec link contents")
+            };
+            directory_files(from, to, spec, registries, variables)?
+                .iter()
+                .map(|(from, to)| links_to(from, to, canonicalize))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .all(|linked| linked)
+        }
+        Action::Decrypt => {
+            let plaintext = opts.decrypter().decrypt(from)?;
+            keep(&plaintext);
+            !to.is_symlink() && fs::read(to).is_ok_and(|contents| contents == plaintext)
+        }
+        Action::Extract => Archive::entries(from)?
+            .into_iter()
+            .all(|entry| match entry.contents {
+                Some(contents) => {
+                    fs::read(to.join(&entry.path)).is_ok_and(|current| current == contents)
+                }
+                None => to.join(&entry.path).is_dir(),
+            }),
+    })
+}
+
+/// Works out, per package, what deploying each target would do and the state
+/// the target is in now, without changing anything.
+fn plan(config: &Configuration, opts: &Options, registries: &Registries<'_>) -> Result<Plan> {
+    let template_extension = config.template_extension.as_deref();
+    let mut plan = Plan::default();
+    let mut unmatched = 0;
+    for (name, package) in config.ordered_by_dependencies()? {
+        let mut steps = vec![];
+        for (from, target) in scoped_files(package.files.clone(), opts, &mut unmatched) {
+            let (action, targets) = match resolve(&from, &target, template_extension) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    warn!("can't plan deploying {from:?}: {e:#}");
+                    continue;
+                }
+            };
+            let variables = &package.variables;
+            let applies = match &target {
+                FileTarget::WithSpec(spec) => applies(spec, registries, variables)?,
+                FileTarget::Simple(_) => true,
+            };
+            for to in targets {
+                let state = if applies {
+                    planned_state(action, &from, &to, &target, variables, registries, opts)?
+                } else {
+                    "condition doesn't hold".to_owned()
+                };
+                steps.push(Step {
+                    source: from.clone(),
+                    target: to,
+                    action,
+                    state,
+                });
+            }
+        }
+        if !steps.is_empty() {
+            plan.packages.push((name, steps));
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Describes the state of `to` compared to what deploying `from` produces,
+/// with the wording deploys log.
+fn planned_state(
+    action: Action,
+    from: &Path,
+    to: &Path,
+    target: &FileTarget,
+    variables: &Variables,
+    registries: &Registries<'_>,
+    opts: &Options,
+) -> Result<String> {
+    let state = match action {
+        Action::Render => {
+            let rendered = Template::render_to_string(
+                from,
+                registries.for_target(target),
+                variables,
+                opts.trim_blocks,
+            )
+            .with_context(|| format!("rendering template {from:?}"))?;
+            TemplateState::from(
+                FileType::File(Some(rendered)),
+                FileType::try_from(to)?,
+                opts.normalize_eol,
+            )
+            .to_string()
+        }
+        Action::Copy if from.is_dir() => {
+            if Filesystem::in_sync(from, to, &target.ignored())? {
+                "target directory is in sync".to_owned()
+            } else if to.exists() {
+                "target directory differs".to_owned()
+            } else {
+                "target directory missing".to_owned()
+            }
+        }
+        Action::Copy => {
+            TemplateState::from(FileType::try_from(from)?, FileType::try_from(to)?, false)
+                .to_string()
+        }
+        Action::Link => SymlinkState::from(
+            from,
+            FileType::try_from(from)?,
+            to,
+            FileType::try_from(to)?,
+            !opts.no_canonicalize,
+        )?
+        .to_string(),
+        Action::RenderBytes
+        | Action::HardLink
+        | Action::LinkContents
+        | Action::Extract
+        | Action::Decrypt => {
+            if is_deployed(action, from, to, target, variables, registries, opts, None)? {
+                "target is up to date".to_owned()
+            } else if to.exists() || to.is_symlink() {
+                "target differs".to_owned()
+            } else {
+                "target missing".to_owned()
+            }
+        }
+    };
+    Ok(state)
+}
+
+/// Lists the existing targets a forced deploy would overwrite because they
+/// differ from what deploying them produces. What this renders and decrypts
+/// is kept in `expected` for the deploy to reuse.
+fn forced_overwrites(
+    config: &Configuration,
+    opts: &Options,
+    registries: &Registries<'_>,
+    expected: &Expected,
+) -> Result<Vec<PathBuf>> {
+    Ok(drifted(config, opts, registries, Some(expected))?
+        .into_iter()
+        .filter(|to| to.exists() || to.is_symlink())
+        .collect())
+}
+
+/// What was rendered or decrypted for each source and target before a forced
+/// deploy, so deploying them doesn't run command helpers or decrypt again.
+#[derive(Default)]
+struct Expected(Mutex<HashMap<(PathBuf, PathBuf), Contents>>);
+
+/// The source as it was read, and what it produced.
+type Contents = (Vec<u8>, Vec<u8>);
+
+impl Expected {
+    /// Sources are keyed by where they resolve to, as deploys dereference
+    /// them.
+    fn key(from: &Path, to: &Path) -> (PathBuf, PathBuf) {
+        let from = from.canonicalize().unwrap_or_else(|_| from.to_owned());
+        (from, to.to_owned())
+    }
+
+    fn insert(&self, from: &Path, to: &Path, contents: &[u8]) {
+        if let Ok(source) = fs::read(from) {
+            let key = Self::key(from, to);
+            let mut expected = self.0.lock().unwrap();
+            expected.insert(key, (source, contents.to_vec()));
+        }
+    }
+
+    /// What deploying `from` to `to` produced, unless the source changed
+    /// since, say because the pre hook rewrote it.
+    fn take(&self, from: &Path, to: &Path) -> Option<Vec<u8>> {
+        let key = Self::key(from, to);
+        let (source, contents) = self.0.lock().unwrap().remove(&key)?;
+        fs::read(from)
+            .is_ok_and(|current| current == source)
+            .then_some(contents)
+    }
+}
+
+/// Asks on `output` for confirmation when a forced deploy would overwrite
+/// more targets than `--force-threshold`, unless `--yes` was passed. Without
+/// an interactive stdin, `--yes` is required instead.
+fn confirm_forced(
+    overwrites: &[PathBuf],
+    opts: &Options,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    interactive: bool,
 ) -> Result<()> {
-    if from.is_template()? {
-        debug!("rendering template file from {from:?} to {to:?}");
-        Template::render(from, to, handlebars, variables, force).context("rendering template")?;
-    } else if !is_symlink {
-        debug!("copying file from {from:?} to {to:?}");
-        Filesystem::copy(from, to, force).context("copying file")?;
+    if overwrites.len() <= opts.force_threshold() || opts.yes {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        interactive,
+        "--force would overwrite {} targets, pass --yes to confirm",
+        overwrites.len()
+    );
+    for to in overwrites {
+        writeln!(output, "  {}", to.display())?;
+    }
+    write!(
+        output,
+        "--force will overwrite the {} targets above, continue? [y/N] ",
+        overwrites.len()
+    )?;
+    output.flush()?;
+    let mut answer = String::new();
+    input
+        .read_line(&mut answer)
+        .context("reading confirmation")?;
+    anyhow::ensure!(
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        "forced deploy aborted"
+    );
+
+    Ok(())
+}
+
+fn links_to(from: &Path, to: &Path, canonicalize: bool) -> Result<bool> {
+    Ok(matches!(
+        SymlinkState::from(
+            from,
+            FileType::try_from(from)?,
+            to,
+            FileType::try_from(to)?,
+            canonicalize
+        )?,
+        SymlinkState::Identical
+    ))
+}
+
+/// Pairs each file below the directory `from` with where it goes below `to`.
+/// With the spec's `template_names` set, each component of a file's name is
+/// rendered with `variables`, so `{{ hostname }}.conf` becomes `myhost.conf`.
+fn directory_files(
+    from: &Path,
+    to: &Path,
+    spec: &TargetSpec,
+    registries: &Registries<'_>,
+    variables: &Variables,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let (files, too_deep) = Filesystem::files_within(from, &spec.ignored(), spec.max_depth)
+        .context("listing source directory")?;
+    if too_deep > 0 {
+        debug!("skipped {too_deep} director(ies) below {from:?} deeper than max_depth");
+    }
+    files
+        .into_iter()
+        .map(|file| {
+            let name = if spec.template_names {
+                render_name(&file, registries.for_spec(spec), variables)?
+            } else {
+                file.clone()
+            };
+            Ok((from.join(file), to.join(name)))
+        })
+        .collect()
+}
+
+/// Renders each component of the relative path `file` on its own, so no
+/// variable can add or remove a directory level.
+fn render_name(file: &Path, handlebars: &Handlebars<'_>, variables: &Variables) -> Result<PathBuf> {
+    file.iter()
+        .map(|component| {
+            let component = component.to_string_lossy();
+            if !component.contains("{{") {
+                return Ok(component.into_owned());
+            }
+            let rendered = handlebars
+                .render_template(&component, variables)
+                .with_context(|| format!("rendering file name {file:?}"))?;
+            anyhow::ensure!(
+                !rendered.is_empty()
+                    && rendered != "."
+                    && rendered != ".."
+                    && !rendered.contains('/'),
+                "file name {component:?} rendered to {rendered:?}, which isn't a file name"
+            );
+            Ok(rendered)
+        })
+        .collect()
+}
+
+/// Handlebars registries for templates in strict mode and for those that
+/// opted out of it.
+struct Registries<'hb> {
+    strict: Handlebars<'hb>,
+    lenient: Handlebars<'hb>,
+    /// Whether templates that don't say otherwise, hooks included, are strict
+    strict_by_default: bool,
+}
+
+impl<'hb> Registries<'hb> {
+    fn new(retry: RetryPolicy, partials_dir: Option<&Path>) -> Result<Self> {
+        let mut strict = init(retry).context("initialize handlebars")?;
+        if let Some(dir) = partials_dir {
+            register_partials(&mut strict, dir)?;
+        }
+        let mut lenient = strict.clone();
+        lenient.set_strict_mode(false);
+
+        Ok(Self {
+            strict,
+            lenient,
+            strict_by_default: true,
+        })
+    }
+
+    /// Renders templates without a `strict` of their own leniently, as
+    /// `--no-strict` asks.
+    fn with_strict(mut self, strict: bool) -> Self {
+        self.strict_by_default = strict;
+        self
+    }
+
+    fn register_script_helpers(&mut self, helpers: &IndexMap<String, PathBuf>, retry: RetryPolicy) {
+        for handlebars in [&mut self.strict, &mut self.lenient] {
+            register_script_helpers(handlebars, helpers, retry);
+        }
+    }
+
+    /// The registry for templates and hooks that don't choose a mode.
+    fn unspecified(&self) -> &Handlebars<'hb> {
+        match self.strict_by_default {
+            true => &self.strict,
+            false => &self.lenient,
+        }
+    }
+
+    fn for_spec(&self, spec: &TargetSpec) -> &Handlebars<'hb> {
+        match spec.strict {
+            Some(true) => &self.strict,
+            Some(false) => &self.lenient,
+            None => self.unspecified(),
+        }
+    }
+
+    fn for_target(&self, target: &FileTarget) -> &Handlebars<'hb> {
+        match target {
+            FileTarget::Simple(_) => self.unspecified(),
+            FileTarget::WithSpec(spec) => self.for_spec(spec),
+        }
+    }
+}
+
+/// The registries a run with `opts` renders `config`'s templates with.
+fn registries<'hb>(config: &Configuration, opts: &Options) -> Result<Registries<'hb>> {
+    let mut registries = Registries::new(opts.retry_policy(), config.partials_dir.as_deref())?
+        .with_strict(!opts.no_strict);
+    if !config.helpers.is_empty() {
+        anyhow::ensure!(
+            opts.allow_command_helpers,
+            "the config's helpers run scripts, pass --allow-command-helpers to use them"
+        );
+        registries.register_script_helpers(&config.helpers, opts.retry_policy());
+    }
+
+    Ok(registries)
+}
+
+/// Registers each file in `dir` as a partial named after its file stem, so
+/// `header.hbs` is included with `{{> header}}`.
+fn register_partials(handlebars: &mut Handlebars<'_>, dir: &Path) -> Result<()> {
+    let mut files = fs::read_dir(dir)
+        .with_context(|| format!("reading partials directory {dir:?}"))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| path.is_file());
+    files.sort();
+    for path in files {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("reading partial {path:?}"))?;
+        debug!("registering partial {name} from {path:?}");
+        handlebars
+            .register_partial(&name, contents)
+            .with_context(|| format!("registering partial {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Works out how a file is deployed and where to, mirroring
+/// `Deployer::process_simple` and `Deployer::process_with_spec`.
+fn resolve(
+    from: &PathBuf,
+    target: &FileTarget,
+    template_extension: Option<&str>,
+) -> Result<(Action, Vec<PathBuf>)> {
+    let (is_symlink, link_contents, binary_template, extract, encrypted, hardlink) = match target {
+        FileTarget::Simple(_) => (true, false, false, false, false, false),
+        FileTarget::WithSpec(spec) => (
+            spec.symlink,
+            spec.link_contents,
+            spec.binary_template,
+            spec.extract,
+            spec.encrypted,
+            spec.hardlink,
+        ),
+    };
+    let strip = |targets: &[PathBuf]| {
+        targets
+            .iter()
+            .map(|to| to.strip_template_extension(template_extension))
+            .collect()
+    };
+
+    Ok(if extract {
+        (Action::Extract, target.targets().to_vec())
+    } else if link_contents {
+        (Action::LinkContents, target.targets().to_vec())
+    } else if encrypted || has_encrypted_extension(from) {
+        let targets = target.targets().iter();
+        (
+            Action::Decrypt,
+            targets.map(|to| strip_encrypted_extension(to)).collect(),
+        )
+    } else if hardlink {
+        (Action::HardLink, target.targets().to_vec())
+    } else if binary_template {
+        (Action::RenderBytes, strip(target.targets()))
+    } else if from.is_template(template_extension)? {
+        (Action::Render, strip(target.targets()))
+    } else if is_symlink {
+        (Action::Link, target.targets().to_vec())
     } else {
-        debug!("creating symlink from {from:?} to {to:?}");
-        Symlink::create(from, to, force).context("creating symlink")?;
+        (Action::Copy, target.targets().to_vec())
+    })
+}
+
+/// Every path deploying `from` to `target` may change.
+fn touched_paths(
+    from: &PathBuf,
+    target: &FileTarget,
+    template_extension: Option<&str>,
+    registries: &Registries<'_>,
+    variables: &Variables,
+) -> Result<Vec<PathBuf>> {
+    let (action, targets) = resolve(from, target, template_extension)?;
+    let mut touched = vec![];
+    for to in targets {
+        match (action, target) {
+            (Action::LinkContents, FileTarget::WithSpec(spec)) => touched.extend(
+                directory_files(from, &to, spec, registries, variables)?
+                    .into_iter()
+                    .map(|(_, to)| to),
+            ),
+            (Action::Extract, _) => touched.extend(
+                Archive::entries(from)?
+                    .into_iter()
+                    .filter(|entry| entry.contents.is_some())
+                    .map(|entry| to.join(entry.path)),
+            ),
+            _ => touched.push(to),
+        }
+    }
+    Ok(touched)
+}
+
+/// Prints a unified diff, grouped by package, between each deployed file and
+/// what deploying it would produce. Symlinks have no contents of their own and
+/// are left out, as are binary templates and decrypted secrets.
+pub fn diff(mut config: Configuration, opts: Options) -> Result<()> {
+    prepare_variables(&mut config, &opts)?;
+    let registries = registries(&config, &opts)?;
+    let template_extension = config.template_extension.as_deref();
+
+    let mut unmatched = 0;
+    for (name, package) in config.ordered_by_dependencies()? {
+        let mut files = scoped_files(package.files.clone(), &opts, &mut unmatched)
+            .into_iter()
+            .collect::<Vec<_>>();
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut diffs = vec![];
+        for (from, target) in files {
+            let variables = &package.variables;
+            if let FileTarget::WithSpec(spec) = &target {
+                if !applies(spec, &registries, variables)? {
+                    continue;
+                }
+            }
+            let (action, targets) = resolve(&from, &target, template_extension)?;
+            let expected = match action {
+                Action::Render => Template::render_to_string(
+                    &from,
+                    registries.for_target(&target),
+                    variables,
+                    opts.trim_blocks,
+                )
+                .with_context(|| format!("rendering template {from:?}"))?,
+                Action::Copy => match fs::read_to_string(&from) {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        debug!("not diffing {from:?}: {e}");
+                        continue;
+                    }
+                },
+                Action::RenderBytes
+                | Action::Link
+                | Action::HardLink
+                | Action::LinkContents
+                | Action::Extract
+                | Action::Decrypt => continue,
+            };
+            for to in targets {
+                let current = match FileType::try_from(to.as_path())? {
+                    FileType::File(Some(contents)) => contents,
+                    FileType::Missing => String::new(),
+                    other => {
+                        debug!("not diffing {to:?}: target is {other:?}");
+                        continue;
+                    }
+                };
+
+                let target = to.display().to_string();
+                let diff =
+                    diff::unified(&current, &expected, &target, &target, opts.diff_context());
+                if !diff.is_empty() {
+                    diffs.push(diff);
+                }
+            }
+        }
+
+        if !diffs.is_empty() {
+            println!("{name}:");
+            for diff in diffs {
+                print!("{diff}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders every template without writing any target, failing with each
+/// template that doesn't render.
+pub fn validate_templates(mut config: Configuration, opts: Options) -> Result<()> {
+    prepare_variables(&mut config, &opts)?;
+    let errors = template_errors(&config, &opts)?;
+    for (from, error) in &errors {
+        println!("{}: {error:#}", from.display());
+    }
+    anyhow::ensure!(
+        errors.is_empty(),
+        "{} template(s) failed to render",
+        errors.len()
+    );
+    info!("every template rendered");
+
+    Ok(())
+}
+
+/// Renders each template source once, collecting the ones that fail along
+/// with why, sorted by source.
+fn template_errors(
+    config: &Configuration,
+    opts: &Options,
+) -> Result<Vec<(PathBuf, anyhow::Error)>> {
+    let registries = registries(config, opts)?;
+    let template_extension = config.template_extension.as_deref();
+
+    let mut errors = vec![];
+    let mut unmatched = 0;
+    for (_, package) in config.ordered_by_dependencies()? {
+        for (from, target) in scoped_files(package.files.clone(), opts, &mut unmatched) {
+            let variables = &package.variables;
+            if let FileTarget::WithSpec(spec) = &target {
+                if !applies(spec, &registries, variables)? {
+                    continue;
+                }
+            }
+            let handlebars = registries.for_target(&target);
+            let rendered = match resolve(&from, &target, template_extension)?.0 {
+                Action::Render => {
+                    Template::render_to_string(&from, handlebars, variables, opts.trim_blocks)
+                        .map(drop)
+                }
+                Action::RenderBytes => {
+                    Template::render_bytes_to_vec(&from, handlebars, variables, opts.trim_blocks)
+                        .map(drop)
+                }
+                _ => continue,
+            };
+            if let Err(e) = rendered {
+                errors.push((from, e));
+            }
+        }
+    }
+    errors.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(errors)
+}
+
+/// A package's name and its sources paired with where they deploy to.
+type PackageTargets = (String, Vec<(PathBuf, PathBuf)>);
+
+/// Lists, per package sorted by name, each source and the absolute path
+/// deploying it writes, sorted by source. Targets whose condition doesn't
+/// hold are left out, and directories whose contents are linked are listed
+/// file by file.
+pub fn resolved_targets(config: &Configuration, opts: &Options) -> Result<Vec<PackageTargets>> {
+    let cwd = crate::cwd!();
+    let registries = registries(config, opts)?;
+    let template_extension = config.template_extension.as_deref();
+    let mut unmatched = 0;
+    let mut packages = vec![];
+    for (name, package) in &config.packages {
+        let mut files = vec![];
+        for (from, target) in scoped_files(package.files.clone(), opts, &mut unmatched) {
+            let variables = &package.variables;
+            if let FileTarget::WithSpec(spec) = &target {
+                if !applies(spec, &registries, variables)? {
+                    continue;
+                }
+            }
+            let (action, targets) = match resolve(&from, &target, template_extension) {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    warn!("can't resolve the targets of {from:?}: {e:#}");
+                    continue;
+                }
+            };
+            let written = match (action, &target) {
+                (Action::LinkContents, FileTarget::WithSpec(spec)) => targets
+                    .iter()
+                    .map(|to| directory_files(&from, to, spec, &registries, variables))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                _ => touched_paths(&from, &target, template_extension, &registries, variables)?
+                    .into_iter()
+                    .map(|to| (from.clone(), to))
+                    .collect::<Vec<_>>(),
+            };
+            files.extend(written.into_iter().map(|(from, to)| (from, cwd.join(to))));
+        }
+        if !files.is_empty() {
+            files.sort();
+            packages.push((name.to_owned(), files));
+        }
     }
+    packages.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(packages)
+}
+
+pub fn print_targets(mut config: Configuration, opts: Options) -> Result<()> {
+    prepare_variables(&mut config, &opts)?;
+    for (name, files) in resolved_targets(&config, &opts)? {
+        println!("{name}:");
+        for (from, to) in files {
+            println!("  {} -> {}", from.display(), to.display());
+        }
+    }
+
     Ok(())
 }
+
+/// Applies the `--match` filter to a package's files, adding the number of
+/// filtered out files to `unmatched`.
+fn scoped_files(files: Files, opts: &Options, unmatched: &mut usize) -> Files {
+    match &opts.pattern {
+        Some(pattern) => {
+            let (files, skipped) = matching_files(files, pattern);
+            *unmatched += skipped;
+            files
+        }
+        None => files,
+    }
+}
+
+/// Keeps only the files whose source or target matches `pattern`, returning
+/// them along with how many were filtered out.
+fn matching_files(files: Files, pattern: &str) -> (Files, usize) {
+    let total = files.len();
+    let matching = files
+        .into_iter()
+        .filter(|(from, to)| {
+            glob::matches(pattern, from) || to.targets().iter().any(|to| glob::matches(pattern, to))
+        })
+        .collect::<Files>();
+    let skipped = total - matching.len();
+
+    (matching, skipped)
+}
+
+/// Resolves a source that is itself a symlink to the file it points at, so it
+/// is templated, copied or linked like that file rather than as a link.
+fn dereference(from: PathBuf) -> Result<PathBuf> {
+    if !from.is_symlink() {
+        return Ok(from);
+    }
+    let resolved = from
+        .real_path()
+        .with_context(|| format!("resolving symlinked source {from:?}"))?;
+    debug!("source {from:?} is a symlink to {resolved:?}");
+
+    Ok(resolved)
+}
+
+/// Whether the spec's `when` condition, if any, holds.
+fn applies(spec: &TargetSpec, registries: &Registries<'_>, variables: &Variables) -> Result<bool> {
+    match &spec.when {
+        Some(when) => condition_holds(registries.for_spec(spec), when, variables),
+        None => Ok(true),
+    }
+}
+
+/// Whether a package's files can deploy concurrently: not when they were
+/// listed to deploy in order, wait for one another, share a target, or may
+/// prompt for a password.
+fn can_run_in_parallel(name: &str, package: &Package, files: &[(PathBuf, FileTarget)]) -> bool {
+    let reason = if package.listed {
+        "its files are listed in order"
+    } else if files
+        .iter()
+        .any(|(_, to)| matches!(to, FileTarget::WithSpec(spec) if spec.sudo))
+    {
+        "some of its files deploy with sudo"
+    } else if files
+        .iter()
+        .any(|(_, to)| matches!(to, FileTarget::WithSpec(spec) if !spec.after.is_empty()))
+    {
+        "some of its files wait for others"
+    } else {
+        let mut targets = BTreeSet::new();
+        match files
+            .iter()
+            .flat_map(|(_, to)| to.targets())
+            .all(|to| targets.insert(to))
+        {
+            true => return true,
+            false => "some of its files share a target",
+        }
+    };
+    debug!("deploying package {name} sequentially, {reason}");
+    false
+}
+
+/// Maps `items` with `process` on up to one thread per CPU, keeping their
+/// order. Once a result is `failed`, no other item is started, and only the
+/// results of the items that were are returned.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    process: impl Fn(&T) -> R + Sync,
+    failed: impl Fn(&R) -> bool + Sync,
+) -> Vec<R> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(items.len()) {
+            scope.spawn(|| loop {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let result = process(item);
+                if failed(&result) {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Runs `process`, returning how long it took along with its result.
+fn timed<T>(to: PathBuf, process: impl FnOnce() -> T) -> (PathBuf, Duration, T) {
+    let start = Instant::now();
+    let result = process();
+    (to, start.elapsed(), result)
+}
+
+/// Settings shared by every file of a deploy.
+struct Deployer<'a, 'hb> {
+    registries: &'a Registries<'hb>,
+    privileged: &'a Privileged,
+    decrypter: &'a Decrypter,
+    /// Contents already rendered or decrypted for a forced deploy
+    expected: &'a Expected,
+    template_extension: Option<&'a str>,
+    force: bool,
+    /// Sources and targets forced on their own, made absolute
+    force_files: BTreeSet<PathBuf>,
+    canonicalize: bool,
+    trim_blocks: bool,
+    normalize_eol: bool,
+    /// Limit on how long `on_change` commands may run
+    timeout: Option<Duration>,
+}
+
+/// A source, the target it was deployed to and what was done about it.
+type Deployed = (PathBuf, PathBuf, Change);
+
+impl Deployer<'_, '_> {
+    fn render(
+        &self,
+        from: &Path,
+        to: &Path,
+        handlebars: &Handlebars<'_>,
+        variables: &Variables,
+    ) -> Result<String> {
+        match self.expected.take(from, to) {
+            Some(rendered) => Ok(String::from_utf8(rendered)?),
+            None => Template::render_to_string(from, handlebars, variables, self.trim_blocks),
+        }
+    }
+
+    fn render_bytes(
+        &self,
+        from: &Path,
+        to: &Path,
+        handlebars: &Handlebars<'_>,
+        variables: &Variables,
+    ) -> Result<Vec<u8>> {
+        match self.expected.take(from, to) {
+            Some(rendered) => Ok(rendered),
+            None => Template::render_bytes_to_vec(from, handlebars, variables, self.trim_blocks),
+        }
+    }
+
+    fn decrypt(&self, from: &Path, to: &Path) -> Result<Vec<u8>> {
+        match self.expected.take(from, to) {
+            Some(plaintext) => Ok(plaintext),
+            None => self.decrypter.decrypt(from),
+        }
+    }
+
+    /// Whether `--force` applies, or a `--force-file` names the source or
+    /// either form of the target.
+    fn forced(&self, from: &Path, to: &Path) -> Result<bool> {
+        if self.force {
+            return Ok(true);
+        }
+        let stripped = to
+            .to_path_buf()
+            .strip_template_extension(self.template_extension);
+        for path in [from, to, &stripped] {
+            if self.force_files.contains(&normalize(path)?) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Deploys a source to every path of its target, timing each.
+    fn deploy_file(
+        &self,
+        package: &str,
+        from: &PathBuf,
+        to: &FileTarget,
+        variables: &Variables,
+    ) -> Vec<(PathBuf, Duration, Result<Vec<Deployed>>)> {
+        match to {
+            FileTarget::Simple(to) => {
+                vec![timed(to.clone(), || {
+                    self.process_simple(package, from, to, variables)
+                        .map_err(|e| explain_denied(e, to))
+                })]
+            }
+            FileTarget::WithSpec(spec) => spec
+                .to
+                .paths()
+                .iter()
+                .map(|to| {
+                    timed(to.clone(), || {
+                        let deployed = self.process_with_spec(package, from, to, spec, variables);
+                        match spec.sudo {
+                            true => deployed,
+                            false => deployed.map_err(|e| explain_denied(e, to)),
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn process_simple(
+        &self,
+        package: &str,
+        from: &PathBuf,
+        to: &PathBuf,
+        variables: &Variables,
+    ) -> Result<Vec<Deployed>> {
+        let force = self.forced(from, to)?;
+        let change = if has_encrypted_extension(from) {
+            let to = &strip_encrypted_extension(to);
+            debug!("[{package}] decrypting {from:?} into {to:?}");
+            let change = self
+                .decrypt(from, to)
+                .and_then(|plaintext| Decrypter::deploy(&plaintext, to, force))
+                .context("decrypting source")?;
+            (from.clone(), to.clone(), change)
+        } else if from
+            .is_template(self.template_extension)
+            .context("check if template")?
+        {
+            let to = &to.strip_template_extension(self.template_extension);
+            debug!("[{package}] rendering template file from {from:?} to {to:?}");
+            let change = Template::render(from, to, force, self.normalize_eol, || {
+                self.render(from, to, self.registries.unspecified(), variables)
+            })
+            .context("rendering template")?;
+            (from.clone(), to.clone(), change)
+        } else {
+            debug!("[{package}] creating symlink from {from:?} to {to:?}");
+            let change =
+                Symlink::create(from, to, force, self.canonicalize).context("creating symlink")?;
+            (from.clone(), to.clone(), change)
+        };
+        Ok(vec![change])
+    }
+
+    /// Deploys with `deploy_spec`, then runs the spec's `on_change` command
+    /// if any target's contents differ from before.
+    fn process_with_spec(
+        &self,
+        package: &str,
+        from: &PathBuf,
+        to: &PathBuf,
+        spec: &TargetSpec,
+        variables: &Variables,
+    ) -> Result<Vec<Deployed>> {
+        let Some(command) = &spec.on_change else {
+            return self.deploy_spec(package, from, to, spec, variables);
+        };
+        let mut candidates = vec![
+            to.clone(),
+            to.strip_template_extension(self.template_extension),
+        ];
+        if spec.link_contents && from.is_dir() {
+            candidates.extend(
+                directory_files(from, to, spec, self.registries, variables)?
+                    .into_iter()
+                    .map(|(_, to)| to),
+            );
+        }
+        let before = candidates
+            .into_iter()
+            .map(|path| {
+                let contents = contents_of(&path);
+                (path, contents)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let deployed = self.deploy_spec(package, from, to, spec, variables)?;
+        let changed = deployed.iter().any(|(_, to, change)| {
+            change.outcome != Outcome::Skipped && before.get(to) != Some(&contents_of(to))
+        });
+        if changed {
+            info!("[{package}] {to:?} changed, running {command:?}");
+            let output = process::output(process::os_shell().arg(command), self.timeout)
+                .with_context(|| format!("running on_change command {command:?}"))?;
+            anyhow::ensure!(
+                output.status.success(),
+                "on_change command {command:?} failed with {}",
+                output.status
+            );
+        }
+
+        Ok(deployed)
+    }
+
+    fn deploy_spec(
+        &self,
+        package: &str,
+        from: &PathBuf,
+        to: &PathBuf,
+        spec: &TargetSpec,
+        variables: &Variables,
+    ) -> Result<Vec<Deployed>> {
+        if !applies(spec, self.registries, variables)? {
+            info!("[{package}] skipping {from:?}, its condition doesn't hold");
+            let change = Change::new("condition doesn't hold", Outcome::Skipped);
+            return Ok(vec![(from.clone(), to.clone(), change)]);
+        }
+        if spec.sudo {
+            return self.process_privileged(package, from, to, spec, variables);
+        }
+
+        let handlebars = self.registries.for_spec(spec);
+        let (force, canonicalize) = (self.forced(from, to)?, self.canonicalize);
+        let change = if spec.extract {
+            debug!("[{package}] extracting {from:?} into {to:?}");
+            return Ok(Archive::extract(from, to, force)
+                .context("extracting archive")?
+                .into_iter()
+                .map(|(to, change)| (from.clone(), to, change))
+                .collect());
+        } else if spec.link_contents {
+            debug!("[{package}] linking contents of {from:?} into {to:?}");
+            return directory_files(from, to, spec, self.registries, variables)?
+                .into_iter()
+                .map(|(from, to)| {
+                    let change = Symlink::create(&from, &to, force, canonicalize)
+                        .context("creating symlink")?;
+                    Ok((from, to, change))
+                })
+                .collect();
+        } else if spec.encrypted || has_encrypted_extension(from) {
+            let to = &strip_encrypted_extension(to);
+            debug!("[{package}] decrypting {from:?} into {to:?}");
+            let change = self
+                .decrypt(from, to)
+                .and_then(|plaintext| Decrypter::deploy(&plaintext, to, force))
+                .context("decrypting source")?;
+            protect(to, spec)?;
+            (from.clone(), to.clone(), change)
+        } else if spec.hardlink {
+            debug!("[{package}] hard linking {to:?} to {from:?}");
+            let change = Filesystem::hard_link(from, to, force).context("creating hard link")?;
+            (from.clone(), to.clone(), change)
+        } else if spec.binary_template {
+            let to = &to.strip_template_extension(self.template_extension);
+            debug!("[{package}] rendering binary template from {from:?} to {to:?}");
+            let change = Template::render_bytes(to, force, || {
+                self.render_bytes(from, to, handlebars, variables)
+            })
+            .context("rendering binary template")?;
+            protect(to, spec)?;
+            (from.clone(), to.clone(), change)
+        } else if from.is_template(self.template_extension)? {
+            let to = &to.strip_template_extension(self.template_extension);
+            debug!("[{package}] rendering template file from {from:?} to {to:?}");
+            let change = Template::render(from, to, force, self.normalize_eol, || {
+                self.render(from, to, handlebars, variables)
+            })
+            .context("rendering template")?;
+            protect(to, spec)?;
+            (from.clone(), to.clone(), change)
+        } else if !spec.symlink {
+            debug!("[{package}] copying file from {from:?} to {to:?}");
+            let change =
+                Filesystem::copy(from, to, force, &spec.ignored()).context("copying file")?;
+            protect(to, spec)?;
+            (from.clone(), to.clone(), change)
+        } else {
+            debug!("[{package}] creating symlink from {from:?} to {to:?}");
+            let change =
+                Symlink::create(from, to, force, canonicalize).context("creating symlink")?;
+            (from.clone(), to.clone(), change)
+        };
+        Ok(vec![change])
+    }
+
+    /// Same as `process_with_spec`, but every change to the target goes
+    /// through the privilege escalation command.
+    fn process_privileged(
+        &self,
+        package: &str,
+        from: &PathBuf,
+        to: &PathBuf,
+        spec: &TargetSpec,
+        variables: &Variables,
+    ) -> Result<Vec<Deployed>> {
+        anyhow::ensure!(
+            !spec.extract,
+            "extracting archives with elevated privileges isn't supported"
+        );
+        anyhow::ensure!(
+            !spec.encrypted && !has_encrypted_extension(from),
+            "decrypting sources with elevated privileges isn't supported"
+        );
+        anyhow::ensure!(
+            !spec.hardlink,
+            "hard linking with elevated privileges isn't supported"
+        );
+        let handlebars = self.registries.for_spec(spec);
+        let (privileged, canonicalize) = (self.privileged, self.canonicalize);
+        let force = self.forced(from, to)?;
+        let change = if spec.link_contents {
+            debug!("[{package}] linking contents of {from:?} into {to:?} with elevated privileges");
+            return directory_files(from, to, spec, self.registries, variables)?
+                .into_iter()
+                .map(|(from, to)| {
+                    let change = privileged
+                        .symlink(&from, &to, force, canonicalize)
+                        .context("creating symlink")?;
+                    Ok((from, to, change))
+                })
+                .collect();
+        } else if spec.binary_template {
+            let to = &to.strip_template_extension(self.template_extension);
+            debug!("[{package}] rendering binary template from {from:?} to {to:?} with elevated privileges");
+            let rendered = self
+                .render_bytes(from, to, handlebars, variables)
+                .context("rendering binary template")?;
+            let change = privileged
+                .write(&rendered, to, force)
+                .context("writing rendered template")?;
+            privileged.chown(to, spec.owner.as_deref(), spec.group.as_deref())?;
+            if spec.read_only {
+                privileged.set_read_only(to)?;
+            }
+            (from.clone(), to.clone(), change)
+        } else if from.is_template(self.template_extension)? {
+            let to = &to.strip_template_extension(self.template_extension);
+            debug!("[{package}] rendering template file from {from:?} to {to:?} with elevated privileges");
+            let rendered = self
+                .render(from, to, handlebars, variables)
+                .context("rendering template")?;
+            let change = privileged
+                .write(rendered.as_bytes(), to, force)
+                .context("writing rendered template")?;
+            privileged.chown(to, spec.owner.as_deref(), spec.group.as_deref())?;
+            if spec.read_only {
+                privileged.set_read_only(to)?;
+            }
+            (from.clone(), to.clone(), change)
+        } else if !spec.symlink {
+            debug!("[{package}] copying file from {from:?} to {to:?} with elevated privileges");
+            let change = privileged.copy(from, to, force).context("copying file")?;
+            privileged.chown(to, spec.owner.as_deref(), spec.group.as_deref())?;
+            if spec.read_only {
+                privileged.set_read_only(to)?;
+            }
+            (from.clone(), to.clone(), change)
+        } else {
+            debug!("[{package}] creating symlink from {from:?} to {to:?} with elevated privileges");
+            let change = privileged
+                .symlink(from, to, force, canonicalize)
+                .context("creating symlink")?;
+            (from.clone(), to.clone(), change)
+        };
+        Ok(vec![change])
+    }
+}
+
+/// Points an error caused by missing permissions on `to` at what to do
+/// about it, leaving other errors alone.
+fn explain_denied(error: anyhow::Error, to: &Path) -> anyhow::Error {
+    let denied = error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == ErrorKind::PermissionDenied)
+    });
+    match denied {
+        true => error.context(format!(
+            "permission denied deploying to {to:?}, check who owns it and its directory, \
+             or deploy it with `sudo: true`"
+        )),
+        false => error,
+    }
+}
+
+/// The bytes of the file at `path`, or where it links to, to tell whether
+/// deploying changed it.
+fn contents_of(path: &Path) -> Option<Vec<u8>> {
+    match fs::read_link(path) {
+        Ok(link) => Some(link.into_os_string().into_vec()),
+        Err(_) => fs::read(path).ok(),
+    }
+}
+
+/// Applies the ownership and permissions a spec asks for to a deployed file.
+fn protect(to: &Path, spec: &TargetSpec) -> Result<()> {
+    if (spec.owner.is_some() || spec.group.is_some()) && to.exists() {
+        debug!("changing ownership of {to:?}");
+        Filesystem::chown(to, spec.owner.as_deref(), spec.group.as_deref())?;
+    }
+    if spec.read_only && to.exists() {
+        debug!("making {to:?} read-only");
+        Filesystem::set_read_only(to, true).context("making target read-only")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn load_config(dir: &Path, contents: &str) -> Result<Configuration> {
+        let path = dir.join("config.yaml");
+        fs::write(&path, contents)?;
+        config::load_config(&path, None, None, false)
+    }
+
+    #[test]
+    fn should_keep_only_matching_files() {
+        let files = vec![
+            (
+                PathBuf::from("bash/.bashrc"),
+                FileTarget::Simple("~/.bashrc".into()),
+            ),
+            (
+                PathBuf::from("zsh/.zshrc"),
+                FileTarget::Simple("~/.zshrc".into()),
+            ),
+            (
+                PathBuf::from("nvim/init.lua"),
+                FileTarget::WithSpec(TargetSpec {
+                    to: "~/.config/nvim/init.lua".into(),
+                    symlink: true,
+                    ..Default::default()
+                }),
+            ),
+        ]
+        .into_iter()
+        .collect::<Files>();
+
+        let (matching, skipped) = matching_files(files, ".zshrc");
+
+        assert_eq!(skipped, 2);
+        assert_eq!(matching.len(), 1);
+        assert!(matching.contains_key(&PathBuf::from("zsh/.zshrc")));
+    }
+
+    #[test]
+    fn should_keep_going_past_failed_files() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let broken = dir.path().join("broken.conf");
+        fs::write(&broken, "{{ missing }}")?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "setting = 1")?;
+        let target = dir.path().join("target.conf");
+        let contents = format!(
+            "app:\n  files:\n    {}: {}\n    {}:\n      to: {}\n      symlink: false\n",
+            broken.display(),
+            dir.path().join("broken-target.conf").display(),
+            source.display(),
+            target.display()
+        );
+
+        let result = deploy(load_config(dir.path(), &contents)?, Options::default());
+        assert!(result.is_err());
+
+        let opts = Options {
+            keep_going: true,
+            ..Default::default()
+        };
+        let result = deploy(load_config(dir.path(), &contents)?, opts);
+
+        let error = format!("{:?}", result.unwrap_err());
+        assert!(error.contains("1 file(s) failed to deploy"));
+        assert!(error.contains("broken.conf"));
+        assert_eq!(fs::read_to_string(target)?, "setting = 1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_prefer_overrides_to_config_variables() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let simple = dir.path().join("simple.conf");
+        fs::write(&simple, "editor = {{ editor }}")?;
+        let spec = dir.path().join("spec.conf");
+        fs::write(&spec, "editor = {{ editor }}")?;
+        let target = dir.path().join("target");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "variables:\n  editor: vim\napp:\n  variables:\n    editor: emacs\n  files:\n    \
+                 {}: {target}/simple.conf\n    {}:\n      to: {target}/spec.conf\n      symlink: false\n",
+                simple.display(),
+                spec.display(),
+                target = target.display()
+            ),
+        )?;
+        let opts = Options {
+            overrides: vec![("editor".to_string(), "nano".to_string())],
+            ..Default::default()
+        };
+
+        deploy(config, opts)?;
+
+        assert_eq!(
+            fs::read_to_string(target.join("simple.conf"))?,
+            "editor = nano"
+        );
+        assert_eq!(
+            fs::read_to_string(target.join("spec.conf"))?,
+            "editor = nano"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_prefer_environment_to_config_variables() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let mut config = load_config(
+            dir.path(),
+            "variables:\n  name: desktop\n  shell: zsh\napp:\n  variables:\n    gitUser: me\n  files: {}\n",
+        )?;
+        let opts = Options {
+            overrides: vec![("shell".to_string(), "fish".to_string())],
+            ..Default::default()
+        };
+        let env = [
+            ("PONTO_VAR_NAME", "laptop"),
+            ("PONTO_VAR_SHELL", "bash"),
+            ("PONTO_VAR_gitUser", "you"),
+        ]
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+
+        layer_variables(&mut config, &opts, env)?;
+
+        assert_eq!(config.variables["name"], "laptop");
+        assert_eq!(config.variables["shell"], "fish");
+        assert_eq!(config.packages["app"].variables["gitUser"], "you");
+        assert_eq!(config.packages["app"].variables["shell"], "fish");
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_touch_targets_on_dry_run() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+        let opts = Options {
+            dry_run: true,
+            verify: true,
+            ..Default::default()
+        };
+
+        deploy(config, opts)?;
+
+        assert!(!target.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_plan_each_file_with_its_action() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let template = dir.path().join("init.lua");
+        fs::write(&template, "editor = {{ editor }}")?;
+        let linked = dir.path().join("lua");
+        fs::create_dir_all(&linked)?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "variables:\n  editor: nvim\nnvim:\n  files:\n    {template:?}: {:?}\n    {linked:?}: {:?}\n",
+                dir.path().join("out").join("init.lua"),
+                dir.path().join("out").join("lua"),
+            ),
+        )?;
+        let opts = Options {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let plan = plan(&config, &opts, &Registries::new(opts.retry_policy(), None)?)?;
+
+        let (name, steps) = &plan.packages[0];
+        assert_eq!(name, "nvim");
+        let planned = steps
+            .iter()
+            .map(|step| (step.source.clone(), step.action, step.state.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            planned,
+            [
+                (template, Action::Render, "templated file doesn't exist"),
+                (linked, Action::Link, "target missing"),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decrypt_encrypted_sources_without_age_extension() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("token.age");
+        fs::write(&source, "-----BEGIN AGE ENCRYPTED FILE-----")?;
+        let target = FileTarget::Simple(dir.path().join("token.age"));
+
+        let (action, targets) = resolve(&source, &target, None)?;
+
+        assert_eq!(action, Action::Decrypt);
+        assert_eq!(targets, [dir.path().join("token")]);
+
+        let plain = dir.path().join("token");
+        fs::write(&plain, "-----BEGIN AGE ENCRYPTED FILE-----")?;
+        let spec = FileTarget::WithSpec(TargetSpec {
+            to: dir.path().join("out").into(),
+            encrypted: true,
+            ..Default::default()
+        });
+        assert_eq!(resolve(&plain, &spec, None)?.0, Action::Decrypt);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_on_warnings_when_asked() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "new")?;
+        let target = dir.path().join("out").join("app.conf");
+        fs::create_dir_all(target.parent().unwrap())?;
+        fs::write(&target, "old")?;
+        let contents = format!(
+            "app:\n  files:\n    {source:?}:\n      to: {target:?}\n      symlink: false\n"
+        );
+
+        let report = deploy(load_config(dir.path(), &contents)?, Options::default())?;
+        assert_eq!(report.warnings().len(), 1);
+
+        let opts = Options {
+            warnings_as_errors: true,
+            ..Default::default()
+        };
+        let error = deploy(load_config(dir.path(), &contents)?, opts).unwrap_err();
+        assert!(error.to_string().contains("app: file"), "{error}");
+        assert!(error.to_string().contains("already exists, skipping"));
+        assert_eq!(fs::read_to_string(&target)?, "old");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_once_per_home() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("bashrc");
+        fs::write(&source, "export EDITOR=vim")?;
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            format!("app:\n  files:\n    {source:?}:\n      to: ~/.bashrc\n      symlink: false\n"),
+        )?;
+        let homes = [dir.path().join("alice"), dir.path().join("bob")];
+        let load = |opts: Options| {
+            Ok((
+                config::load_config(&path, None, opts.home.as_deref(), false)?,
+                opts,
+            ))
+        };
+        let opts = Options {
+            homes: homes.to_vec(),
+            quiet: true,
+            ..Default::default()
+        };
+
+        let error = run_homes(
+            Options {
+                list_drift: true,
+                ..opts.clone()
+            },
+            load,
+        )
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("drifted"), "{error:#}");
+        assert!(homes.iter().all(|home| !home.exists()));
+
+        run_homes(opts, load)?;
+
+        for home in &homes {
+            assert_eq!(
+                fs::read_to_string(home.join(".bashrc"))?,
+                "export EDITOR=vim"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_record_filesystem_operations_in_trace_file() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "plain")?;
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            format!("app:\n  files:\n    {source:?}: ~/app.conf\n"),
+        )?;
+        let homes = [dir.path().join("alice"), dir.path().join("bob")];
+        let trace_file = dir.path().join("trace.jsonl");
+        let opts = Options {
+            homes: homes.to_vec(),
+            quiet: true,
+            ..Default::default()
+        };
+
+        let trace = crate::fs_trace::Trace::start(&trace_file)?;
+        run_homes(opts, |opts| {
+            Ok((
+                config::load_config(&path, None, opts.home.as_deref(), false)?,
+                opts,
+            ))
+        })?;
+        drop(trace);
+
+        // other tests may run at the same time and be recorded too
+        let operations = fs::read_to_string(&trace_file)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        let mine = operations
+            .iter()
+            .filter(|op| {
+                op["to"].as_str().is_some_and(|to| {
+                    homes
+                        .iter()
+                        .any(|home| to.starts_with(home.to_str().unwrap()))
+                })
+            })
+            .collect::<Vec<_>>();
+        // every home is recorded, not only the last one
+        let expected = homes
+            .iter()
+            .flat_map(|home| {
+                [
+                    serde_json::json!({"op": "mkdir", "from": null, "to": home, "result": "ok"}),
+                    serde_json::json!({"op": "symlink", "from": source, "to": home.join("app.conf"), "result": "ok"}),
+                ]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(mine, expected.iter().collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_the_same_after_migrating_config() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let rc = dir.path().join("rc");
+        fs::write(&rc, "editor = {{ editor }}, shell = {{ shell }}")?;
+        let env = dir.path().join("env");
+        fs::write(&env, "editor = {{ editor }}, shell = {{ shell }}")?;
+        let plain = dir.path().join("plain");
+        fs::write(&plain, "no variables")?;
+        let target = dir.path().join("out");
+        let path = dir.path().join("config.yaml");
+        fs::write(
+            &path,
+            format!(
+                "variables:\n  editor: vim\n  shell: bash\napp:\n  variables:\n    shell: zsh\n  files:\n    \
+                 {rc:?}: {:?}\n    {plain:?}: {:?}\n    \
+                 {env:?}:\n      to: {:?}\n      symlink: false\n      read_only: false\n",
+                target.join("rc"),
+                target.join("plain"),
+                target.join("env"),
+            ),
+        )?;
+        let deployed = || -> Result<Vec<String>> {
+            let config = config::load_config(&path, None, None, false)?;
+            deploy(config, Options::default())?;
+            let mut contents = ["rc", "env"]
+                .map(|name| fs::read_to_string(target.join(name)))
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+            contents.push(fs::read_link(target.join("plain"))?.display().to_string());
+            fs::remove_dir_all(&target)?;
+            Ok(contents)
+        };
+
+        let before = deployed()?;
+        fs::write(&path, config::migrate_config(&path, None, false)?)?;
+        let after = deployed()?;
+
+        let plain = plain.canonicalize()?.display().to_string();
+        assert_eq!(
+            before,
+            [
+                "editor = vim, shell = zsh",
+                "editor = vim, shell = zsh",
+                &plain
+            ]
+        );
+        assert_eq!(before, after);
+        let migrated = config::load_config(&path, None, None, false)?;
+        assert!(migrated.packages["app"]
+            .files
+            .values()
+            .all(|target| matches!(target, FileTarget::WithSpec(_))));
+        assert!(!fs::read_to_string(&path)?.contains("read_only"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_on_missing_sources() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let present = dir.path().join("present.conf");
+        fs::write(&present, "here")?;
+        let missing = dir.path().join("missing.conf");
+        let gone = dir.path().join("gone.conf");
+        let target = dir.path().join("out");
+        let contents = format!(
+            "app:\n  files:\n    {present:?}: {:?}\n    {missing:?}: {:?}\nshell:\n  files:\n    {gone:?}: {:?}\n",
+            target.join("present.conf"),
+            target.join("missing.conf"),
+            target.join("gone.conf"),
+        );
+
+        let opts = Options {
+            fail_on_missing_source: true,
+            ..Default::default()
+        };
+        let error = deploy(load_config(dir.path(), &contents)?, opts).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "2 configured source(s) missing:\napp: {}\nshell: {}",
+                missing.display(),
+                gone.display()
+            )
+        );
+        assert!(!target.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_deploy_when_nothing_changed() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "editor = {{ editor }}")?;
+        let target = dir.path().join("out").join("app.conf");
+        let contents = |editor: &str| {
+            format!("variables:\n  editor: {editor}\napp:\n  files:\n    {source:?}: {target:?}\n")
+        };
+        let opts = || Options {
+            skip_if_unchanged: true,
+            state_dir: Some(dir.path().join("state")),
+            ..Default::default()
+        };
+
+        let report = deploy(load_config(dir.path(), &contents("vim"))?, opts())?;
+        assert_eq!(report.count(Outcome::Created), 1);
+        fs::remove_file(&target)?;
+
+        // targets aren't looked at, only the config and sources
+        let report = deploy(load_config(dir.path(), &contents("vim"))?, opts())?;
+        assert!(report.actions.is_empty());
+        assert!(!target.exists());
+
+        let report = deploy(load_config(dir.path(), &contents("nvim"))?, opts())?;
+        assert_eq!(report.count(Outcome::Created), 1);
+
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&source)?
+            .set_modified(later)?;
+        let report = deploy(load_config(dir.path(), &contents("nvim"))?, opts())?;
+        assert_eq!(report.actions.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_use_script_helpers_when_allowed() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("deploy")?;
+        let script = dir.path().join("greet.sh");
+        fs::write(&script, "#!/bin/sh\nprintf 'Hello, %s!' \"$1\"\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        let source = dir.path().join("greeting");
+        fs::write(&source, "{{ greet \"world\" }}")?;
+        let target = dir.path().join("out").join("greeting");
+        let contents =
+            format!("helpers:\n  greet: {script:?}\napp:\n  files:\n    {source:?}: {target:?}\n");
+
+        let error = deploy(load_config(dir.path(), &contents)?, Options::default()).unwrap_err();
+        assert!(error.to_string().contains("--allow-command-helpers"));
+
+        let opts = Options {
+            allow_command_helpers: true,
+            ..Default::default()
+        };
+        deploy(load_config(dir.path(), &contents)?, opts)?;
+        assert_eq!(fs::read_to_string(&target)?, "Hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_run_script_helpers_once_when_forced() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("deploy")?;
+        let calls = dir.path().join("calls");
+        let script = dir.path().join("greet.sh");
+        fs::write(
+            &script,
+            format!("#!/bin/sh\necho >> {calls:?}\nprintf 'Hello, %s!' \"$1\"\n"),
+        )?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        let source = dir.path().join("greeting");
+        fs::write(&source, "{{ greet \"world\" }}")?;
+        let target = dir.path().join("out").join("greeting");
+        fs::create_dir(dir.path().join("out"))?;
+        fs::write(&target, "Hello, stale!")?;
+        let contents =
+            format!("helpers:\n  greet: {script:?}\napp:\n  files:\n    {source:?}: {target:?}\n");
+        let opts = Options {
+            allow_command_helpers: true,
+            force: true,
+            ..Default::default()
+        };
+
+        deploy(load_config(dir.path(), &contents)?, opts)?;
+
+        assert_eq!(fs::read_to_string(&target)?, "Hello, world!");
+        assert_eq!(fs::read_to_string(&calls)?.lines().count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_reuse_expected_contents_of_changed_sources() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let (from, to) = (dir.path().join("source"), dir.path().join("target"));
+        fs::write(&from, "{{ a }}")?;
+        let expected = Expected::default();
+
+        expected.insert(&from, &to, b"1");
+        assert_eq!(expected.take(&from, &to), Some(b"1".to_vec()));
+        assert_eq!(expected.take(&from, &to), None);
+
+        expected.insert(&from, &to, b"1");
+        fs::write(&from, "{{ b }}")?;
+        assert_eq!(expected.take(&from, &to), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_explain_permission_denied_on_targets() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let to = Path::new("/etc/app.conf");
+        let denied = anyhow::Error::new(std::io::Error::from(ErrorKind::PermissionDenied))
+            .context("create file");
+        let message = format!("{:#}", explain_denied(denied, to));
+        assert!(message.contains("\"/etc/app.conf\""), "{message}");
+        assert!(message.contains("sudo: true"), "{message}");
+        let missing = anyhow::Error::new(std::io::Error::from(ErrorKind::NotFound));
+        assert!(!format!("{:#}", explain_denied(missing, to)).contains("sudo"));
+
+        // root can write anywhere, so only the message is checked then
+        // SAFETY: geteuid has no preconditions and can't fail
+        if unsafe { libc::geteuid() } == 0 {
+            return Ok(());
+        }
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "{{ name }}")?;
+        let locked = dir.path().join("locked");
+        fs::create_dir_all(&locked)?;
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555))?;
+        let target = locked.join("app.conf");
+        let contents =
+            format!("variables:\n  name: app\napp:\n  files:\n    {source:?}: {target:?}\n");
+
+        let error = deploy(load_config(dir.path(), &contents)?, Options::default()).unwrap_err();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+        assert!(format!("{error:#}").contains("sudo: true"), "{error:#}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_stop_cleanly_when_pre_hook_aborts() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "app")?;
+        let target = dir.path().join("out").join("app.conf");
+        let pre = dir.path().join("pre.sh");
+        fs::write(&pre, format!("exit {ABORT_EXIT_CODE}"))?;
+        let contents = format!("app:\n  files:\n    {source:?}: {target:?}\n");
+        let opts = |pre: &Path| Options {
+            pre: Some(pre.to_owned()),
+            ..Default::default()
+        };
+
+        let report = deploy(load_config(dir.path(), &contents)?, opts(&pre))?;
+
+        assert!(report.actions.is_empty());
+        assert!(!target.exists());
+        assert!(!pre.with_extension("templated").exists());
+
+        fs::write(&pre, "exit 1")?;
+        let error = deploy(load_config(dir.path(), &contents)?, opts(&pre)).unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_under_given_umask() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("token.conf");
+        fs::write(&source, "token = {{ token }}")?;
+        let target = dir.path().join("out").join("token.conf");
+        let config = load_config(
+            dir.path(),
+            &format!("variables:\n  token: secret\napp:\n  files:\n    {source:?}: {target:?}\n"),
+        )?;
+        let _outer = Umask::set(0o022);
+
+        deploy(
+            config,
+            Options {
+                umask: Some(0o077),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(fs::metadata(&target)?.permissions().mode() & 0o777, 0o600);
+        // SAFETY: umask has no preconditions; this sets the same mask back
+        let restored = unsafe { libc::umask(0o022) };
+        assert_eq!(restored, 0o022);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_ask_before_forcing_over_many_targets() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "new")?;
+        for name in ["a.conf", "b.conf", "c.conf"] {
+            fs::write(dir.path().join(name), "old")?;
+        }
+        fs::write(dir.path().join("same.conf"), "new")?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {source:?}:\n      symlink: false\n      to: [{:?}, {:?}, {:?}, {:?}]\n",
+                dir.path().join("a.conf"),
+                dir.path().join("b.conf"),
+                dir.path().join("c.conf"),
+                dir.path().join("same.conf"),
+            ),
+        )?;
+        let opts = Options {
+            force: true,
+            force_threshold: Some(2),
+            ..Default::default()
+        };
+        let registries = Registries::new(opts.retry_policy(), config.partials_dir.as_deref())?;
+        let overwrites = forced_overwrites(&config, &opts, &registries, &Expected::default())?;
+        assert_eq!(overwrites.len(), 3);
+
+        let confirm = |opts: &Options, answer: &str, interactive| {
+            let mut prompt = vec![];
+            let result = confirm_forced(
+                &overwrites,
+                opts,
+                &mut answer.as_bytes(),
+                &mut prompt,
+                interactive,
+            );
+            (result, String::from_utf8(prompt).unwrap())
+        };
+        let (result, prompt) = confirm(&opts, "n\n", true);
+        assert!(result.is_err());
+        assert!(prompt.contains("overwrite the 3 targets"));
+        assert!(confirm(&opts, "y\n", true).0.is_ok());
+        assert!(confirm(&opts, "", false).0.is_err());
+
+        let below = Options {
+            force_threshold: Some(3),
+            ..opts.clone()
+        };
+        let (result, prompt) = confirm(&below, "", true);
+        assert!(result.is_ok());
+        assert!(prompt.is_empty());
+        let yes = Options { yes: true, ..opts };
+        assert!(confirm(&yes, "", false).0.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_listed_files_in_declared_order() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let mut entries = String::new();
+        for name in ["zeta", "alpha", "mid", "beta"] {
+            let source = dir.path().join(name);
+            fs::write(&source, name)?;
+            entries += &format!(
+                "    - from: {}\n      to: {}\n",
+                source.display(),
+                dir.path().join(format!("{name}.link")).display()
+            );
+        }
+        let config = load_config(dir.path(), &format!("app:\n  files:\n{entries}"))?;
+
+        let report = deploy(config, Options::default())?;
+
+        let deployed = report
+            .actions
+            .iter()
+            .map(|action| action.source.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(deployed, ["zeta", "alpha", "mid", "beta"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_force_only_named_files() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let mut files = String::new();
+        for name in ["forced", "kept"] {
+            let source = dir.path().join(format!("{name}.src"));
+            fs::write(&source, "deployed")?;
+            let target = dir.path().join(format!("{name}.conf"));
+            fs::write(&target, "drifted")?;
+            files += &format!("    {source:?}:\n      to: {target:?}\n      symlink: false\n");
+        }
+        let config = load_config(dir.path(), &format!("app:\n  files:\n{files}"))?;
+        let opts = Options {
+            force_files: vec![dir.path().join("forced.conf")],
+            ..Default::default()
+        };
+
+        let report = deploy(config, opts)?;
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("forced.conf"))?,
+            "deployed"
+        );
+        assert_eq!(fs::read_to_string(dir.path().join("kept.conf"))?, "drifted");
+        assert_eq!(report.count(Outcome::Updated), 1);
+        assert_eq!(report.count(Outcome::Skipped), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_many_files_in_parallel() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let mut files = String::new();
+        for i in 0..200 {
+            let source = dir.path().join(format!("{i}.src"));
+            fs::write(&source, format!("{i}: {{{{ name }}}}"))?;
+            let target = dir.path().join(format!("{i}.conf"));
+            files += &format!("    {source:?}: {target:?}\n");
+        }
+        let config = load_config(
+            dir.path(),
+            &format!("variables:\n  name: ponto\napp:\n  files:\n{files}"),
+        )?;
+        let opts = Options {
+            parallel_files: true,
+            ..Default::default()
+        };
+
+        let report = deploy(config, opts)?;
+
+        assert_eq!(report.count(Outcome::Created), 200);
+        for (i, action) in report.actions.iter().enumerate() {
+            assert_eq!(action.target, dir.path().join(format!("{i}.conf")));
+            assert_eq!(fs::read_to_string(&action.target)?, format!("{i}: ponto"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_stop_starting_items_after_one_fails() {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let items = (0..threads * 10).collect::<Vec<_>>();
+
+        let results = parallel_map(
+            &items,
+            |&i| match i {
+                0 => Err(i),
+                _ => {
+                    std::thread::sleep(Duration::from_millis(100));
+                    Ok(i)
+                }
+            },
+            Result::is_err,
+        );
+
+        assert_eq!(results[0], Err(0));
+        assert!(results.len() <= threads, "{} processed", results.len());
+        let all = parallel_map(&items, |&i| i, |_| false);
+        assert_eq!(all, items);
+    }
+
+    #[test]
+    fn should_keep_ordered_and_colliding_files_sequential() {
+        let file = |from: &str, to: &str| (PathBuf::from(from), FileTarget::Simple(to.into()));
+        let listed = Package {
+            listed: true,
+            ..Default::default()
+        };
+        let files = [file("a", "x"), file("b", "y")];
+
+        assert!(can_run_in_parallel("app", &Package::default(), &files));
+        assert!(!can_run_in_parallel("app", &listed, &files));
+        assert!(!can_run_in_parallel(
+            "app",
+            &Package::default(),
+            &[file("a", "x"), file("b", "x")]
+        ));
+    }
+
+    #[test]
+    fn should_run_on_change_only_when_target_changes() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "first")?;
+        let target = dir.path().join("target.conf");
+        let log = dir.path().join("changes.log");
+        let contents = format!(
+            "app:\n  files:\n    {source:?}:\n      to: {target:?}\n      symlink: false\n      on_change: echo changed >> {log:?}\n"
+        );
+        let runs = || fs::read_to_string(&log).map_or(0, |log| log.lines().count());
+        let forced = || Options {
+            force: true,
+            ..Default::default()
+        };
+
+        deploy(load_config(dir.path(), &contents)?, Options::default())?;
+        assert_eq!(runs(), 1);
+        deploy(load_config(dir.path(), &contents)?, Options::default())?;
+        deploy(load_config(dir.path(), &contents)?, forced())?;
+        assert_eq!(runs(), 1);
+
+        fs::write(&source, "second")?;
+        deploy(load_config(dir.path(), &contents)?, forced())?;
+        assert_eq!(runs(), 2);
+        assert_eq!(fs::read_to_string(&target)?, "second");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_read_only_targets_again() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "name = {{ name }}")?;
+        let target = dir.path().join("target.conf");
+        let contents = |name: &str| {
+            format!(
+                "app:\n  variables:\n    name: {name}\n  files:\n    {}:\n      to: {}\n      symlink: false\n      read_only: true\n",
+                source.display(),
+                target.display()
+            )
+        };
+
+        deploy(
+            load_config(dir.path(), &contents("ponto"))?,
+            Options::default(),
+        )?;
+        assert_eq!(fs::metadata(&target)?.permissions().mode() & 0o222, 0);
+
+        let opts = Options {
+            force: true,
+            ..Default::default()
+        };
+        deploy(load_config(dir.path(), &contents("dotfiles"))?, opts)?;
+
+        assert_eq!(fs::read_to_string(&target)?, "name = dotfiles");
+        assert_eq!(fs::metadata(&target)?.permissions().mode() & 0o222, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_outcome_of_each_target() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let template = dir.path().join("app.conf");
+        fs::write(&template, "name = {{ name }}")?;
+        let link = dir.path().join("app.sh");
+        fs::write(&link, "echo app")?;
+        let target = dir.path().join("target");
+        let contents = |name: &str| {
+            format!(
+                "variables:\n  name: {name}\napp:\n  files:\n    {}: {target}/app.conf\n    {}: {target}/app.sh\n",
+                template.display(),
+                link.display(),
+                target = target.display()
+            )
+        };
+        let outcome = |report: &Report, file: &str| {
+            report
+                .actions
+                .iter()
+                .find(|action| action.target == target.join(file))
+                .map(|action| action.outcome)
+        };
+
+        let report = deploy(
+            load_config(dir.path(), &contents("ponto"))?,
+            Options::default(),
+        )?;
+        assert_eq!(outcome(&report, "app.conf"), Some(Outcome::Created));
+        assert_eq!(outcome(&report, "app.sh"), Some(Outcome::Created));
+
+        let report = deploy(
+            load_config(dir.path(), &contents("dotfiles"))?,
+            Options::default(),
+        )?;
+        assert_eq!(outcome(&report, "app.conf"), Some(Outcome::Updated));
+        assert_eq!(outcome(&report, "app.sh"), Some(Outcome::Skipped));
+        assert!(report.actions.iter().all(|action| action.package == "app"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_absolute_targets() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = |name: &str| -> Result<PathBuf> {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, name)?;
+            Ok(path)
+        };
+        let (zshrc, env, plain) = (source("zshrc")?, source("env.tmpl")?, source("plain")?);
+        let (gitconfig, token, skipped) =
+            (source("gitconfig")?, source("token.age")?, source("off")?);
+        let nvim = source("nvim/init.lua")?.parent().unwrap().to_owned();
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "template_extension: tmpl\n\
+                 zsh:\n  files:\n    {zshrc:?}: /home/me/.zshrc\n    {env:?}: relative/env.tmpl\n    \
+                 {plain:?}: /home/me/plain.tmpl\n\
+                 git:\n  files:\n    {gitconfig:?}:\n      to: [/home/me/.gitconfig, /etc/gitconfig]\n      symlink: false\n\
+                 secrets:\n  files:\n    {token:?}: /home/me/token.age\n    {skipped:?}:\n      to: /home/me/off\n      symlink: true\n      when: \"false\"\n\
+                 nvim:\n  files:\n    {nvim:?}:\n      to: /home/me/.config/nvim\n      symlink: true\n      link_contents: true\n",
+            ),
+        )?;
+
+        let targets = resolved_targets(&config, &Options::default())?;
+
+        let cwd = std::env::current_dir()?;
+        assert_eq!(
+            targets,
+            [
+                (
+                    "git".to_string(),
+                    vec![
+                        (gitconfig.clone(), "/etc/gitconfig".into()),
+                        (gitconfig, "/home/me/.gitconfig".into()),
+                    ]
+                ),
+                (
+                    "nvim".to_string(),
+                    vec![(
+                        nvim.join("init.lua"),
+                        "/home/me/.config/nvim/init.lua".into()
+                    )]
+                ),
+                (
+                    "secrets".to_string(),
+                    vec![(token, "/home/me/token".into())]
+                ),
+                (
+                    "zsh".to_string(),
+                    vec![
+                        (env, cwd.join("relative/env")),
+                        (plain, "/home/me/plain.tmpl".into()),
+                        (zshrc, "/home/me/.zshrc".into()),
+                    ]
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_time_each_package() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "base:\n  files:\n    {source:?}: {:?}\napp:\n  depends: [base]\n  files:\n    {source:?}: {:?}\n",
+                dir.path().join("base.txt"),
+                dir.path().join("app.txt"),
+            ),
+        )?;
+
+        let report = deploy(config, Options::default())?;
+
+        let packages = report
+            .packages
+            .iter()
+            .map(|timing| timing.package.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(packages, ["base", "app"]);
+        let sum: Duration = report.packages.iter().map(|timing| timing.duration).sum();
+        assert!(report.total >= sum);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_only_templates_that_fail_to_render() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let good = dir.path().join("good.conf");
+        fs::write(&good, "editor = {{ editor }}")?;
+        let broken = dir.path().join("broken.conf");
+        fs::write(&broken, "shell = {{ shell }}")?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "variables:\n  editor: vim\napp:\n  files:\n    {good:?}: {:?}\n    {broken:?}: {:?}\n",
+                dir.path().join("good.out"),
+                dir.path().join("broken.out"),
+            ),
+        )?;
+
+        let errors = template_errors(&config, &Options::default())?;
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, broken);
+        assert!(format!("{:#}", errors[0].1).contains("shell"));
+        assert!(!dir.path().join("good.out").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_exit_with_drift_code_until_deployed() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+        let contents = format!("app:\n  files:\n    {source:?}: {target:?}\n");
+
+        let error =
+            list_drift(load_config(dir.path(), &contents)?, Options::default()).unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 3);
+        assert!(!target.exists());
+
+        deploy(load_config(dir.path(), &contents)?, Options::default())?;
+        list_drift(load_config(dir.path(), &contents)?, Options::default())?;
+
+        // replace the deployed symlink with a copy of its own
+        fs::remove_file(&target)?;
+        fs::write(&target, "Hello, world!")?;
+        let error =
+            list_drift(load_config(dir.path(), &contents)?, Options::default()).unwrap_err();
+        assert_eq!(crate::error::exit_code(&error), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_report_as_json() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+        let summary = dir.path().join("summary.json");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+        let opts = Options {
+            summary_json: Some(summary.clone()),
+            ..Default::default()
+        };
+
+        deploy(config, opts)?;
+
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(summary)?)?;
+        let action = &json["actions"][0];
+        assert_eq!(action["package"], "app");
+        assert_eq!(action["target"], target.display().to_string());
+        assert_eq!(action["outcome"], "created");
+        assert_eq!(action["state"], "target missing");
+        assert!(action["duration_ms"].is_number());
+        assert_eq!(json["packages"][0]["package"], "app");
+        assert!(json["total_ms"].is_number());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_report_as_json_when_a_file_fails() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        // a file where the target's parent directory should be
+        fs::write(dir.path().join("blocked"), "")?;
+        let target = dir.path().join("blocked").join("target.txt");
+        let summary = dir.path().join("summary.json");
+
+        let config = load_config(
+            dir.path(),
+            &format!("app:\n  files:\n    {source:?}: {target:?}\n"),
+        )?;
+        let opts = Options {
+            summary_json: Some(summary.clone()),
+            ..Default::default()
+        };
+
+        assert!(deploy(config, opts).is_err());
+
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(summary)?)?;
+        assert_eq!(json["actions"][0]["target"], target.display().to_string());
+        assert_eq!(json["actions"][0]["outcome"], "failed");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_targets_whose_condition_fails() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        for file in [".Xresources", ".bashrc"] {
+            fs::write(dir.path().join(file), file)?;
+        }
+        let target = dir.path().join("home");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "shell:\n  variables:\n    gui: false\n  files:\n    \
+                 {root}/.Xresources:\n      to: {target}/.Xresources\n      symlink: false\n      when: \"{{{{ gui }}}}\"\n    \
+                 {root}/.bashrc:\n      to: {target}/.bashrc\n      symlink: false\n",
+                root = dir.path().display(),
+                target = target.display()
+            ),
+        )?;
+        let opts = Options {
+            verify: true,
+            ..Default::default()
+        };
+
+        let report = deploy(config, opts)?;
+
+        assert!(!target.join(".Xresources").exists());
+        assert_eq!(fs::read_to_string(target.join(".bashrc"))?, ".bashrc");
+        assert_eq!(report.count(Outcome::Skipped), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_symlinked_template_source() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let real = dir.path().join("shared.conf");
+        fs::write(&real, "name = {{ name }}")?;
+        let source = dir.path().join("app.conf");
+        std::os::unix::fs::symlink(&real, &source)?;
+        let target = dir.path().join("target.conf");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "variables:\n  name: ponto\napp:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+
+        deploy(config, Options::default())?;
+
+        assert_eq!(fs::read_to_string(target)?, "name = ponto");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_nested_values_from_data_file() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join(".gitconfig");
+        fs::write(
+            &source,
+            "name = {{ git.user.name }}\nemail = {{ git.user.email }}",
+        )?;
+        let data = dir.path().join("data.json");
+        fs::write(
+            &data,
+            r#"{"git": {"user": {"name": "ponto", "email": "data@example.com"}}}"#,
+        )?;
+        let target = dir.path().join("target");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+        let opts = Options {
+            data: Some(data),
+            ..Default::default()
+        };
+
+        deploy(config, opts)?;
+
+        assert_eq!(
+            fs::read_to_string(target)?,
+            "name = ponto\nemail = data@example.com"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_strip_template_extension_from_target() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("foo.conf.tmpl");
+        fs::write(&source, "name = {{ name }}")?;
+        let target = dir.path().join("target").join("foo.conf.tmpl");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "template_extension: tmpl\nvariables:\n  name: ponto\napp:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+
+        deploy(config, Options::default())?;
+
+        assert!(!target.exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("target").join("foo.conf"))?,
+            "name = ponto"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_verification_when_hook_removes_target() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+        let post = dir.path().join("post.sh");
+        fs::write(&post, format!("rm {}", target.display()))?;
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {}: {}\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+        let opts = Options {
+            post: Some(post),
+            verify: true,
+            ..Default::default()
+        };
+
+        let result = deploy(config, opts);
+
+        assert!(result.is_err());
+        assert!(format!("{:?}", result.unwrap_err()).contains("verification failed"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_roll_back_targets_when_post_hook_fails() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let (source, first, second) = (
+            dir.path().join("source.txt"),
+            dir.path().join("first.txt"),
+            dir.path().join("second.txt"),
+        );
+        for source in [&source, &first, &second] {
+            fs::write(source, "new")?;
+        }
+        let (created, replaced, copied) = (
+            dir.path().join("created.txt"),
+            dir.path().join("replaced.txt"),
+            dir.path().join("copied.txt"),
+        );
+        fs::write(&copied, "old copy")?;
+        fs::write(&replaced, "old file")?;
+        let post = dir.path().join("post.sh");
+        fs::write(&post, "exit 1")?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {source:?}: {created:?}\n    {first:?}:\n      to: {replaced:?}\n      symlink: true\n    {second:?}:\n      to: {copied:?}\n      symlink: false\n",
+            ),
+        )?;
+        let summary = dir.path().join("summary.json");
+        let opts = Options {
+            post: Some(post),
+            force: true,
+            rollback_on_hook_failure: true,
+            summary_json: Some(summary.clone()),
+            ..Default::default()
+        };
+
+        assert!(deploy(config, opts).is_err());
+
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(summary)?)?;
+        let outcomes = json["actions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| action["outcome"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes, ["rolled_back"; 3]);
+
+        assert!(!created.exists() && !created.is_symlink());
+        assert_eq!(fs::read_to_string(&copied)?, "old copy");
+        assert!(!replaced.is_symlink());
+        assert_eq!(fs::read_to_string(&replaced)?, "old file");
+        assert!(!dir.path().join("replaced.txt.ponto.bak").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_resolve_sources_against_package_root() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        for package in ["nvim", "zsh"] {
+            fs::create_dir_all(dir.path().join("packages").join(package))?;
+        }
+        fs::write(dir.path().join("packages/nvim/init.lua"), "nvim")?;
+        fs::write(dir.path().join("packages/zsh/.zshrc"), "zsh")?;
+        let target = dir.path().join("home");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "nvim:\n  root: {root}/packages/nvim\n  files:\n    init.lua: {target}/init.lua\n\
+                 zsh:\n  root: {root}/packages/zsh\n  files:\n    .zshrc: {target}/.zshrc\n",
+                root = dir.path().display(),
+                target = target.display()
+            ),
+        )?;
+
+        deploy(config, Options::default())?;
+
+        assert_eq!(fs::read_to_string(target.join("init.lua"))?, "nvim");
+        assert_eq!(fs::read_to_string(target.join(".zshrc"))?, "zsh");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_templated_scripts_when_asked() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let pre = dir.path().join("pre.sh");
+        fs::write(&pre, "true")?;
+        let templated = dir.path().join("pre.templated");
+
+        let opts = Options {
+            pre: Some(pre.clone()),
+            no_delete_templated: true,
+            ..Default::default()
+        };
+        deploy(load_config(dir.path(), "{}")?, opts)?;
+        assert!(templated.exists());
+
+        let opts = Options {
+            pre: Some(pre),
+            ..Default::default()
+        };
+        deploy(load_config(dir.path(), "{}")?, opts)?;
+        assert!(!templated.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_deploy_one_source_to_multiple_targets() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "setting = 1")?;
+        let first = dir.path().join("config").join("app.conf");
+        let second = dir.path().join("etc").join("app.conf");
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "app:\n  files:\n    {}:\n      to: [{}, {}]\n      symlink: false\n",
+                source.display(),
+                first.display(),
+                second.display()
+            ),
+        )?;
+
+        deploy(config, Options::default())?;
+
+        assert_eq!(fs::read_to_string(first)?, "setting = 1");
+        assert_eq!(fs::read_to_string(second)?, "setting = 1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_missing_variables_empty_when_not_strict() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let strict = dir.path().join("strict.conf");
+        fs::write(&strict, "value = {{ missing }}")?;
+        let lenient = dir.path().join("lenient.conf");
+        fs::write(&lenient, "value = {{ missing }}")?;
+        let target = dir.path().join("target");
+
+        let config = |source: &Path, strict: bool| {
+            load_config(
+                dir.path(),
+                &format!(
+                    "app:\n  files:\n    {}:\n      to: {}\n      symlink: false\n      strict: {strict}\n",
+                    source.display(),
+                    target.join(source.file_name().unwrap()).display()
+                ),
+            )
+        };
+
+        assert!(deploy(config(&strict, true)?, Options::default()).is_err());
+
+        deploy(config(&lenient, false)?, Options::default())?;
+        assert_eq!(fs::read_to_string(target.join("lenient.conf"))?, "value = ");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_missing_variables_empty_with_no_strict() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("app.conf.tpl");
+        fs::write(&source, "value = {{ missing }}")?;
+        let target = dir.path().join("out").join("app.conf.tpl");
+        let contents =
+            format!("template_extension: tpl\napp:\n  files:\n    {source:?}: {target:?}\n");
+
+        assert!(deploy(load_config(dir.path(), &contents)?, Options::default()).is_err());
+
+        let opts = Options {
+            no_strict: true,
+            ..Default::default()
+        };
+        deploy(load_config(dir.path(), &contents)?, opts)?;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out").join("app.conf"))?,
+            "value = "
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_link_directory_contents_individually() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("nvim");
+        fs::create_dir_all(source.join("lua"))?;
+        fs::write(source.join("init.lua"), "init")?;
+        fs::write(source.join("lua").join("plugins.lua"), "plugins")?;
+        let target = dir.path().join("config").join("nvim");
+        fs::create_dir_all(&target)?;
+        fs::write(target.join("local.lua"), "local")?;
+
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "nvim:\n  files:\n    {}:\n      to: {}\n      symlink: true\n      link_contents: true\n",
+                source.display(),
+                target.display()
+            ),
+        )?;
+
+        deploy(
+            config,
+            Options {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+
+        assert!(!target.is_symlink());
+        assert_eq!(
+            fs::read_link(target.join("init.lua"))?,
+            source.join("init.lua")
+        );
+        assert_eq!(
+            fs::read_link(target.join("lua").join("plugins.lua"))?,
+            source.join("lua").join("plugins.lua")
+        );
+        assert!(!target.join("local.lua").is_symlink());
+        assert_eq!(fs::read_to_string(target.join("local.lua"))?, "local");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_link_repository_marker_files() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("scripts");
+        fs::create_dir_all(source.join(".git"))?;
+        fs::write(source.join(".git").join("HEAD"), "ref")?;
+        fs::write(source.join(".gitkeep"), "")?;
+        fs::write(source.join("notes.md"), "notes")?;
+        fs::write(source.join("run.sh"), "run")?;
+        let target = dir.path().join("bin");
+        let contents = |extra: &str| {
+            format!(
+                "scripts:\n  files:\n    {source:?}:\n      to: {target:?}\n      symlink: true\n      link_contents: true\n{extra}"
+            )
+        };
+
+        let config = load_config(dir.path(), &contents("      ignore: ['*.md']\n"))?;
+        deploy(config, Options::default())?;
+
+        assert!(target.join("run.sh").is_symlink());
+        assert!(!target.join(".gitkeep").is_symlink());
+        assert!(!target.join(".git").exists());
+        assert!(!target.join("notes.md").is_symlink());
+
+        let config = load_config(dir.path(), &contents("      no_default_ignore: true\n"))?;
+        deploy(config, Options::default())?;
+
+        assert!(target.join(".gitkeep").is_symlink());
+        assert!(target.join(".git").join("HEAD").is_symlink());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_link_simple_directory_entry() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("nvim");
+        fs::create_dir_all(source.join("lua"))?;
+        fs::write(source.join("init.lua"), "init")?;
+        let target = dir.path().join("config").join("nvim");
+        // directories are often written with a trailing slash
+        let contents = format!(
+            "nvim:\n  files:\n    {}/: {}/\n",
+            source.display(),
+            target.display()
+        );
+        let opts = || Options {
+            verify: true,
+            ..Default::default()
+        };
+
+        let report = deploy(load_config(dir.path(), &contents)?, opts())?;
+
+        assert_eq!(report.count(Outcome::Created), 1);
+        assert_eq!(fs::read_link(&target)?, source);
+        assert_eq!(fs::read_to_string(target.join("init.lua"))?, "init");
+
+        let report = deploy(load_config(dir.path(), &contents)?, opts())?;
+        assert_eq!(report.count(Outcome::Skipped), 1);
+
+        Ok(())
+    }
+
+    /// Keeps every log message, so tests can check what deploys log.
+    struct Captured(Mutex<Vec<String>>);
+
+    static CAPTURED: Captured = Captured(Mutex::new(vec![]));
+
+    impl log::Log for Captured {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn should_prefix_deploy_logs_with_package() -> Result<()> {
+        // other tests may log too, so only this test's target is looked for
+        let _ = log::set_logger(&CAPTURED);
+        log::set_max_level(log::LevelFilter::Debug);
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("init.lua");
+        fs::write(&source, "init")?;
+        let target = dir.path().join("out").join("init.lua");
+        let contents = format!("nvim:\n  files:\n    {source:?}: {target:?}\n");
+
+        deploy(load_config(dir.path(), &contents)?, Options::default())?;
+
+        let logged = CAPTURED.0.lock().unwrap();
+        let line = logged
+            .iter()
+            .find(|line| line.contains(&format!("{target:?}")))
+            .expect("deploying logs the target");
+        assert!(line.starts_with("[nvim] creating symlink"), "{line}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_registered_partials() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let partials = dir.path().join("partials");
+        fs::create_dir_all(&partials)?;
+        fs::write(
+            partials.join("header.hbs"),
+            "# managed by ponto for {{ user }}\n",
+        )?;
+        let source = dir.path().join("gitconfig");
+        fs::write(&source, "{{> header}}\n[user]\n  name = {{ user }}\n")?;
+        let target = dir.path().join("out").join("gitconfig");
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "partials_dir: {partials:?}\nvariables:\n  user: me\ngit:\n  files:\n    {source:?}: {target:?}\n"
+            ),
+        )?;
+
+        deploy(config, Options::default())?;
+
+        assert_eq!(
+            fs::read_to_string(&target)?,
+            "# managed by ponto for me\n[user]\n  name = me\n"
+        );
+
+        fs::write(partials.join("broken.hbs"), "{{#if}}")?;
+        let error = Registries::new(RetryPolicy::default(), Some(&partials))
+            .err()
+            .unwrap();
+        assert!(format!("{error:#}").contains("broken.hbs"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_identical_targets_as_skipped() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+        let contents = format!("app:\n  files:\n    {source:?}: {target:?}\n");
+
+        deploy(load_config(dir.path(), &contents)?, Options::default())?;
+        let report = deploy(load_config(dir.path(), &contents)?, Options::default())?;
+
+        assert_eq!(
+            report.skipped(),
+            format!(
+                "skipped, {}:\n  app: {}\n",
+                SymlinkState::Identical,
+                target.display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_back_up_conflicts_when_config_says_so() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "Hello, world!")?;
+        let target = dir.path().join("target.txt");
+        fs::write(&target, "in the way")?;
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "defaults:\n  conflict_policy: backup\napp:\n  files:\n    {source:?}: {target:?}\n"
+            ),
+        )?;
+
+        let opts = Options::default().with_defaults(&config.defaults);
+        let report = deploy(config, opts)?;
+
+        assert_eq!(report.count(Outcome::BackedUp), 1);
+        assert_eq!(fs::read_link(&target)?, source);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("target.txt.ponto.bak"))?,
+            "in the way"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_file_names_when_linking_contents() -> Result<()> {
+        let dir = TempDir::new("deploy")?;
+        let source = dir.path().join("ssh");
+        fs::create_dir_all(source.join("hosts"))?;
+        fs::write(source.join("hosts").join("{{ hostname }}.conf"), "Host me")?;
+        fs::write(source.join("config"), "Include hosts/*")?;
+        let target = dir.path().join("out");
+        let config = load_config(
+            dir.path(),
+            &format!(
+                "ssh:\n  variables:\n    hostname: myhost\n  files:\n    {source:?}:\n      to: {target:?}\n      symlink: true\n      link_contents: true\n      template_names: true\n"
+            ),
+        )?;
+
+        deploy(
+            config,
+            Options {
+                verify: true,
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(
+            fs::read_link(target.join("hosts").join("myhost.conf"))?,
+            source.join("hosts").join("{{ hostname }}.conf")
+        );
+        assert!(target.join("config").is_symlink());
+        assert!(render_name(
+            Path::new("{{ dir }}.conf"),
+            &Handlebars::new(),
+            &[("dir".to_string(), "../escape".into())]
+                .into_iter()
+                .collect()
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
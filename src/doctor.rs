@@ -0,0 +1,189 @@
+use crate::config;
+use crate::options::Options;
+use anyhow::Result;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// One thing ponto needs from its environment, and whether it's there.
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    /// Whether deploys can't work without it
+    pub critical: bool,
+    pub detail: String,
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match (self.passed, self.critical) {
+            (true, _) => "ok  ",
+            (false, true) => "FAIL",
+            (false, false) => "warn",
+        };
+        write!(f, "{status} {}: {}", self.name, self.detail)
+    }
+}
+
+/// Checks the environment, printing a line per check, and fails when a
+/// critical check does.
+pub fn run(opts: &Options) -> Result<()> {
+    let home = match &opts.home {
+        Some(home) => home.clone(),
+        None => PathBuf::from(shellexpand::tilde("~").as_ref()),
+    };
+    let path = std::env::var_os("PATH").unwrap_or_default();
+
+    let checks = checks(opts, &home, &path);
+    for check in &checks {
+        println!("{check}");
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.critical && !check.passed)
+        .map(|check| check.name)
+        .collect::<Vec<_>>();
+    anyhow::ensure!(failed.is_empty(), "critical checks failed: {failed:?}");
+
+    Ok(())
+}
+
+/// Runs every check, looking up commands in `path` and trying symlinks in
+/// `home`.
+pub fn checks(opts: &Options, home: &Path, path: &OsStr) -> Vec<Check> {
+    let sh = find_executable("sh", path);
+    let which = find_executable("which", path);
+    let symlinks = symlinks_supported(home);
+    let configs = opts.configs();
+    let config = config::load_configs(
+        &configs,
+        opts.config_format,
+        opts.home.as_deref(),
+        opts.template_config,
+    );
+
+    vec![
+        Check {
+            name: "sh",
+            passed: sh.is_some(),
+            critical: true,
+            detail: match sh {
+                Some(sh) => format!("found at {sh:?}"),
+                None => "not found in PATH, hooks and on_change commands can't run".to_owned(),
+            },
+        },
+        Check {
+            name: "which",
+            passed: which.is_some(),
+            critical: false,
+            detail: match which {
+                Some(which) => format!("found at {which:?}"),
+                None => "not found in PATH, the is_executable helper can't work".to_owned(),
+            },
+        },
+        Check {
+            name: "symlinks",
+            passed: symlinks.is_ok(),
+            critical: true,
+            detail: match symlinks {
+                Ok(()) => format!("can be created in {home:?}"),
+                Err(e) => format!("can't be created in {home:?}: {e:#}"),
+            },
+        },
+        Check {
+            name: "config",
+            passed: config.is_ok(),
+            critical: true,
+            detail: match config {
+                Ok(_) => format!("{configs:?} loaded"),
+                Err(e) => format!("{configs:?} can't be loaded: {e:#}"),
+            },
+        },
+    ]
+}
+
+/// The first executable file called `name` in the directories of `path`.
+fn find_executable(name: &str, path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| {
+            fs::metadata(candidate).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
+}
+
+/// Creates and removes a symlink in `dir`.
+fn symlinks_supported(dir: &Path) -> Result<()> {
+    let link = dir.join(format!(".ponto-doctor-{}", std::process::id()));
+    std::os::unix::fs::symlink(dir, &link)?;
+    fs::remove_file(&link)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn should_warn_when_which_is_missing() -> Result<()> {
+        let dir = TempDir::new("doctor")?;
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin)?;
+        fs::write(bin.join("sh"), "#!/bin/false\n")?;
+        fs::set_permissions(bin.join("sh"), fs::Permissions::from_mode(0o755))?;
+        // present but not executable, so doesn't count
+        fs::write(bin.join("which"), "")?;
+        let home = dir.path().join("home");
+        fs::create_dir(&home)?;
+        let config = dir.path().join("config.yaml");
+        fs::write(&config, "variables: {}\n")?;
+        let opts = Options {
+            config: vec![config],
+            ..Default::default()
+        };
+
+        let checks = checks(&opts, &home, bin.as_os_str());
+
+        let passed = checks
+            .iter()
+            .map(|check| (check.name, check.passed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            passed,
+            [
+                ("sh", true),
+                ("which", false),
+                ("symlinks", true),
+                ("config", true)
+            ]
+        );
+        assert!(!checks[1].critical);
+        assert!(checks[1].to_string().starts_with("warn which: not found"));
+        assert_eq!(fs::read_dir(&home)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_critical_checks() -> Result<()> {
+        let dir = TempDir::new("doctor")?;
+        let opts = Options {
+            config: vec![dir.path().join("missing.yaml")],
+            ..Default::default()
+        };
+
+        let checks = checks(&opts, &dir.path().join("missing"), OsStr::new(""));
+
+        assert!(checks
+            .iter()
+            .filter(|check| check.name != "which")
+            .all(|check| check.critical && !check.passed));
+        assert!(checks[3].to_string().starts_with("FAIL config:"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use std::io;
+use thiserror::Error;
+
+/// Exit codes, documented in `--help`. Conflicting targets are skipped with
+/// no failure, and there's no `--fail-on-conflict` to make them one, so 3 is
+/// taken by `--list-drift` rather than conflicts.
+pub const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  any other error
+  2  the configuration could not be loaded
+  3  --list-drift found targets out of sync
+  4  a pre or post hook failed
+  5  an I/O error occurred";
+
+/// Categories of failures that map to distinct process exit codes. They are
+/// attached to errors as anyhow context.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum PontoError {
+    #[error("invalid configuration")]
+    Config,
+    #[error("targets drifted")]
+    Drift,
+    #[error("hook failed")]
+    Hook,
+}
+
+impl PontoError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PontoError::Config => 2,
+            PontoError::Drift => 3,
+            PontoError::Hook => 4,
+        }
+    }
+}
+
+/// Picks the exit code for an error that made ponto bail out.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    if let Some(error) = error.downcast_ref::<PontoError>() {
+        error.exit_code()
+    } else if error.chain().any(|cause| cause.is::<io::Error>()) {
+        5
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn should_map_errors_to_exit_codes() {
+        let config = Err::<(), _>(anyhow!("bad yaml")).context(PontoError::Config);
+        let hook = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .context(PontoError::Hook)
+            .context("running post hook");
+        let io = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied)).context("copy");
+        let other = anyhow!("something else");
+
+        assert_eq!(exit_code(&config.unwrap_err()), 2);
+        assert_eq!(exit_code(&hook.unwrap_err()), 4);
+        assert_eq!(exit_code(&io.unwrap_err()), 5);
+        assert_eq!(exit_code(&other), 1);
+    }
+}
@@ -1,36 +1,301 @@
+use crate::fs_trace;
+use crate::glob;
+use crate::handlebars;
+use crate::report::{Change, Outcome};
 use anyhow::{Context, Result};
-use log::warn;
+use log::{trace, warn};
+use std::ffi::CString;
 use std::fs::{self, File};
 use std::io::Read;
-use std::path::PathBuf;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
 pub struct Filesystem;
 
 impl Filesystem {
-    pub fn copy(from: &PathBuf, to: &PathBuf, force: bool) -> Result<()> {
+    /// Copies a file, or the files below a directory leaving out those
+    /// matching an `ignore` glob.
+    pub fn copy(from: &Path, to: &Path, force: bool, ignore: &[String]) -> Result<Change> {
+        if from.is_dir() {
+            return Self::copy_dir(from, to, force, ignore);
+        }
         if to.exists() && !force {
-            warn!("file {:?} already exists, skipping", to);
-            return Ok(());
+            return Ok(Change::new("target exists", Outcome::Skipped)
+                .with_warning(format!("file {to:?} already exists, skipping")));
         }
 
-        fs::create_dir_all(to.parent().unwrap()).context("creating parent directory")?;
-        fs::copy(from, to).context("copying file")?;
-        Ok(())
+        fs_trace::create_dir_all(to.parent().unwrap()).context("creating parent directory")?;
+        let change = if to.exists() {
+            Self::set_read_only(to, false)?;
+            Change::new("target exists", Outcome::Updated)
+        } else {
+            Change::new("target missing", Outcome::Created)
+        };
+        fs_trace::copy(from, to).context("copying file")?;
+        Ok(change)
     }
+
+    /// Copies the files below `from` into `to`. Files that differ from the
+    /// source are only overwritten when `force` is set, and files only the
+    /// target has are left alone.
+    fn copy_dir(from: &Path, to: &Path, force: bool, ignore: &[String]) -> Result<Change> {
+        if Self::in_sync(from, to, ignore)? {
+            return Ok(Change::new("target directory is in sync", Outcome::Skipped));
+        }
+        let existed = to.exists();
+        fs_trace::create_dir_all(to).context("creating target directory")?;
+
+        let (mut copied, mut kept) = (0, 0);
+        for file in Self::files_in(from, ignore).context("listing source directory")? {
+            let (source, target) = (from.join(&file), to.join(&file));
+            let exists = target.exists() || target.is_symlink();
+            if exists && same_contents(&source, &target)? {
+                continue;
+            }
+            if exists && !force {
+                kept += 1;
+                continue;
+            }
+            fs_trace::create_dir_all(target.parent().unwrap())
+                .context("creating parent directory")?;
+            if target.is_symlink() {
+                fs_trace::remove_file(&target).context("removing symlink")?;
+            } else if exists {
+                Self::set_read_only(&target, false)?;
+            }
+            fs_trace::copy(&source, &target).with_context(|| format!("copying {source:?}"))?;
+            copied += 1;
+        }
+        let change = match (existed, copied) {
+            (false, _) => Change::new("target directory missing", Outcome::Created),
+            (true, 0) => Change::new("target directory differs", Outcome::Skipped),
+            (true, _) => Change::new("target directory differs", Outcome::Updated),
+        };
+        Ok(match kept {
+            0 => change,
+            _ => change.with_warning(format!(
+                "{kept} file(s) in {to:?} differ from the source, use --force to overwrite them"
+            )),
+        })
+    }
+
+    /// Whether `target` holds exactly the files below `source`, with the same
+    /// contents, leaving out those matching an `ignore` glob on both sides.
+    pub fn in_sync(source: &Path, target: &Path, ignore: &[String]) -> Result<bool> {
+        if !target.is_dir() {
+            return Ok(false);
+        }
+        let files = Self::files_in(source, ignore).context("listing source directory")?;
+        if files != Self::files_in(target, ignore).context("listing target directory")? {
+            return Ok(false);
+        }
+        for file in files {
+            if !same_contents(&source.join(&file), &target.join(&file))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Hard links `to` to `from`, replacing another file there only when
+    /// `force` is set. Both have to be on the same filesystem.
+    pub fn hard_link(from: &Path, to: &Path, force: bool) -> Result<Change> {
+        anyhow::ensure!(
+            !from.is_dir(),
+            "{from:?} is a directory, which can't be hard linked"
+        );
+        if Self::is_hard_link(from, to)? {
+            let state = "target is a hard link to the source";
+            return Ok(Change::new(state, Outcome::Skipped));
+        }
+        let exists = to.exists() || to.is_symlink();
+        anyhow::ensure!(to.is_symlink() || !to.is_dir(), "{to:?} is a directory");
+        if exists && !force {
+            return Ok(
+                Change::new("target exists", Outcome::Skipped).with_warning(format!(
+                    "{to:?} isn't a hard link to {from:?}, use --force to replace it"
+                )),
+            );
+        }
+
+        fs_trace::create_dir_all(to.parent().unwrap()).context("creating parent directory")?;
+        // linked next to the target first, so a failure leaves it untouched
+        let mut name = to.file_name().unwrap_or_default().to_owned();
+        name.push(".ponto-link");
+        let staged = to.with_file_name(name);
+        fs_trace::hard_link(from, &staged).map_err(|e| match e.raw_os_error() {
+            Some(libc::EXDEV) => anyhow::Error::new(e).context(format!(
+                "{from:?} and {to:?} are on different filesystems, which hard links can't span"
+            )),
+            _ => anyhow::Error::new(e).context("creating hard link"),
+        })?;
+        fs_trace::rename(&staged, to).context("moving hard link into place")?;
+
+        Ok(match exists {
+            true => Change::new("target isn't a hard link to the source", Outcome::Updated),
+            false => Change::new("target missing", Outcome::Created),
+        })
+    }
+
+    /// Whether `to` is the very file `from` is, rather than a copy of it.
+    pub fn is_hard_link(from: &Path, to: &Path) -> Result<bool> {
+        let source = fs::metadata(from).with_context(|| format!("reading {from:?}"))?;
+        Ok(fs::symlink_metadata(to)
+            .is_ok_and(|target| (target.dev(), target.ino()) == (source.dev(), source.ino())))
+    }
+
+    /// Changes the owner and/or group of `path`, which usually needs root.
+    pub fn chown(path: &Path, owner: Option<&str>, group: Option<&str>) -> Result<()> {
+        let uid = owner.map(user_id).transpose()?;
+        let gid = group.map(group_id).transpose()?;
+        std::os::unix::fs::lchown(path, uid, gid).map_err(|e| {
+            // SAFETY: geteuid has no preconditions and can't fail
+            let root = unsafe { libc::geteuid() } == 0;
+            match e.kind() {
+                std::io::ErrorKind::PermissionDenied if !root => {
+                    anyhow::Error::new(e).context(format!(
+                        "changing ownership of {path:?} needs root, deploy it with `sudo: true`"
+                    ))
+                }
+                _ => anyhow::Error::new(e).context(format!("changing ownership of {path:?}")),
+            }
+        })
+    }
+
+    /// Clears every write bit, or gives the owner write permission back.
+    pub fn set_read_only(path: &Path, read_only: bool) -> Result<()> {
+        let mode = fs::metadata(path)
+            .context("read permissions")?
+            .permissions()
+            .mode();
+        let mode = if read_only {
+            mode & !0o222
+        } else {
+            mode | 0o200
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).context("set permissions")
+    }
+
+    /// Lists the files below `dir`, recursively and sorted, relative to it.
+    /// Symlinks are listed as files and never followed. Entries matching an
+    /// `ignore` glob are left out, along with everything below them.
+    pub fn files_in(dir: &Path, ignore: &[String]) -> Result<Vec<PathBuf>> {
+        Ok(Self::files_within(dir, ignore, None)?.0)
+    }
+
+    /// Same as `files_in`, but only descends `max_depth` levels, so 1 lists
+    /// the files right in `dir`. Also returns how many directories were left
+    /// out for being deeper.
+    pub fn files_within(
+        dir: &Path,
+        ignore: &[String],
+        max_depth: Option<usize>,
+    ) -> Result<(Vec<PathBuf>, usize)> {
+        let mut files = vec![];
+        let mut too_deep = 0;
+        let mut pending = vec![(PathBuf::new(), 1)];
+        while let Some((relative, depth)) = pending.pop() {
+            for entry in fs::read_dir(dir.join(&relative))? {
+                let entry = entry?;
+                let path = relative.join(entry.file_name());
+                if ignore.iter().any(|pattern| glob::matches(pattern, &path)) {
+                    trace!("ignoring {path:?} in {dir:?}");
+                    continue;
+                }
+                if !entry.file_type()?.is_dir() {
+                    files.push(path);
+                } else if max_depth.is_some_and(|max| depth >= max) {
+                    trace!("not descending into {path:?} in {dir:?}, it's too deep");
+                    too_deep += 1;
+                } else {
+                    pending.push((path, depth + 1));
+                }
+            }
+        }
+        files.sort();
+
+        Ok((files, too_deep))
+    }
+}
+
+/// Whether two files, symlinks followed, hold the same bytes. A broken link
+/// differs from everything.
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let read = |path: &Path| match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {path:?}")),
+    };
+    Ok(match (read(a)?, read(b)?) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    })
+}
+
+/// Sets the process umask until dropped, then restores the previous one.
+/// Hooks and other child processes inherit it.
+pub struct Umask {
+    previous: libc::mode_t,
+}
+
+impl Umask {
+    pub fn set(mask: u32) -> Self {
+        // SAFETY: umask has no preconditions and can't fail
+        let previous = unsafe { libc::umask(mask as libc::mode_t) };
+        Self { previous }
+    }
+}
+
+impl Drop for Umask {
+    fn drop(&mut self) {
+        // SAFETY: as in `Umask::set`
+        unsafe { libc::umask(self.previous) };
+    }
+}
+
+/// Looks up a user by name or numeric id.
+fn user_id(owner: &str) -> Result<libc::uid_t> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner).context("user name")?;
+    // SAFETY: `name` is a valid C string; the returned entry is only read
+    // before any other passwd call
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    anyhow::ensure!(!entry.is_null(), "unknown user {owner}");
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// Looks up a group by name or numeric id.
+fn group_id(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).context("group name")?;
+    // SAFETY: as in `user_id`
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    anyhow::ensure!(!entry.is_null(), "unknown group {group}");
+    Ok(unsafe { (*entry).gr_gid })
 }
 
 pub trait FilesystemExt {
-    fn is_template(&self) -> Result<bool>;
+    fn is_template(&self, template_extension: Option<&str>) -> Result<bool>;
+
+    fn strip_template_extension(&self, template_extension: Option<&str>) -> PathBuf;
 
     fn real_path(&self) -> Result<PathBuf>;
 }
 
 impl FilesystemExt for PathBuf {
-    fn is_template(&self) -> Result<bool> {
+    fn is_template(&self, template_extension: Option<&str>) -> Result<bool> {
         if fs::metadata(self)?.is_dir() {
             return Ok(false);
         }
 
+        if let Some(extension) = template_extension {
+            return Ok(has_extension(self, extension));
+        }
+
         let mut file = File::open(self).context("open file")?;
         let mut buf = String::new();
 
@@ -38,7 +303,14 @@ impl FilesystemExt for PathBuf {
             warn!("file {:?} is not valid UTF-8 - detecting as symlink. Explicitly specify it to silence this message.", self);
             Ok(false)
         } else {
-            Ok(buf.contains("{{"))
+            Ok(handlebars::has_unescaped(&buf, handlebars::OPEN_DELIMITER))
+        }
+    }
+
+    fn strip_template_extension(&self, template_extension: Option<&str>) -> PathBuf {
+        match template_extension {
+            Some(extension) if has_extension(self, extension) => self.with_extension(""),
+            _ => self.to_owned(),
         }
     }
 
@@ -48,6 +320,11 @@ impl FilesystemExt for PathBuf {
     }
 }
 
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == extension.trim_start_matches('.'))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,7 +341,7 @@ mod tests {
         File::create(&from)?.write_all(b"Hello, world!")?;
         let to = dir.path().join("to.txt");
 
-        Filesystem::copy(&from, &to, false)?;
+        Filesystem::copy(&from, &to, false, &[])?;
 
         let from_content = fs::read_to_string(&from)?;
         let to_content = fs::read_to_string(&to)?;
@@ -74,6 +351,145 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_hard_link_file_once() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+        let from = dir.path().join("from.txt");
+        fs::write(&from, "Hello, world!")?;
+        let to = dir.path().join("nested").join("to.txt");
+
+        let change = Filesystem::hard_link(&from, &to, false)?;
+
+        assert_eq!(change.outcome, Outcome::Created);
+        assert!(Filesystem::is_hard_link(&from, &to)?);
+        let change = Filesystem::hard_link(&from, &to, false)?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+        assert_eq!(change.state, "target is a hard link to the source");
+
+        // a copy has the same contents but isn't the same file
+        fs::remove_file(&to)?;
+        fs::copy(&from, &to)?;
+        assert!(!Filesystem::is_hard_link(&from, &to)?);
+        assert_eq!(
+            Filesystem::hard_link(&from, &to, true)?.outcome,
+            Outcome::Updated
+        );
+        assert!(Filesystem::is_hard_link(&from, &to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_list_files_within_max_depth() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+        fs::create_dir_all(dir.path().join("a").join("b"))?;
+        fs::create_dir_all(dir.path().join("node_modules").join("pkg"))?;
+        fs::write(dir.path().join("top"), "")?;
+        fs::write(dir.path().join("a").join("middle"), "")?;
+        fs::write(dir.path().join("a").join("b").join("bottom"), "")?;
+        fs::write(
+            dir.path().join("node_modules").join("pkg").join("index.js"),
+            "",
+        )?;
+
+        let (files, too_deep) = Filesystem::files_within(dir.path(), &[], Some(1))?;
+        assert_eq!(files, [PathBuf::from("top")]);
+        assert_eq!(too_deep, 2);
+
+        let (files, too_deep) = Filesystem::files_within(dir.path(), &[], Some(2))?;
+        assert_eq!(files, [PathBuf::from("a/middle"), PathBuf::from("top")]);
+        assert_eq!(too_deep, 2);
+
+        assert_eq!(Filesystem::files_within(dir.path(), &[], None)?.0.len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_files_recursively() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+
+        fs::create_dir_all(dir.path().join("b").join("c"))?;
+        File::create(dir.path().join("a.txt"))?;
+        File::create(dir.path().join("b").join("c").join("d.txt"))?;
+
+        let files = Filesystem::files_in(dir.path(), &[])?;
+
+        assert_eq!(
+            files,
+            vec![PathBuf::from("a.txt"), PathBuf::from("b/c/d.txt")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_tell_in_sync_directories_from_drifted_ones() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("nested"))?;
+        fs::write(source.join("a.conf"), "a")?;
+        fs::write(source.join("nested").join("b.conf"), "b")?;
+        let target = dir.path().join("target");
+        assert!(!Filesystem::in_sync(&source, &target, &[])?);
+
+        let change = Filesystem::copy(&source, &target, false, &[])?;
+        assert_eq!(change.outcome, Outcome::Created);
+        assert!(Filesystem::in_sync(&source, &target, &[])?);
+        let change = Filesystem::copy(&source, &target, false, &[])?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+
+        fs::write(target.join("nested").join("b.conf"), "edited")?;
+        assert!(!Filesystem::in_sync(&source, &target, &[])?);
+        fs::write(target.join("nested").join("b.conf"), "b")?;
+        fs::write(target.join("extra.conf"), "extra")?;
+        assert!(!Filesystem::in_sync(&source, &target, &[])?);
+        assert!(Filesystem::in_sync(
+            &source,
+            &target,
+            &["extra.*".to_string()]
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_overwrite_drifted_directory_files_only_when_forced() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(&source)?;
+        fs::write(source.join("a.conf"), "a")?;
+        let target = dir.path().join("target");
+        fs::create_dir_all(&target)?;
+        fs::write(target.join("a.conf"), "edited")?;
+
+        let change = Filesystem::copy(&source, &target, false, &[])?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+        assert_eq!(fs::read_to_string(target.join("a.conf"))?, "edited");
+
+        let change = Filesystem::copy(&source, &target, true, &[])?;
+        assert_eq!(change.outcome, Outcome::Updated);
+        assert_eq!(fs::read_to_string(target.join("a.conf"))?, "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_leave_ignored_files_out_of_listing() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+
+        fs::create_dir_all(dir.path().join(".git").join("objects"))?;
+        File::create(dir.path().join(".git").join("objects").join("pack"))?;
+        File::create(dir.path().join(".gitkeep"))?;
+        File::create(dir.path().join("a.txt"))?;
+
+        let files = Filesystem::files_in(dir.path(), &[".git*".to_string()])?;
+
+        assert_eq!(files, vec![PathBuf::from("a.txt")]);
+
+        Ok(())
+    }
+
     #[test]
     fn should_check_if_file_is_template() -> Result<()> {
         let dir = TempDir::new("filesystem")?;
@@ -81,7 +497,57 @@ mod tests {
         let file_path = dir.path().join("file.txt");
         File::create(&file_path)?.write_all(b"Hello, {{ name }}!")?;
 
-        assert!(file_path.is_template()?);
+        assert!(file_path.is_template(None)?);
+
+        let escaped = dir.path().join("escaped.txt");
+        File::create(&escaped)?.write_all(br"Hello, \{{ name }}!")?;
+        assert!(!escaped.is_template(None)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_template_by_extension() -> Result<()> {
+        let dir = TempDir::new("filesystem")?;
+
+        let template = dir.path().join("foo.conf.tmpl");
+        File::create(&template)?.write_all(b"plain")?;
+        let sniffed = dir.path().join("bar.conf");
+        File::create(&sniffed)?.write_all(b"Hello, {{ name }}!")?;
+
+        assert!(template.is_template(Some("tmpl"))?);
+        assert!(!sniffed.is_template(Some(".tmpl"))?);
+        assert_eq!(
+            template.strip_template_extension(Some(".tmpl")),
+            dir.path().join("foo.conf")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_chown_to_current_user_and_group() -> Result<()> {
+        use std::ffi::CStr;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new("filesystem")?;
+        let path = dir.path().join("owned.txt");
+        fs::write(&path, "owned")?;
+
+        // SAFETY: the entries are read right away, before any other lookup
+        let (user, group) = unsafe {
+            let user = CStr::from_ptr((*libc::getpwuid(libc::geteuid())).pw_name);
+            let user = user.to_string_lossy().into_owned();
+            let group = CStr::from_ptr((*libc::getgrgid(libc::getegid())).gr_name);
+            (user, group.to_string_lossy().into_owned())
+        };
+
+        Filesystem::chown(&path, Some(&user), Some(&group))?;
+
+        let metadata = fs::metadata(&path)?;
+        assert_eq!(metadata.uid(), unsafe { libc::geteuid() });
+        assert_eq!(metadata.gid(), unsafe { libc::getegid() });
+        assert!(Filesystem::chown(&path, Some("no-such-ponto-user"), None).is_err());
 
         Ok(())
     }
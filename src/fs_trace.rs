@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Where operations are recorded while a trace is running.
+static TRACE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Records every filesystem operation deploys make into a file, one JSON
+/// object per line, until dropped.
+pub struct Trace;
+
+impl Trace {
+    pub fn start(path: &Path) -> Result<Trace> {
+        let file = File::create(path).with_context(|| format!("creating trace file {path:?}"))?;
+        *TRACE.lock().unwrap() = Some(file);
+        Ok(Trace)
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        *TRACE.lock().unwrap() = None;
+    }
+}
+
+/// Records `op` on `to`, from `from` when it has a source, and how it went.
+/// Does nothing unless a trace is running; failing to record is only logged.
+pub fn record<T>(op: &str, from: Option<&Path>, to: &Path, result: &io::Result<T>) {
+    let mut trace = TRACE.lock().unwrap();
+    let Some(file) = trace.as_mut() else {
+        return;
+    };
+    let line = serde_json::json!({
+        "op": op,
+        "from": from,
+        "to": to,
+        "result": match result {
+            Ok(_) => "ok".to_owned(),
+            Err(e) => e.to_string(),
+        },
+    });
+    if let Err(e) = writeln!(file, "{line}") {
+        log::warn!("failed to record {op} of {to:?} in the trace file: {e}");
+    }
+}
+
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    let result = std::os::unix::fs::symlink(original, link);
+    record("symlink", Some(original), link, &result);
+    result
+}
+
+pub fn hard_link(original: &Path, link: &Path) -> io::Result<()> {
+    let result = fs::hard_link(original, link);
+    record("hard_link", Some(original), link, &result);
+    result
+}
+
+pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
+    let result = fs::copy(from, to);
+    record("copy", Some(from), to, &result);
+    result
+}
+
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    let result = fs::rename(from, to);
+    record("rename", Some(from), to, &result);
+    result
+}
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let result = fs::write(path, contents);
+    record("write", None, path, &result);
+    result
+}
+
+pub fn remove_file(path: &Path) -> io::Result<()> {
+    let result = fs::remove_file(path);
+    record("remove", None, path, &result);
+    result
+}
+
+pub fn remove_dir(path: &Path) -> io::Result<()> {
+    let result = fs::remove_dir(path);
+    record("remove_dir", None, path, &result);
+    result
+}
+
+/// Only recorded when there was a directory to create.
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+    let result = fs::create_dir_all(path);
+    record("mkdir", None, path, &result);
+    result
+}
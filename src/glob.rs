@@ -0,0 +1,62 @@
+use std::path::Path;
+
+/// Matches `path` against a shell-style glob `pattern`.
+///
+/// `*` matches any run of characters except `/`, `**` also crosses `/` and
+/// `?` matches a single character. Patterns without a `/` are matched
+/// against the file name only, so `--match .bashrc` finds `~/.bashrc`.
+pub fn matches(pattern: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let candidate = if pattern.contains('/') {
+        path.as_ref()
+    } else {
+        path.rsplit('/').next().unwrap_or_default()
+    };
+
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let candidate = candidate.chars().collect::<Vec<_>>();
+    matches_from(&pattern, &candidate)
+}
+
+fn matches_from(pattern: &[char], candidate: &[char]) -> bool {
+    match pattern {
+        [] => candidate.is_empty(),
+        ['*', '*', rest @ ..] => {
+            let rest = rest.strip_prefix(&['/']).unwrap_or(rest);
+            (0..=candidate.len()).any(|i| matches_from(rest, &candidate[i..]))
+        }
+        ['*', rest @ ..] => (0..=candidate.len())
+            .take_while(|&i| i == 0 || candidate[i - 1] != '/')
+            .any(|i| matches_from(rest, &candidate[i..])),
+        ['?', rest @ ..] => match candidate {
+            [c, tail @ ..] if *c != '/' => matches_from(rest, tail),
+            _ => false,
+        },
+        [p, rest @ ..] => match candidate {
+            [c, tail @ ..] if c == p => matches_from(rest, tail),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_file_name_when_pattern_has_no_separator() {
+        assert!(matches(".bashrc", Path::new("/home/user/.bashrc")));
+        assert!(matches("*.lua", Path::new("nvim/init.lua")));
+        assert!(!matches("*.lua", Path::new("nvim/init.vim")));
+    }
+
+    #[test]
+    fn should_match_full_path_with_double_star() {
+        assert!(matches(
+            "**/nvim/*",
+            Path::new("/home/user/.config/nvim/init.lua")
+        ));
+        assert!(matches("nvim/**", Path::new("nvim/lua/plugins.lua")));
+        assert!(!matches("nvim/*", Path::new("nvim/lua/plugins.lua")));
+    }
+}
@@ -1,24 +1,118 @@
-use anyhow::Result;
+use crate::process;
+use crate::retry::RetryPolicy;
+use anyhow::{Context as _, Result};
 use handlebars::{
-    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason,
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonValue, Output, RenderContext,
+    RenderError, RenderErrorReason, Renderable, ScopedJson,
 };
+use indexmap::IndexMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-pub fn init<'hb>() -> Result<Handlebars<'hb>> {
+pub fn init<'hb>(retry: RetryPolicy) -> Result<Handlebars<'hb>> {
     let mut handlebars = Handlebars::new();
     handlebars.register_escape_fn(str::to_string);
     handlebars.set_strict_mode(true);
-    register_helpers(&mut handlebars);
+    register_helpers(&mut handlebars, retry);
 
     Ok(handlebars)
 }
-fn register_helpers(handlebars: &mut Handlebars<'_>) {
+fn register_helpers(handlebars: &mut Handlebars<'_>, retry: RetryPolicy) {
     handlebars_misc_helpers::register(handlebars);
     handlebars.register_helper("math", Box::new(math_helper));
     handlebars.register_helper("include_template", Box::new(include_template_helper));
     handlebars.register_helper("is_executable", Box::new(is_executable_helper));
-    handlebars.register_helper("command_success", Box::new(command_success_helper));
-    handlebars.register_helper("command_output", Box::new(command_output_helper));
+    handlebars.register_helper(
+        "command_success",
+        Box::new(
+            move |h: &Helper<'_>,
+                  r: &Handlebars<'_>,
+                  ctx: &Context,
+                  rc: &mut RenderContext<'_, '_>,
+                  out: &mut dyn Output| {
+                command_success_helper(h, r, ctx, rc, out, retry)
+            },
+        ),
+    );
+    handlebars.register_helper(
+        "command_output",
+        Box::new(
+            move |h: &Helper<'_>,
+                  r: &Handlebars<'_>,
+                  ctx: &Context,
+                  rc: &mut RenderContext<'_, '_>,
+                  out: &mut dyn Output| {
+                command_output_helper(h, r, ctx, rc, out, retry)
+            },
+        ),
+    );
+    handlebars.register_helper("indent", Box::new(indent_helper));
+    handlebars.register_helper("base64_encode", Box::new(base64_encode_helper));
+    handlebars.register_helper("base64_decode", Box::new(base64_decode_helper));
+    handlebars.register_helper("lookup_var", Box::new(LookupVarHelper));
+    handlebars.register_helper("shell_quote", Box::new(shell_quote_helper));
+    handlebars.register_helper("os_path", Box::new(os_path_helper));
+    handlebars.register_helper("to_yaml", Box::new(to_yaml_helper));
+    // replaces handlebars_misc_helpers' block-only `to_json`, which
+    // `to_json_helper` still handles
+    handlebars.register_helper("to_json", Box::new(to_json_helper));
+}
+
+/// Registers a helper per entry of `helpers` that runs its script with the
+/// helper's parameters as arguments, writing what it prints.
+pub fn register_script_helpers(
+    handlebars: &mut Handlebars<'_>,
+    helpers: &IndexMap<String, PathBuf>,
+    retry: RetryPolicy,
+) {
+    for (name, script) in helpers {
+        let (name, script) = (name.clone(), script.clone());
+        handlebars.register_helper(
+            &name.clone(),
+            Box::new(
+                move |h: &Helper<'_>,
+                      _: &Handlebars<'_>,
+                      _: &Context,
+                      _: &mut RenderContext<'_, '_>,
+                      out: &mut dyn Output| {
+                    script_helper(h, out, &name, &script, retry)
+                },
+            ),
+        );
+    }
+}
+
+fn script_helper(
+    h: &Helper<'_>,
+    out: &mut dyn Output,
+    name: &str,
+    script: &Path,
+    retry: RetryPolicy,
+) -> HelperResult {
+    let args = h.params().iter().map(|p| p.render()).collect::<Vec<_>>();
+    let output = retry.run(
+        || {
+            process::output(
+                Command::new(script)
+                    .args(&args)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped()),
+                retry.timeout,
+            )
+        },
+        |output| output.status.success(),
+    )?;
+    if !output.status.success() {
+        return Err(RenderErrorReason::Other(format!(
+            "{name}: script {script:?} failed with {}",
+            output.status
+        ))
+        .into());
+    }
+    out.write(&String::from_utf8_lossy(&output.stdout))?;
+
+    Ok(())
 }
 
 fn math_helper(
@@ -114,6 +208,7 @@ fn command_success_helper(
     _: &Context,
     _: &mut RenderContext<'_, '_>,
     out: &mut dyn Output,
+    retry: RetryPolicy,
 ) -> HelperResult {
     let mut params = h.params().iter();
     let command = params
@@ -130,14 +225,22 @@ fn command_success_helper(
         .into());
     }
 
-    let status = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()?
-        .success();
-    if status {
+    // exiting non-zero is an answer, only failing to run or timing out is
+    // worth another try
+    let output = retry.run(
+        || {
+            Ok::<_, std::io::Error>(process::output(
+                process::os_shell()
+                    .arg(&command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null()),
+                retry.timeout,
+            ))
+        },
+        |output| output.is_ok(),
+    )??;
+    if output.status.success() {
         out.write("true")?;
     }
 
@@ -150,6 +253,7 @@ fn command_output_helper(
     _: &Context,
     _: &mut RenderContext<'_, '_>,
     out: &mut dyn Output,
+    retry: RetryPolicy,
 ) -> HelperResult {
     let mut params = h.params().iter();
     let command = params
@@ -166,15 +270,394 @@ fn command_output_helper(
         .into());
     }
 
-    let output = os_shell()
-        .arg(&command)
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .output()?;
+    let output = retry.run(
+        || {
+            process::output(
+                process::os_shell()
+                    .arg(&command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped()),
+                retry.timeout,
+            )
+        },
+        |output| output.status.success(),
+    )?;
     out.write(&String::from_utf8_lossy(&output.stdout))?;
 
     Ok(())
 }
+fn indent_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut params = h.params().iter();
+    let width = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("indent", 0))?
+        .render();
+    let width = width.parse::<usize>().map_err(|_| {
+        RenderErrorReason::Other(format!(
+            "indent: width must be a non-negative integer, got {width}"
+        ))
+    })?;
+    let value = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("indent", 1))?
+        .render();
+    if params.next().is_some() {
+        return Err(
+            RenderErrorReason::Other("indent: More than two parameters given".to_owned()).into(),
+        );
+    }
+
+    out.write(&indent(&value, width))?;
+
+    Ok(())
+}
+
+/// Prefixes every line but the first with `width` spaces, so the value lines
+/// up under the column where the helper was placed.
+fn indent(value: &str, width: usize) -> String {
+    let padding = " ".repeat(width);
+    value
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 || line.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{padding}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn base64_encode_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut params = h.params().iter();
+    let value = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("base64_encode", 0))?
+        .render();
+    if params.next().is_some() {
+        return Err(RenderErrorReason::Other(
+            "base64_encode: More than one parameter given".to_owned(),
+        )
+        .into());
+    }
+
+    out.write(&base64_encode(value.as_bytes()))?;
+
+    Ok(())
+}
+
+fn base64_decode_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut params = h.params().iter();
+    let value = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("base64_decode", 0))?
+        .render();
+    if params.next().is_some() {
+        return Err(RenderErrorReason::Other(
+            "base64_decode: More than one parameter given".to_owned(),
+        )
+        .into());
+    }
+
+    let decoded = base64_decode(&value)
+        .and_then(|bytes| {
+            String::from_utf8(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        })
+        .map_err(|e| RenderErrorReason::NestedError(Box::new(e)))?;
+    out.write(&decoded)?;
+
+    Ok(())
+}
+
+fn shell_quote_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let mut params = h.params().iter();
+    let value = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("shell_quote", 0))?
+        .render();
+    if params.next().is_some() {
+        return Err(RenderErrorReason::Other(
+            "shell_quote: More than one parameter given".to_owned(),
+        )
+        .into());
+    }
+
+    out.write(&shell_quote(&value))?;
+
+    Ok(())
+}
+
+/// Joins its parameters into one path with the platform's separator. As
+/// with `PathBuf::push`, an absolute component replaces what came before.
+fn os_path_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    if h.params().is_empty() {
+        return Err(RenderErrorReason::ParamNotFoundForIndex("os_path", 0).into());
+    }
+    let path = h
+        .params()
+        .iter()
+        .map(|p| p.render())
+        .collect::<std::path::PathBuf>();
+
+    out.write(&path.to_string_lossy())?;
+    Ok(())
+}
+
+/// Serializes a variable, lists and maps included, as YAML without the
+/// trailing newline. Combine with `indent` to nest it under a key.
+fn to_yaml_helper(
+    h: &Helper<'_>,
+    _: &Handlebars<'_>,
+    _: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = single_value(h, "to_yaml")?;
+    let yaml =
+        serde_yaml::to_string(value).map_err(|e| RenderErrorReason::NestedError(Box::new(e)))?;
+
+    out.write(yaml.trim_end_matches('\n'))?;
+
+    Ok(())
+}
+
+/// Serializes a variable, lists and maps included, as pretty printed JSON.
+/// Used as a block, `{{#to_json format="yaml"}}...{{/to_json}}` converts its
+/// rendered contents, in `format` (json, yaml or toml), to JSON instead.
+fn to_json_helper<'reg, 'rc>(
+    h: &Helper<'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let nested = |e: Box<dyn std::error::Error + Send + Sync>| RenderErrorReason::NestedError(e);
+    let json = match h.template() {
+        Some(template) => {
+            let contents = template.renders(r, ctx, rc)?;
+            let format = h.hash_get("format").map(|format| format.render());
+            let value: JsonValue = match format.as_deref().unwrap_or("json") {
+                "json" => serde_json::from_str(&contents).map_err(|e| nested(Box::new(e)))?,
+                "yaml" => serde_yaml::from_str(&contents).map_err(|e| nested(Box::new(e)))?,
+                "toml" => toml::from_str(&contents).map_err(|e| nested(Box::new(e)))?,
+                format => {
+                    return Err(RenderErrorReason::Other(format!(
+                        "to_json: unknown format {format}"
+                    ))
+                    .into())
+                }
+            };
+            serde_json::to_string_pretty(&value)
+        }
+        None => serde_json::to_string_pretty(single_value(h, "to_json")?),
+    }
+    .map_err(|e| nested(Box::new(e)))?;
+
+    out.write(&json)?;
+
+    Ok(())
+}
+
+/// The value of a helper's only parameter.
+fn single_value<'a>(h: &'a Helper<'_>, name: &'static str) -> Result<&'a JsonValue, RenderError> {
+    let mut params = h.params().iter();
+    let value = params
+        .next()
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex(name, 0))?
+        .value();
+    if params.next().is_some() {
+        return Err(
+            RenderErrorReason::Other(format!("{name}: More than one parameter given")).into(),
+        );
+    }
+    Ok(value)
+}
+
+/// Wraps `value` in single quotes, closing and reopening them around each
+/// embedded single quote, so a POSIX shell reads it back verbatim.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Reads the variable named by its rendered parameter, so the name can be
+/// computed, e.g. `{{ lookup_var (replace host "-" "_") }}`.
+struct LookupVarHelper;
+
+impl HelperDef for LookupVarHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let mut params = h.params().iter();
+        let name = params
+            .next()
+            .ok_or(RenderErrorReason::ParamNotFoundForIndex("lookup_var", 0))?
+            .render();
+        if params.next().is_some() {
+            return Err(RenderErrorReason::Other(
+                "lookup_var: More than one parameter given".to_owned(),
+            )
+            .into());
+        }
+
+        match ctx.data().get(&name) {
+            Some(value) => Ok(ScopedJson::Context(value, vec![name])),
+            None if r.strict_mode() => Err(RenderErrorReason::Other(format!(
+                "lookup_var: variable {name} not found"
+            ))
+            .into()),
+            None => Ok(ScopedJson::Derived(JsonValue::Null)),
+        }
+    }
+}
+
+/// Renders a `when` condition and decides whether it holds. The rendered text
+/// is evaluated as a boolean expression (e.g. `{{ cores }} > 4`) when it is
+/// one; otherwise it holds unless empty, `false` or `0`.
+pub fn condition_holds<T: serde::Serialize>(
+    handlebars: &Handlebars<'_>,
+    condition: &str,
+    variables: &T,
+) -> Result<bool> {
+    let rendered = handlebars
+        .render_template(condition, variables)
+        .with_context(|| format!("rendering condition {condition:?}"))?;
+    let rendered = rendered.trim();
+
+    Ok(evalexpr::eval_boolean(rendered)
+        .unwrap_or_else(|_| !matches!(rendered.to_lowercase().as_str(), "" | "false" | "0")))
+}
+
+/// What opens a handlebars tag.
+pub const OPEN_DELIMITER: &str = "{{";
+
+/// Whether `text` holds an `open` delimiter that isn't escaped with a
+/// backslash. A doubled backslash is a literal one, so `\\{{` still opens a
+/// tag, as in handlebars.
+pub fn has_unescaped(text: &str, open: &str) -> bool {
+    text.match_indices(open).any(|(start, _)| {
+        let backslashes = text[..start]
+            .bytes()
+            .rev()
+            .take_while(|&byte| byte == b'\\')
+            .count();
+        backslashes % 2 == 0
+    })
+}
+
+/// Drops the newline right after each block tag (`{{#...}}`, `{{^...}}`,
+/// `{{/...}}` and `{{else}}`), like Jinja's `trim_blocks`. Tags on a line of
+/// their own are already removed by handlebars; this covers tags that share a
+/// line with other text, which would otherwise leave blank lines behind when
+/// their block renders empty. Other whitespace, such as indentation of the
+/// following line, is kept.
+pub fn trim_blocks(template: &str) -> String {
+    let mut trimmed = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end + 2) else {
+            break;
+        };
+        let tag = rest[start + 2..end - 2]
+            .trim_start_matches(['{', '~'])
+            .trim_start();
+        trimmed.push_str(&rest[..end]);
+        rest = &rest[end..];
+
+        let is_block =
+            tag.starts_with(['#', '^', '/']) || tag.trim_end_matches('~').trim() == "else";
+        if is_block {
+            rest = rest
+                .strip_prefix("\r\n")
+                .or_else(|| rest.strip_prefix('\n'))
+                .unwrap_or(rest);
+        }
+    }
+    trimmed.push_str(rest);
+
+    trimmed
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+fn base64_decode(value: &str) -> Result<Vec<u8>, io::Error> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, format!("invalid base64: {value}"));
+
+    let value = value.trim().trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(value.len() * 3 / 4);
+    for chunk in value.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return Err(invalid());
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let sextet = BASE64_ALPHABET
+                .iter()
+                .position(|a| a == c)
+                .ok_or_else(invalid)?;
+            n |= (sextet as u32) << (18 - 6 * i);
+        }
+        decoded.extend(n.to_be_bytes()[1..chunk.len()].iter());
+    }
+
+    Ok(decoded)
+}
+
 fn is_executable(name: &str) -> Result<bool, std::io::Error> {
     Command::new("which")
         .arg(name)
@@ -185,8 +668,260 @@ fn is_executable(name: &str) -> Result<bool, std::io::Error> {
         .map(|s| s.success())
 }
 
-fn os_shell() -> Command {
-    let mut cmd = Command::new("sh");
-    cmd.arg("-c");
-    cmd
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn should_skip_escaped_delimiters() {
+        assert!(has_unescaped("Hello, {{ name }}", OPEN_DELIMITER));
+        assert!(!has_unescaped(r"Hello, \{{ name }}", OPEN_DELIMITER));
+        assert!(has_unescaped(r"Hello, \\{{ name }}", OPEN_DELIMITER));
+        assert!(has_unescaped(
+            r"\{{ literal }} and {{ name }}",
+            OPEN_DELIMITER
+        ));
+        assert!(!has_unescaped("no tags here", OPEN_DELIMITER));
+    }
+
+    #[test]
+    fn should_look_for_the_given_delimiter() {
+        assert!(has_unescaped("Hello, <% name %>", "<%"));
+        assert!(!has_unescaped(r"Hello, \<% name %>", "<%"));
+        assert!(!has_unescaped("Hello, <% name %>", OPEN_DELIMITER));
+    }
+
+    fn variables(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_run_script_helpers_with_their_parameters() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir::TempDir::new("handlebars")?;
+        let script = dir.path().join("greet.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\nprintf 'Hello, %s and %s!' \"$1\" \"$2\"\n",
+        )?;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+        let mut handlebars = init(RetryPolicy::default())?;
+        let helpers = [("greet".to_owned(), script)].into_iter().collect();
+        register_script_helpers(&mut handlebars, &helpers, RetryPolicy::default());
+
+        let rendered = handlebars.render_template(
+            "{{ greet name \"world\" }}",
+            &variables(&[("name", "ponto")]),
+        )?;
+
+        assert_eq!(rendered, "Hello, ponto and world!");
+        Ok(())
+    }
+
+    #[test]
+    fn should_indent_single_line_value() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        let rendered = handlebars
+            .render_template("key: {{ indent 4 value }}", &variables(&[("value", "one")]))?;
+
+        assert_eq!(rendered, "key: one");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_indent_multi_line_value() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        let rendered = handlebars.render_template(
+            "key: {{ indent 4 value }}",
+            &variables(&[("value", "one\ntwo\n\nthree")]),
+        )?;
+
+        assert_eq!(rendered, "key: one\n    two\n\n    three");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_negative_indent_width() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        let rendered =
+            handlebars.render_template("{{ indent -1 value }}", &variables(&[("value", "a")]));
+
+        assert!(rendered.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_retry_failing_command_success() -> Result<()> {
+        let dir = tempdir::TempDir::new("handlebars")?;
+        let attempts = dir.path().join("attempts");
+        let handlebars = init(RetryPolicy::new(3, std::time::Duration::from_secs(10)))?;
+        let template = format!(
+            "{{{{#if (command_success \"echo >> {} && false\")}}}}yes{{{{else}}}}no{{{{/if}}}}",
+            attempts.display()
+        );
+
+        let started = std::time::Instant::now();
+        let rendered = handlebars.render_template(&template, &variables(&[]))?;
+
+        assert_eq!(rendered, "no");
+        assert_eq!(std::fs::read_to_string(&attempts)?.lines().count(), 1);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_trip_base64() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        for value in ["", "a", "ab", "abc", "ssh-ed25519 AAAA ponto@host"] {
+            let encoded = handlebars
+                .render_template("{{ base64_encode value }}", &variables(&[("value", value)]))?;
+            let decoded = handlebars.render_template(
+                "{{ base64_decode value }}",
+                &variables(&[("value", &encoded)]),
+            )?;
+
+            assert_eq!(decoded, value);
+        }
+        assert_eq!(base64_encode(b"ponto"), "cG9udG8=");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_invalid_base64() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        let rendered = handlebars.render_template(
+            "{{ base64_decode value }}",
+            &variables(&[("value", "no*pe")]),
+        );
+
+        assert!(rendered.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_look_up_variable_by_dynamic_name() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+        let variables = variables(&[("host", "work-laptop"), ("work_laptop", "#000")]);
+
+        let rendered = handlebars
+            .render_template("{{ lookup_var (replace host \"-\" \"_\") }}", &variables)?;
+        assert_eq!(rendered, "#000");
+
+        let missing = handlebars.render_template("{{ lookup_var \"home_desktop\" }}", &variables);
+        assert!(missing.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_trim_newlines_after_block_tags() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+        let template = "[core]\n{{#if pager}}pager = less{{/if}}\nname = ponto\n";
+        let variables = HashMap::from([("pager".to_string(), false)]);
+
+        let untrimmed = handlebars.render_template(template, &variables)?;
+        let trimmed = handlebars.render_template(&trim_blocks(template), &variables)?;
+
+        assert_eq!(untrimmed, "[core]\n\nname = ponto\n");
+        assert_eq!(trimmed, "[core]\nname = ponto\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_join_path_components() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+        let data = variables(&[("home", "/home/me"), ("app", "nvim")]);
+
+        let rendered =
+            handlebars.render_template("{{ os_path home \".config\" app \"init.lua\" }}", &data)?;
+
+        let separator = std::path::MAIN_SEPARATOR;
+        assert_eq!(
+            rendered,
+            format!("/home/me{separator}.config{separator}nvim{separator}init.lua")
+        );
+        assert!(handlebars.render_template("{{ os_path }}", &data).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_serialize_nested_map_as_yaml_and_json() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+        let data = serde_json::json!({
+            "settings": {"editor": {"tabs": 4, "plugins": ["git", "lsp"]}}
+        });
+
+        let yaml = handlebars.render_template("{{ to_yaml settings }}", &data)?;
+        assert_eq!(yaml, "editor:\n  plugins:\n  - git\n  - lsp\n  tabs: 4");
+        assert_eq!(serde_yaml::from_str::<JsonValue>(&yaml)?, data["settings"]);
+
+        let json = handlebars.render_template("extra: {{ to_json settings }}", &data)?;
+        let json = json.strip_prefix("extra: ").unwrap();
+        assert_eq!(serde_json::from_str::<JsonValue>(json)?, data["settings"]);
+        assert!(json.contains("\n  \"editor\": {"));
+
+        let converted =
+            handlebars.render_template("{{#to_json format=\"yaml\"}}tabs: 4{{/to_json}}", &data)?;
+        assert_eq!(converted, "{\n  \"tabs\": 4\n}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_shell_quote_values() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+
+        for (value, quoted) in [
+            ("two words", "'two words'"),
+            ("it's", r"'it'\''s'"),
+            ("$HOME", "'$HOME'"),
+        ] {
+            let rendered = handlebars
+                .render_template("{{ shell_quote value }}", &variables(&[("value", value)]))?;
+            assert_eq!(rendered, quoted);
+        }
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote("it's $HOME")))
+            .output()?;
+        assert_eq!(String::from_utf8(output.stdout)?, "it's $HOME");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_evaluate_conditions() -> Result<()> {
+        let handlebars = init(RetryPolicy::default())?;
+        let variables = variables(&[("gui", "false"), ("os", "linux"), ("cores", "8")]);
+
+        assert!(!condition_holds(&handlebars, "{{ gui }}", &variables)?);
+        assert!(condition_holds(&handlebars, "{{ os }}", &variables)?);
+        assert!(condition_holds(&handlebars, "{{ cores }} > 4", &variables)?);
+        assert!(!condition_holds(
+            &handlebars,
+            "\"{{ os }}\" == \"macos\"",
+            &variables
+        )?);
+
+        Ok(())
+    }
 }
@@ -1,11 +1,28 @@
 use crate::config::Variables;
+use crate::process;
+use crate::retry::RetryPolicy;
 use anyhow::{Context, Result};
-use handlebars::Handlebars;
+use handlebars::{Handlebars, RenderError, RenderErrorReason};
 use log::{debug, info, trace};
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::process::{Child, Command};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::Duration;
+
+/// Extension of rendered hook scripts unless `--templated-extension` says otherwise.
+pub const DEFAULT_TEMPLATED_EXTENSION: &str = "templated";
+
+/// Exit code a pre hook uses to stop the deploy without failing it.
+pub const ABORT_EXIT_CODE: i32 = 42;
+
+/// Whether to go on after a hook ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// The hook exited with [`ABORT_EXIT_CODE`]
+    Abort,
+}
 
 #[macro_export]
 macro_rules! cwd {
@@ -16,24 +33,76 @@ macro_rules! cwd {
 }
 
 pub trait Hook {
-    fn run(location: &Path, handlebars: &Handlebars<'_>, variables: &Variables) -> Result<()> {
+    /// Renders the hook with `handlebars` next to it, with its extension
+    /// replaced by `templated_extension`, and runs it. Deploys pass the same
+    /// registry file templates use, so hooks get the same helpers, strict
+    /// mode and lack of HTML escaping. `secrets` are left out, so their values
+    /// never land in the rendered script and, in strict mode, a hook using
+    /// one fails to render. Exiting with [`ABORT_EXIT_CODE`] asks to stop
+    /// cleanly, any other failure is an error.
+    fn run(
+        location: &Path,
+        templated_extension: &str,
+        handlebars: &Handlebars<'_>,
+        variables: &Variables,
+        secrets: &[String],
+        retry: RetryPolicy,
+        dry_run: bool,
+    ) -> Result<Flow> {
         if !location.exists() {
             debug!("No hook at {:?}", location);
-            return Ok(());
+            return Ok(Flow::Continue);
+        }
+        let script_location = cwd!().join(location);
+        let public = variables
+            .iter()
+            .filter(|(name, _)| !secrets.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Variables>();
+        let script_location =
+            render_template(&script_location, templated_extension, handlebars, &public)
+                .map_err(|e| explain_secret(e, secrets))?;
+
+        if dry_run {
+            info!("Would run hook at {:?}", location);
+            fs::remove_file(&script_location).context("remove rendered hook")?;
+            return Ok(Flow::Continue);
         }
         info!("Running hook at {:?}", location);
+        let aborted = |status: &ExitStatus| status.code() == Some(ABORT_EXIT_CODE);
+        let status = retry.run(
+            || run_script_file(&script_location, retry.timeout),
+            |status| status.success() || aborted(status),
+        )?;
+        if aborted(&status) {
+            info!("hook at {location:?} asked to abort");
+            return Ok(Flow::Abort);
+        }
 
-        let script_location = cwd!().join(location);
-        render_template(&script_location, handlebars, variables)?;
-        let script_location = script_location.with_extension("templated");
-        let mut child = run_script_file(&script_location)?;
+        anyhow::ensure!(status.success(), "subshell returned error");
 
-        anyhow::ensure!(
-            child.wait().context("wait for child shell")?.success(),
-            "subshell returned error"
-        );
+        Ok(Flow::Continue)
+    }
+}
 
-        Ok(())
+/// Points a render error caused by a secret variable at why it's missing,
+/// leaving other errors alone.
+fn explain_secret(error: anyhow::Error, secrets: &[String]) -> anyhow::Error {
+    let secret =
+        error.chain().find_map(
+            |cause| match cause.downcast_ref::<RenderError>()?.reason() {
+                RenderErrorReason::MissingVariable(Some(path)) => {
+                    let name = path.split(['.', '/']).next()?;
+                    secrets.iter().find(|secret| *secret == name)
+                }
+                _ => None,
+            },
+        );
+    match secret {
+        Some(secret) => error.context(format!(
+            "{secret:?} is a secret variable, secret variables aren't available to hooks"
+        )),
+        None => error,
     }
 }
 
@@ -43,41 +112,45 @@ pub struct Post;
 impl Hook for Pre {}
 impl Hook for Post {}
 
-fn run_script_file(script: &Path) -> Result<Child> {
+fn run_script_file(script: &Path, timeout: Option<Duration>) -> Result<ExitStatus> {
     let permissions = script.metadata()?.permissions();
-    if !script.is_dir() && permissions.mode() & 0o111 != 0 {
-        Command::new(script).spawn().context("spawn script file")
+    let mut command = if !script.is_dir() && permissions.mode() & 0o111 != 0 {
+        Command::new(script)
     } else {
-        Command::new("sh")
-            .arg(script)
-            .spawn()
-            .context("spawn shell")
-    }
+        let mut command = Command::new("sh");
+        command.arg(script);
+        command
+    };
+    let output = process::output(&mut command, timeout).context("run script file")?;
+
+    Ok(output.status)
 }
 
+/// Renders the script at `source` next to it, returning where to.
 fn render_template(
     source: &Path,
+    templated_extension: &str,
     handlebars: &Handlebars<'_>,
     variables: &Variables,
-) -> Result<()> {
+) -> Result<PathBuf> {
     let file_contents = std::fs::read_to_string(source).context("read template source file")?;
     let rendered = handlebars
         .render_template(&file_contents, variables)
         .context("render template")?;
 
-    let templated_source = source.with_extension("templated");
-    fs::write(templated_source, rendered)?;
+    let templated_source = source.with_extension(templated_extension.trim_start_matches('.'));
+    fs::write(&templated_source, rendered)?;
 
-    Ok(())
+    Ok(templated_source)
 }
 
-pub fn remove_templated_scripts() -> Result<()> {
-    let templated = fs::read_dir(cwd!())?
-        .filter_map(Result::ok)
-        .filter(|entry| {
-            let path = entry.path();
-            path.extension().map_or(false, |ext| ext == "templated")
-        });
+pub fn remove_templated_scripts(dir: &Path, templated_extension: &str) -> Result<()> {
+    let templated_extension = templated_extension.trim_start_matches('.');
+    let templated = fs::read_dir(dir)?.filter_map(Result::ok).filter(|entry| {
+        let path = entry.path();
+        path.extension()
+            .is_some_and(|ext| ext == templated_extension)
+    });
     for entry in templated {
         trace!("removing templated script: {:?}", entry.path());
         fs::remove_file(entry.path())?;
@@ -90,9 +163,11 @@ pub fn remove_templated_scripts() -> Result<()> {
 mod tests {
     use super::*;
     use crate::config::Variables;
+    use crate::retry::RetryPolicy;
     use handlebars::Handlebars;
     use std::fs::File;
     use std::io::Write;
+    use std::time::Duration;
     use tempdir::TempDir;
 
     #[test]
@@ -105,7 +180,101 @@ mod tests {
         let handlebars = Handlebars::new();
         let variables = Variables::new();
 
-        Pre::run(&script, &handlebars, &variables)?;
+        Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &handlebars,
+            &variables,
+            &[],
+            RetryPolicy::default(),
+            false,
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_retry_failing_hook() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+
+        let counter = dir.path().join("counter");
+        let script = dir.path().join("script.sh");
+        File::create(&script)?.write_all(
+            format!(
+                "if [ -f {counter} ]; then exit 0; else touch {counter}; exit 1; fi",
+                counter = counter.display()
+            )
+            .as_bytes(),
+        )?;
+
+        let result = Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &Variables::new(),
+            &[],
+            RetryPolicy::default(),
+            false,
+        );
+        assert!(result.is_err());
+        fs::remove_file(&counter)?;
+
+        Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &Variables::new(),
+            &[],
+            RetryPolicy::new(1, Duration::ZERO),
+            false,
+        )?;
+        assert!(counter.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_not_pass_secret_variables_to_hooks() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+        let config_path = dir.path().join("config.yaml");
+        fs::write(
+            &config_path,
+            "variables:\n  user: me\n  token: hunter2\nsecret_variables: [token]\n",
+        )?;
+        let config = crate::config::load_config(&config_path, None, None, false)?;
+
+        let output = dir.path().join("output");
+        let script = dir.path().join("script.sh");
+        let mut strict = Handlebars::new();
+        strict.set_strict_mode(true);
+        let run = |contents: &str| {
+            fs::write(&script, contents)?;
+            Pre::run(
+                &script,
+                DEFAULT_TEMPLATED_EXTENSION,
+                &strict,
+                &config.variables,
+                &config.secret_variables,
+                RetryPolicy::default(),
+                false,
+            )
+        };
+
+        run(&format!("echo \"{{{{ user }}}}\" > {}", output.display()))?;
+        assert_eq!(fs::read_to_string(&output)?.trim(), "me");
+
+        let error = run(&format!(
+            "curl -H \"Authorization: {{{{ token }}}}\" > {}",
+            output.display()
+        ))
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("secret variables aren't available to hooks"),
+            "{error:#}"
+        );
+        assert_eq!(fs::read_to_string(&output)?.trim(), "me");
 
         Ok(())
     }
@@ -113,30 +282,56 @@ mod tests {
     #[test]
     fn should_remove_templated_scripts() -> Result<()> {
         let dir = TempDir::new("hook")?;
-        // override current dir
-        std::env::set_current_dir(dir.path())?;
 
         let script = dir.path().join("script.sh");
         File::create(&script)?.write_all(b"echo 'Hello, {{name}}!'")?;
 
         let templated = dir.path().join("script.templated");
-        let variables = vec![("name".to_string(), "world".to_string())]
+        let variables = vec![("name".to_string(), "world".into())]
             .into_iter()
             .collect::<Variables>();
 
         assert!(!templated.exists());
 
-        Pre::run(&script, &Handlebars::new(), &variables)?;
+        Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &variables,
+            &[],
+            RetryPolicy::default(),
+            false,
+        )?;
 
         assert!(templated.exists());
 
-        remove_templated_scripts()?;
+        remove_templated_scripts(dir.path(), DEFAULT_TEMPLATED_EXTENSION)?;
 
         assert!(!templated.exists());
 
         Ok(())
     }
 
+    #[test]
+    fn should_render_hook_with_custom_extension() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+        let script = dir.path().join("script.sh");
+        fs::write(&script, "echo 'Hello, {{name}}!'")?;
+        let variables = vec![("name".to_string(), "world".into())]
+            .into_iter()
+            .collect::<Variables>();
+
+        let rendered = render_template(&script, ".rendered", &Handlebars::new(), &variables)?;
+
+        assert_eq!(rendered, dir.path().join("script.rendered"));
+        assert!(!script.with_extension("templated").exists());
+        remove_templated_scripts(dir.path(), "rendered")?;
+        assert!(!rendered.exists());
+        assert!(script.exists());
+
+        Ok(())
+    }
+
     #[test]
     fn should_render_script_template() -> Result<()> {
         let dir = TempDir::new("hook")?;
@@ -145,13 +340,18 @@ mod tests {
         File::create(&script)?.write_all(b"echo 'Hello, {{name}}!'")?;
 
         let desired_templated_script = dir.path().join("script.templated");
-        let variables = vec![("name".to_string(), "world".to_string())]
+        let variables = vec![("name".to_string(), "world".into())]
             .into_iter()
             .collect::<Variables>();
 
         assert!(!desired_templated_script.exists());
 
-        render_template(&script, &Handlebars::new(), &variables)?;
+        render_template(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &variables,
+        )?;
 
         assert!(desired_templated_script.exists());
         let templated_contents = fs::read_to_string(&desired_templated_script)?;
@@ -159,4 +359,92 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_render_hook_with_file_template_helpers() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+        let output = dir.path().join("output");
+        let script = dir.path().join("post.sh");
+        fs::write(
+            &script,
+            format!(
+                "echo '{{{{ command_output \"echo hi\" }}}} {{{{ markup }}}}' > {}",
+                output.display()
+            ),
+        )?;
+        let handlebars = crate::handlebars::init(RetryPolicy::default())?;
+        let variables = vec![("markup".to_string(), "<a & b>".into())]
+            .into_iter()
+            .collect::<Variables>();
+
+        Post::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &handlebars,
+            &variables,
+            &[],
+            RetryPolicy::default(),
+            false,
+        )?;
+        assert_eq!(fs::read_to_string(&output)?.trim(), "hi\n <a & b>");
+
+        // strict mode applies to hooks too
+        fs::write(&script, "echo {{ missing }}")?;
+        let result = Post::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &handlebars,
+            &variables,
+            &[],
+            RetryPolicy::default(),
+            false,
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_only_render_hook_on_dry_run() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+        let created = dir.path().join("created");
+        let script = dir.path().join("pre.sh");
+        fs::write(&script, format!("touch {}", created.display()))?;
+
+        Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &Variables::new(),
+            &[],
+            RetryPolicy::default(),
+            true,
+        )?;
+
+        assert!(!created.exists());
+        assert!(!script.with_extension("templated").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_kill_hook_exceeding_timeout() -> Result<()> {
+        let dir = TempDir::new("hook")?;
+        let script = dir.path().join("pre.sh");
+        fs::write(&script, "sleep 5")?;
+
+        let result = Pre::run(
+            &script,
+            DEFAULT_TEMPLATED_EXTENSION,
+            &Handlebars::new(),
+            &Variables::new(),
+            &[],
+            RetryPolicy::default().with_timeout(Some(Duration::from_millis(100))),
+            false,
+        );
+
+        assert!(format!("{:?}", result.unwrap_err()).contains("timed out"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const CONFIG: &str = r#"# Variables available to every template and hook.
+variables:
+  editor: vim
+
+# Each top-level key besides `variables` is a package mapping sources in this
+# repository to targets. Uncomment and adapt this one to get started.
+#
+# shell:
+#   depends: []
+#   variables:
+#     prompt: "> "
+#   files:
+#     # symlinked, or rendered when the source contains {{ }} placeholders
+#     shell/.bashrc: ~/.bashrc
+#     # copied instead of symlinked
+#     shell/.inputrc:
+#       to: ~/.inputrc
+#       symlink: false
+"#;
+
+const PRE_HOOK: &str = "#!/bin/sh
+# Runs before deploying. It is rendered as a template first, so variables
+# like {{ editor }} can be used here.
+";
+
+const POST_HOOK: &str = "#!/bin/sh
+# Runs after deploying. It is rendered as a template first, so variables
+# like {{ editor }} can be used here.
+";
+
+/// Writes a starter config and hook scripts, refusing to overwrite existing
+/// files unless forced.
+pub fn run(config: &Path, pre: &Path, post: &Path, force: bool) -> Result<()> {
+    let files = [(config, CONFIG), (pre, PRE_HOOK), (post, POST_HOOK)];
+
+    if !force {
+        let existing = files
+            .iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        anyhow::ensure!(
+            existing.is_empty(),
+            "refusing to overwrite {existing:?}, use --force to replace them"
+        );
+    }
+
+    for (path, contents) in files {
+        if path.exists() {
+            warn!("overwriting {path:?}");
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("create dir all")?;
+        }
+        fs::write(path, contents).with_context(|| format!("write {path:?}"))?;
+        info!("created {path:?}");
+    }
+    for hook in [pre, post] {
+        fs::set_permissions(hook, fs::Permissions::from_mode(0o755))
+            .context("make hook executable")?;
+    }
+
+    Ok(())
+}
+
+/// What to tell someone running ponto for the first time, when there's no
+/// config where it looks by default.
+pub fn first_run_guidance(config: &Path) -> String {
+    format!(
+        "no config found at {config:?}, where ponto looks by default.\n\
+         Run `ponto --init` to write a starter config and hooks there, or pass \
+         --config to use one somewhere else."
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::options::DEFAULT_CONFIG;
+    use tempdir::TempDir;
+
+    #[test]
+    fn should_scaffold_a_loadable_config() -> Result<()> {
+        let dir = TempDir::new("init")?;
+        let config_path = dir.path().join("ponto").join("config.yaml");
+        let pre = dir.path().join("ponto").join("pre.sh");
+        let post = dir.path().join("ponto").join("post.sh");
+
+        run(&config_path, &pre, &post, false)?;
+
+        let config = config::load_config(&config_path, None, None, false)?;
+        assert_eq!(config.variables["editor"], "vim");
+        assert!(config.packages.is_empty());
+        assert!(pre.exists() && post.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_guide_first_run_to_init() {
+        let guidance = first_run_guidance(Path::new(DEFAULT_CONFIG));
+
+        assert!(guidance.contains("\"ponto/config.yaml\""), "{guidance}");
+        assert!(guidance.contains("ponto --init"), "{guidance}");
+    }
+
+    #[test]
+    fn should_not_overwrite_without_force() -> Result<()> {
+        let dir = TempDir::new("init")?;
+        let config_path = dir.path().join("config.yaml");
+        fs::write(&config_path, "mine: {}")?;
+        let pre = dir.path().join("pre.sh");
+        let post = dir.path().join("post.sh");
+
+        assert!(run(&config_path, &pre, &post, false).is_err());
+        assert_eq!(fs::read_to_string(&config_path)?, "mine: {}");
+        assert!(!pre.exists());
+
+        run(&config_path, &pre, &post, true)?;
+        assert_eq!(fs::read_to_string(&config_path)?, CONFIG);
+
+        Ok(())
+    }
+}
@@ -1,7 +1,37 @@
 use anyhow::Result;
+use clap::ValueEnum;
 use simple_logger::SimpleLogger;
 
-pub fn init(verbosity: u8, quiet: bool) -> Result<()> {
+/// How log lines are timestamped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Timestamps {
+    None,
+    #[default]
+    Utc,
+    Local,
+}
+
+/// When output, logs included, is colored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Color {
+    /// Only when writing to a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+pub fn init(verbosity: u8, quiet: bool, timestamps: Timestamps, color: Color) -> Result<()> {
+    match color {
+        Color::Auto => {}
+        Color::Always => colored::control::set_override(true),
+        Color::Never => colored::control::set_override(false),
+    }
+    logger(verbosity, quiet, timestamps).init()?;
+    Ok(())
+}
+
+fn logger(verbosity: u8, quiet: bool, timestamps: Timestamps) -> SimpleLogger {
     let level = match (verbosity, quiet) {
         (0, false) => log::LevelFilter::Info,
         (1, false) => log::LevelFilter::Debug,
@@ -10,9 +40,38 @@ pub fn init(verbosity: u8, quiet: bool) -> Result<()> {
         _ => unreachable!("invalid verbosity level"),
     };
 
-    SimpleLogger::new()
+    let logger = SimpleLogger::new()
         .with_level(log::LevelFilter::Error)
-        .with_module_level("ponto", level)
-        .init()?;
-    Ok(())
+        .with_module_level("ponto", level);
+    match timestamps {
+        Timestamps::None => logger.without_timestamps(),
+        Timestamps::Utc => logger.with_utc_timestamps(),
+        // the offset can only be looked up while single threaded, so it's
+        // resolved now rather than on every log line
+        Timestamps::Local => match time::UtcOffset::current_local_offset() {
+            Ok(offset) => logger.with_utc_offset(offset),
+            Err(_) => logger.with_utc_timestamps(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Log;
+
+    #[test]
+    fn should_build_logger_for_each_timestamp_mode() {
+        for timestamps in Timestamps::value_variants() {
+            let logger = logger(1, false, *timestamps);
+            logger.log(
+                &log::Record::builder()
+                    .module_path(Some("ponto"))
+                    .level(log::Level::Debug)
+                    .args(format_args!("{timestamps:?} timestamps"))
+                    .build(),
+            );
+            logger.flush();
+        }
+    }
 }
@@ -1,26 +1,122 @@
+mod archive;
 mod config;
 mod deploy;
+mod diff;
+mod doctor;
+mod error;
 mod file_type;
 mod filesystem;
+mod fs_trace;
+mod glob;
 mod handlebars;
 mod hook;
+mod init;
 mod logger;
 mod options;
+mod plan;
+mod privileged;
+mod process;
+mod report;
+mod retry;
+mod rollback;
+mod schema;
+mod secret;
+mod state;
 mod symlink;
 mod template;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
+use config::Configuration;
+use error::PontoError;
 use options::Options;
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {e:?}");
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+fn run() -> Result<()> {
     let opts = Options::parse();
 
-    logger::init(opts.verbosity, opts.quiet)?;
+    logger::init(opts.verbosity, opts.quiet, opts.timestamps(), opts.color)?;
+
+    if opts.init {
+        return init::run(
+            &opts.config(),
+            &opts.pre_hook(),
+            &opts.post_hook(),
+            opts.force,
+        );
+    }
+
+    if opts.doctor {
+        return doctor::run(&opts);
+    }
 
-    let config = config::load_config(&opts.config)?;
+    if opts.config_schema {
+        println!("{:#}", schema::config_schema());
+        return Ok(());
+    }
+
+    let config_path = opts.config();
+    if opts.config_migrate {
+        anyhow::ensure!(
+            opts.config.len() <= 1,
+            "--config-migrate takes a single --config, migrate them one by one"
+        );
+        anyhow::ensure!(
+            !(opts.in_place && opts.template_config),
+            "--in-place would replace the config template with its rendered form"
+        );
+        let migrated =
+            config::migrate_config(&config_path, opts.config_format, opts.template_config)
+                .context(PontoError::Config)?;
+        if opts.in_place {
+            std::fs::write(&config_path, migrated)
+                .with_context(|| format!("writing migrated config to {config_path:?}"))?;
+        } else {
+            print!("{migrated}");
+        }
+        return Ok(());
+    }
+    if opts.config.is_empty() && !config_path.exists() {
+        return Err(anyhow::anyhow!(init::first_run_guidance(&config_path)))
+            .context(PontoError::Config);
+    }
+    // started once, so every home of --homes is recorded
+    let _trace = opts
+        .trace_file
+        .as_deref()
+        .map(fs_trace::Trace::start)
+        .transpose()?;
+    if !opts.homes.is_empty() {
+        return deploy::run_homes(opts, load);
+    }
+    let (config, opts) = load(opts)?;
+
+    deploy::run(config, opts)
+}
 
-    deploy::deploy(config, opts)?;
+/// Loads the configs and fills in the options the command line left unset
+/// from them.
+fn load(opts: Options) -> Result<(Configuration, Options)> {
+    let mut config = config::load_configs(
+        &opts.configs(),
+        opts.config_format,
+        opts.home.as_deref(),
+        opts.template_config,
+    )
+    .context(PontoError::Config)?;
+    let opts = opts.with_defaults(&config.defaults);
+    config
+        .exclude_packages(&opts.exclude_packages, opts.exclude_dependents)
+        .context(PontoError::Config)?;
+    if let Some(max_depth) = opts.max_depth {
+        config.default_max_depth(max_depth as usize);
+    }
 
-    Ok(())
+    Ok((config, opts))
 }
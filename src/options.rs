@@ -1,35 +1,480 @@
+use crate::config::{ConfigFormat, ConflictPolicy, Defaults, Variables};
+use crate::error::EXIT_CODES;
+use crate::hook::DEFAULT_TEMPLATED_EXTENSION;
+use crate::logger::{Color, Timestamps};
+use crate::retry::RetryPolicy;
+use crate::secret::{Decrypter, IDENTITY_VAR};
 use clap::Parser;
 use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_FORCE_THRESHOLD: usize = 20;
+const DEFAULT_DIFF_CONTEXT: usize = 3;
+
+/// Where the config is looked for unless `--config` says otherwise.
+pub const DEFAULT_CONFIG: &str = "ponto/config.yaml";
 
 #[derive(Debug, Parser, Default, Clone)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, after_help = EXIT_CODES)]
 pub struct Options {
-    #[clap(short, long, value_parser, default_value = "ponto/config.yaml")]
-    pub config: PathBuf,
+    /// Config file, or a directory whose yaml and toml files are merged.
+    /// Repeat it to merge several in order [default: ponto/config.yaml]
+    #[clap(short, long, value_parser)]
+    pub config: Vec<PathBuf>,
+
+    /// Expand `~` in config paths to this directory instead of `$HOME`
+    #[clap(long, value_parser)]
+    pub home: Option<PathBuf>,
+
+    /// Run once for each of these home directories, expanding `~` to each
+    /// in turn (comma separated or repeatable)
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        value_name = "DIR",
+        conflicts_with_all = ["home", "summary_json"]
+    )]
+    pub homes: Vec<PathBuf>,
+
+    /// Write a starter config and hooks at the configured locations and exit
+    #[clap(long, value_parser)]
+    pub init: bool,
+
+    /// Check that the environment has what deploys need, such as `sh`,
+    /// symlink support in the home directory and a readable config, and exit
+    #[clap(long, value_parser)]
+    pub doctor: bool,
+
+    /// Print a JSON Schema of the config format and exit
+    #[clap(long, value_parser)]
+    pub config_schema: bool,
 
-    #[clap(long, value_parser, default_value = "ponto/pre.sh")]
-    pub pre: PathBuf,
+    /// Print the config rewritten in its current canonical form and exit,
+    /// with default settings dropped and keys sorted
+    #[clap(long, value_parser)]
+    pub config_migrate: bool,
 
-    #[clap(long, value_parser, default_value = "ponto/post.sh")]
-    pub post: PathBuf,
+    /// Write the migrated config over the original instead of printing it
+    #[clap(long, value_parser, requires = "config_migrate")]
+    pub in_place: bool,
+
+    /// Parse the config as this format instead of detecting it by extension
+    #[clap(long, value_enum)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Render the config as a handlebars template before parsing it. Only
+    /// `os`, `arch`, `env` and helpers are available, not variables
+    #[clap(long, value_parser)]
+    pub template_config: bool,
+
+    /// Hook run before deploying. Exiting with 42 stops the deploy without
+    /// failing it [default: pre.sh next to the config]
+    #[clap(long, value_parser)]
+    pub pre: Option<PathBuf>,
+
+    /// Hook run after deploying [default: post.sh next to the config]
+    #[clap(long, value_parser)]
+    pub post: Option<PathBuf>,
 
     #[clap(short, long, value_parser)]
     pub force: bool,
 
+    /// How to treat targets in the way, overriding the config's `defaults`.
+    /// `backup` is the same as --force
+    #[clap(long, value_enum)]
+    pub conflict_policy: Option<ConflictPolicy>,
+
+    /// Force only this source or target, leaving the others alone (repeatable)
+    #[clap(long = "force-file", value_name = "TARGET_OR_SOURCE", value_parser)]
+    pub force_files: Vec<PathBuf>,
+
+    /// Ask before a forced deploy overwrites more than this many differing targets [default: 20]
+    #[clap(long, value_parser, value_name = "COUNT")]
+    pub force_threshold: Option<usize>,
+
+    /// Don't ask before a forced deploy overwrites many targets
+    #[clap(short, long, value_parser)]
+    pub yes: bool,
+
+    /// Extension given to rendered hook scripts [default: templated]
+    #[clap(long, value_parser, value_name = "EXTENSION")]
+    pub templated_extension: Option<String>,
+
+    /// Keep the rendered hook scripts around for inspection
+    #[clap(long, value_parser)]
+    pub no_delete_templated: bool,
+
+    /// Show how deployed files differ from what would be deployed, without
+    /// changing anything
+    #[clap(long, value_parser)]
+    pub diff_only: bool,
+
+    /// Unchanged lines shown around each change by --diff-only [default: 3]
+    #[clap(long, value_parser, value_name = "LINES")]
+    pub diff_context: Option<usize>,
+
+    /// List the targets that differ from the config without changing
+    /// anything, exiting with 3 when there is any
+    #[clap(long, value_parser)]
+    pub list_drift: bool,
+
+    /// Render every template without deploying, reporting each one that fails
+    #[clap(long, value_parser)]
+    pub validate_templates: bool,
+
+    /// Print each source and the absolute target it deploys to, then exit
+    #[clap(long, value_parser)]
+    pub print_targets: bool,
+
+    /// Check every target reached its expected state after deploying
+    #[clap(long, value_parser)]
+    pub verify: bool,
+
+    /// How many levels linking directory contents descends, for targets
+    /// without a `max_depth` of their own
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), value_name = "LEVELS")]
+    pub max_depth: Option<u64>,
+
+    /// Leave this package out of the run (repeatable)
+    #[clap(long = "exclude-package", value_name = "NAME", value_parser)]
+    pub exclude_packages: Vec<String>,
+
+    /// Also leave out packages depending on an excluded one, instead of failing
+    #[clap(long, value_parser, requires = "exclude_packages")]
+    pub exclude_dependents: bool,
+
+    /// Only deploy files whose source or target matches this glob
+    #[clap(short = 'm', long = "match", value_parser)]
+    pub pattern: Option<String>,
+
+    /// Retry failing hooks and command helpers this many times
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub retries: u32,
+
+    /// Delay in milliseconds before the first retry, doubled on each attempt
+    #[clap(long, value_parser, default_value_t = 1000)]
+    pub retry_delay: u64,
+
+    /// YAML, JSON or TOML file of variables, overridden by the config's own
+    #[clap(long, value_parser)]
+    pub data: Option<PathBuf>,
+
+    /// Override a variable, taking precedence over the config and
+    /// `PONTO_VAR_<KEY>` environment variables (repeatable)
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+
+    /// Render missing variables as empty instead of failing, in every template
+    /// and hook without a `strict` of its own. Typos in variable names then
+    /// go unnoticed
+    #[clap(long, value_parser)]
+    pub no_strict: bool,
+
+    /// Register the config's `helpers`, which run their scripts while rendering
+    #[clap(long, value_parser)]
+    pub allow_command_helpers: bool,
+
+    /// Treat rendered templates and targets that only differ in CRLF and LF
+    /// line endings as equal, leaving such targets untouched
+    #[clap(long, value_parser)]
+    pub normalize_eol: bool,
+
+    /// Drop the newline after each block tag in templates, like Jinja's trim_blocks
+    #[clap(long, value_parser)]
+    pub trim_blocks: bool,
+
+    /// Deploy sources that are symlinks as links instead of as the files they point at
+    #[clap(long, value_parser)]
+    pub no_dereference: bool,
+
+    /// Link to sources by the path given instead of resolving symlinks in it
+    #[clap(long, value_parser)]
+    pub no_canonicalize: bool,
+
+    /// Umask, in octal, for files created while deploying, hooks included.
+    /// Copies keep their source's permissions
+    #[clap(long, value_name = "OCTAL", value_parser = parse_umask)]
+    pub umask: Option<u32>,
+
+    /// Show what would be deployed without changing any file or running hooks
+    #[clap(long, value_parser)]
+    pub dry_run: bool,
+
+    /// Fail before deploying anything when a configured source doesn't
+    /// exist, listing every missing one
+    #[clap(long, value_parser)]
+    pub fail_on_missing_source: bool,
+
+    /// Fail when deploying any target raised a warning, listing every one
+    #[clap(long, value_parser)]
+    pub warnings_as_errors: bool,
+
+    /// Do nothing when neither the config nor any source changed since the
+    /// last deploy that used this flag. Changes made to targets go unnoticed
+    #[clap(long, value_parser)]
+    pub skip_if_unchanged: bool,
+
+    /// Where to keep what --skip-if-unchanged compares against
+    /// [default: $XDG_STATE_HOME/ponto or ~/.local/state/ponto]
+    #[clap(long, value_parser)]
+    pub state_dir: Option<PathBuf>,
+
+    /// After deploying, list every skipped target grouped by why
+    #[clap(long, value_parser)]
+    pub report_skipped: bool,
+
+    /// Write a JSON summary of every deployed target to this file, or `-` for stdout
+    #[clap(long, value_parser, value_name = "PATH|-")]
+    pub summary_json: Option<PathBuf>,
+
+    /// Record every filesystem operation of the deploy to this file as it
+    /// happens, one JSON object per line
+    #[clap(long, value_parser, value_name = "PATH")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Kill hooks and command helpers still running after this many seconds.
+    /// When stdin is a terminal only the hook itself is killed, not the
+    /// commands it started, so interactive hooks can still use the terminal
+    #[clap(long, value_parser, value_name = "SECONDS")]
+    pub hook_timeout: Option<u64>,
+
+    /// Undo this run's changes to targets when the post hook fails
+    #[clap(long, value_parser)]
+    pub rollback_on_hook_failure: bool,
+
+    /// Deploy the files of each package concurrently, unless they're listed in order
+    #[clap(long, value_parser)]
+    pub parallel_files: bool,
+
+    /// Keep deploying the remaining files when one fails, reporting every failure at the end
+    #[clap(long, value_parser)]
+    pub keep_going: bool,
+
+    /// age identity file decrypting `.age` and `encrypted` sources
+    /// [default: $PONTO_AGE_IDENTITY]
+    #[clap(long, value_parser)]
+    pub identity: Option<PathBuf>,
+
+    /// age compatible command used to decrypt sources [default: age]
+    #[clap(long, value_parser)]
+    pub age_command: Option<String>,
+
+    /// Command used to deploy targets marked `sudo` [default: sudo]
+    #[clap(long, value_parser)]
+    pub sudo_command: Option<String>,
+
     #[clap(short, long, value_parser)]
     pub quiet: bool,
 
+    /// How log lines are timestamped
+    #[clap(long, value_enum, default_value_t)]
+    pub timestamps: Timestamps,
+
+    /// Log without timestamps, same as `--timestamps none`
+    #[clap(long, value_parser, conflicts_with = "timestamps")]
+    pub no_timestamps: bool,
+
+    /// When to color output
+    #[clap(long, value_enum, default_value_t)]
+    pub color: Color,
+
     #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
     pub verbosity: u8,
 }
 
+impl Options {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(self.retries, Duration::from_millis(self.retry_delay))
+            .with_timeout(self.hook_timeout.map(Duration::from_secs))
+    }
+
+    pub fn timestamps(&self) -> Timestamps {
+        match self.no_timestamps {
+            true => Timestamps::None,
+            false => self.timestamps,
+        }
+    }
+
+    /// Fills in what the command line left unset from the config's `defaults`.
+    pub fn with_defaults(mut self, defaults: &Defaults) -> Self {
+        let force = match (self.conflict_policy, defaults.conflict_policy) {
+            (Some(policy), _) => policy == ConflictPolicy::Backup,
+            (None, policy) => defaults.force || policy == Some(ConflictPolicy::Backup),
+        };
+        self.force |= force;
+        self
+    }
+
+    pub fn templated_extension(&self) -> String {
+        self.templated_extension
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TEMPLATED_EXTENSION.to_owned())
+    }
+
+    pub fn diff_context(&self) -> usize {
+        self.diff_context.unwrap_or(DEFAULT_DIFF_CONTEXT)
+    }
+
+    pub fn force_threshold(&self) -> usize {
+        self.force_threshold.unwrap_or(DEFAULT_FORCE_THRESHOLD)
+    }
+
+    pub fn pre_hook(&self) -> PathBuf {
+        self.pre
+            .clone()
+            .unwrap_or_else(|| self.beside_config("pre.sh"))
+    }
+
+    pub fn post_hook(&self) -> PathBuf {
+        self.post
+            .clone()
+            .unwrap_or_else(|| self.beside_config("post.sh"))
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.state_dir {
+            return dir.clone();
+        }
+        match std::env::var_os("XDG_STATE_HOME") {
+            Some(state) if !state.is_empty() => PathBuf::from(state).join("ponto"),
+            _ => PathBuf::from(shellexpand::tilde("~/.local/state/ponto").as_ref()),
+        }
+    }
+
+    /// The configs to load, in order.
+    pub fn configs(&self) -> Vec<PathBuf> {
+        match self.config.is_empty() {
+            true => vec![PathBuf::from(DEFAULT_CONFIG)],
+            false => self.config.clone(),
+        }
+    }
+
+    /// The first config, which hooks and `--init` go next to.
+    pub fn config(&self) -> PathBuf {
+        self.configs().swap_remove(0)
+    }
+
+    /// `file_name` next to the config, or inside it when it's a directory of
+    /// fragments.
+    fn beside_config(&self, file_name: &str) -> PathBuf {
+        let config = self.config();
+        if config.is_dir() {
+            return config.join(file_name);
+        }
+        config
+            .parent()
+            .map_or_else(|| PathBuf::from(file_name), |dir| dir.join(file_name))
+    }
+
+    pub fn decrypter(&self) -> Decrypter {
+        Decrypter {
+            command: self.age_command.clone().unwrap_or_else(|| "age".to_owned()),
+            identity: self
+                .identity
+                .clone()
+                .or_else(|| std::env::var_os(IDENTITY_VAR).map(PathBuf::from)),
+            timeout: self.hook_timeout.map(Duration::from_secs),
+        }
+    }
+
+    pub fn overrides(&self) -> Variables {
+        self.overrides
+            .iter()
+            .map(|(key, value)| (key.to_owned(), value.as_str().into()))
+            .collect()
+    }
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {arg:?}")),
+    }
+}
+
+fn parse_umask(arg: &str) -> Result<u32, String> {
+    match u32::from_str_radix(arg, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!("expected an octal umask like 022, got {arg:?}")),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use clap::CommandFactory;
 
+    #[test]
+    fn should_prefer_command_line_to_config_defaults() {
+        let defaults = Defaults {
+            conflict_policy: None,
+            force: true,
+        };
+        assert!(Options::default().with_defaults(&defaults).force);
+
+        let skip = Options {
+            conflict_policy: Some(ConflictPolicy::Skip),
+            ..Default::default()
+        };
+        assert!(!skip.with_defaults(&defaults).force);
+
+        let backup = Defaults {
+            conflict_policy: Some(ConflictPolicy::Backup),
+            force: false,
+        };
+        assert!(Options::default().with_defaults(&backup).force);
+        assert!(!Options::default().with_defaults(&Defaults::default()).force);
+    }
+
     #[test]
     fn verify_cli() {
         Options::command().debug_assert()
     }
+
+    #[test]
+    fn should_default_hooks_next_to_config() {
+        let opts = Options::try_parse_from(["ponto"]).unwrap();
+        assert_eq!(opts.pre_hook(), PathBuf::from("ponto/pre.sh"));
+
+        let opts = Options::try_parse_from(["ponto", "-c", "/other/place/config.yaml"]).unwrap();
+        assert_eq!(opts.pre_hook(), PathBuf::from("/other/place/pre.sh"));
+        assert_eq!(opts.post_hook(), PathBuf::from("/other/place/post.sh"));
+
+        let opts = Options::try_parse_from([
+            "ponto",
+            "-c",
+            "/other/place/config.yaml",
+            "--post",
+            "hooks/post.sh",
+        ])
+        .unwrap();
+        assert_eq!(opts.post_hook(), PathBuf::from("hooks/post.sh"));
+    }
+
+    #[test]
+    fn should_reject_malformed_overrides() {
+        assert!(Options::try_parse_from(["ponto", "--set", "editor"]).is_err());
+        assert!(Options::try_parse_from(["ponto", "--set", "=vim"]).is_err());
+
+        let opts = Options::try_parse_from(["ponto", "--set", "editor=nvim=1"]).unwrap();
+        assert_eq!(
+            opts.overrides(),
+            [("editor".to_string(), "nvim=1".into())].into()
+        );
+    }
+
+    #[test]
+    fn should_not_share_a_summary_between_homes() {
+        let args = ["ponto", "--homes", "a,b", "--summary-json", "summary.json"];
+        assert!(Options::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn should_take_m_as_short_match() {
+        let opts = Options::try_parse_from(["ponto", "-m", "*.zshrc"]).unwrap();
+        assert_eq!(opts.pattern.as_deref(), Some("*.zshrc"));
+
+        assert!(Options::try_parse_from(["ponto", "-p", "*.zshrc"]).is_err());
+    }
 }
@@ -0,0 +1,98 @@
+use colored::Colorize;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+/// How a file gets deployed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Render,
+    RenderBytes,
+    Copy,
+    Link,
+    HardLink,
+    LinkContents,
+    Extract,
+    Decrypt,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let name = match self {
+            Action::Render => "render".cyan(),
+            Action::RenderBytes => "render bytes".cyan(),
+            Action::Copy => "copy".blue(),
+            Action::Link => "symlink".magenta(),
+            Action::HardLink => "hard link".magenta(),
+            Action::LinkContents => "symlink contents".magenta(),
+            Action::Extract => "extract".yellow(),
+            Action::Decrypt => "decrypt".green(),
+        };
+        name.fmt(f)
+    }
+}
+
+/// What a dry run would do to one target, and the state it's in now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub action: Action,
+    pub state: String,
+}
+
+/// Every step of a dry run, grouped by package in deploy order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub packages: Vec<(String, Vec<Step>)>,
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for (name, steps) in &self.packages {
+            writeln!(f, "{}/", name.bold())?;
+            for (i, step) in steps.iter().enumerate() {
+                let branch = if i + 1 == steps.len() {
+                    "└─"
+                } else {
+                    "├─"
+                };
+                writeln!(
+                    f,
+                    "{branch} {} -> {}: {} ({})",
+                    step.source.display(),
+                    step.target.display(),
+                    step.action,
+                    step.state
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_draw_steps_as_tree() {
+        colored::control::set_override(false);
+        let step = |source: &str, action| Step {
+            source: source.into(),
+            target: format!("/home/{source}").into(),
+            action,
+            state: "target missing".to_owned(),
+        };
+        let plan = Plan {
+            packages: vec![(
+                "nvim".to_owned(),
+                vec![step("init.lua", Action::Render), step("lua", Action::Link)],
+            )],
+        };
+
+        assert_eq!(
+            plan.to_string(),
+            "nvim/\n├─ init.lua -> /home/init.lua: render (target missing)\n└─ lua -> /home/lua: symlink (target missing)\n"
+        );
+    }
+}
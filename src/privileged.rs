@@ -0,0 +1,255 @@
+use crate::file_type::FileType;
+use crate::report::{Change, Outcome};
+use crate::symlink::{self, SymlinkState};
+use anyhow::{Context, Result};
+use log::{debug, trace};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Deploys files by shelling out through a privilege escalation command such
+/// as `sudo`, for targets the current user cannot write to.
+pub struct Privileged {
+    command: Vec<String>,
+}
+
+impl Privileged {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.split_whitespace().map(str::to_string).collect(),
+        }
+    }
+
+    pub fn copy(&self, from: &Path, to: &Path, force: bool) -> Result<Change> {
+        let change = if to.exists() {
+            Change::new("target exists", Outcome::Updated)
+        } else {
+            Change::new("target missing", Outcome::Created)
+        };
+        if to.exists() && !force {
+            return Ok(Change::new("target exists", Outcome::Skipped)
+                .with_warning(format!("file {to:?} already exists, skipping")));
+        }
+
+        self.create_parent(to)?;
+        self.run(&["cp".as_ref(), from.as_os_str(), to.as_os_str()])?;
+        Ok(change)
+    }
+
+    pub fn symlink(
+        &self,
+        from: &Path,
+        to: &Path,
+        force: bool,
+        canonicalize: bool,
+    ) -> Result<Change> {
+        let state = SymlinkState::from(
+            from,
+            FileType::try_from(from)?,
+            to,
+            FileType::try_from(to)?,
+            canonicalize,
+        )
+        .context("get symlink state")?;
+        trace!("{state}");
+
+        let outcome = match state {
+            SymlinkState::OnlySourceExists => Outcome::Created,
+            SymlinkState::Identical if force => Outcome::Updated,
+            _ => Outcome::Skipped,
+        };
+        if outcome != Outcome::Skipped {
+            let source = symlink::link_source(from, canonicalize)?;
+            self.create_parent(to)?;
+            self.run(&[
+                "ln".as_ref(),
+                "-sfn".as_ref(),
+                source.as_os_str(),
+                to.as_os_str(),
+            ])?;
+        }
+
+        Ok(Change::new(state, outcome))
+    }
+
+    /// Writes `contents` to `to` by piping them into `tee`, so they never
+    /// touch a file other users could read or replace.
+    pub fn write(&self, contents: &[u8], to: &Path, force: bool) -> Result<Change> {
+        let change = match fs::read(to) {
+            Err(_) => Change::new("target missing", Outcome::Created),
+            Ok(current) if current != contents => {
+                Change::new("contents differ from the target", Outcome::Updated)
+            }
+            Ok(_) if force => Change::new("contents equal the target", Outcome::Updated),
+            Ok(_) => return Ok(Change::new("contents equal the target", Outcome::Skipped)),
+        };
+
+        self.create_parent(to)?;
+        self.run_with_input(&["tee".as_ref(), to.as_os_str()], Some(contents))?;
+        Ok(change)
+    }
+
+    pub fn chown(&self, to: &Path, owner: Option<&str>, group: Option<&str>) -> Result<()> {
+        let owner = match (owner, group) {
+            (Some(owner), Some(group)) => format!("{owner}:{group}"),
+            (Some(owner), None) => owner.to_owned(),
+            (None, Some(group)) => format!(":{group}"),
+            (None, None) => return Ok(()),
+        };
+        self.run(&[
+            "chown".as_ref(),
+            "-h".as_ref(),
+            owner.as_ref(),
+            to.as_os_str(),
+        ])
+    }
+
+    pub fn set_read_only(&self, to: &Path) -> Result<()> {
+        self.run(&["chmod".as_ref(), "a-w".as_ref(), to.as_os_str()])
+    }
+
+    fn create_parent(&self, to: &Path) -> Result<()> {
+        match to.parent() {
+            Some(parent) if !parent.exists() => {
+                self.run(&["mkdir".as_ref(), "-p".as_ref(), parent.as_os_str()])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn run(&self, args: &[&std::ffi::OsStr]) -> Result<()> {
+        self.run_with_input(args, None)
+    }
+
+    /// Runs `args` through the escalation command, writing `input`, if any,
+    /// to its stdin and discarding its stdout.
+    fn run_with_input(&self, args: &[&std::ffi::OsStr], input: Option<&[u8]>) -> Result<()> {
+        let (program, escalation_args) = self
+            .command
+            .split_first()
+            .context("privilege escalation command is empty")?;
+        debug!("running {args:?} through {program}");
+
+        let mut command = Command::new(program);
+        command.args(escalation_args).args(args);
+        let context = || format!("run privilege escalation command {program:?}");
+        let (status, written) = match input {
+            None => (command.status().with_context(context)?, Ok(())),
+            Some(input) => {
+                let mut child = command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .spawn()
+                    .with_context(context)?;
+                // dropped once written, so the command sees the end of its input
+                let written = child
+                    .stdin
+                    .take()
+                    .map_or(Ok(()), |mut stdin| stdin.write_all(input));
+                (child.wait().with_context(context)?, written)
+            }
+        };
+        anyhow::ensure!(
+            status.success(),
+            "privilege escalation with {:?} failed ({status}) running {args:?}",
+            self.command.join(" ")
+        );
+        written.context("write input of privilege escalation command")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    /// Writes an escalation command that records it was used and then runs
+    /// its arguments unprivileged.
+    fn fake_sudo(dir: &Path, script: &str) -> Result<PathBuf> {
+        let path = dir.join("fake-sudo");
+        fs::write(&path, script)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        Ok(path)
+    }
+
+    #[test]
+    fn should_copy_and_link_through_escalation_command() -> Result<()> {
+        let dir = TempDir::new("privileged")?;
+        let marker = dir.path().join("escalated");
+        let sudo = fake_sudo(
+            dir.path(),
+            &format!(
+                "#!/bin/sh\necho \"$@\" >> {}\nexec \"$@\"\n",
+                marker.display()
+            ),
+        )?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "setting = 1")?;
+        let privileged = Privileged::new(&sudo.display().to_string());
+
+        let copied = dir.path().join("etc").join("copied.conf");
+        privileged.copy(&source, &copied, false)?;
+        let linked = dir.path().join("etc").join("linked.conf");
+        privileged.symlink(&source, &linked, false, true)?;
+
+        assert_eq!(fs::read_to_string(&copied)?, "setting = 1");
+        assert_eq!(fs::read_link(&linked)?, source.canonicalize()?);
+        assert!(fs::read_to_string(marker)?.contains("cp"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_through_escalation_command_without_staging() -> Result<()> {
+        let dir = TempDir::new("privileged")?;
+        let marker = dir.path().join("escalated");
+        let sudo = fake_sudo(
+            dir.path(),
+            &format!(
+                "#!/bin/sh\necho \"$@\" >> {}\nexec \"$@\"\n",
+                marker.display()
+            ),
+        )?;
+        let privileged = Privileged::new(&sudo.display().to_string());
+        let target = dir.path().join("etc").join("rendered.conf");
+
+        let change = privileged.write(b"token = hunter2\n", &target, false)?;
+
+        assert_eq!(change.outcome, Outcome::Created);
+        assert_eq!(fs::read_to_string(&target)?, "token = hunter2\n");
+        let escalated = fs::read_to_string(marker)?;
+        assert!(escalated.contains(&format!("tee {}", target.display())));
+        assert!(!escalated.contains("cp"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_fail_when_escalation_fails() -> Result<()> {
+        let dir = TempDir::new("privileged")?;
+        let sudo = fake_sudo(dir.path(), "#!/bin/sh\nexit 1\n")?;
+        let source = dir.path().join("source.conf");
+        fs::write(&source, "setting = 1")?;
+
+        let result = Privileged::new(&sudo.display().to_string()).copy(
+            &source,
+            &dir.path().join("target.conf"),
+            false,
+        );
+
+        assert!(format!("{:?}", result.unwrap_err()).contains("privilege escalation"));
+        let result = Privileged::new(&sudo.display().to_string()).write(
+            b"setting = 1",
+            &dir.path().join("target.conf"),
+            false,
+        );
+        assert!(format!("{:?}", result.unwrap_err()).contains("privilege escalation"));
+
+        Ok(())
+    }
+}
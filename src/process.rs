@@ -0,0 +1,159 @@
+use std::io::{self, ErrorKind, IsTerminal, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A shell to run a command line with, by appending it as an argument.
+pub fn os_shell() -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c");
+    cmd
+}
+
+/// Runs `command` to completion like `Command::output`, but kills it along
+/// with everything it started once `timeout` passes. Streams that aren't
+/// piped are left as configured and come back empty.
+///
+/// With stdin a terminal only the command itself is killed, see
+/// [`output_in`].
+pub fn output(command: &mut Command, timeout: Option<Duration>) -> io::Result<Output> {
+    output_in(command, timeout, !io::stdin().is_terminal())
+}
+
+/// [`output`], with the command put in a process group of its own when
+/// `detach` is set, so a timeout kills everything it started. Outside the
+/// terminal's foreground group an interactive command would be stopped by
+/// SIGTTIN or SIGTTOU as soon as it used the terminal.
+fn output_in(command: &mut Command, timeout: Option<Duration>, detach: bool) -> io::Result<Output> {
+    let Some(timeout) = timeout else {
+        let child = command.spawn()?;
+        return child.wait_with_output();
+    };
+
+    if detach {
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            match detach {
+                true => kill_group(&mut child)?,
+                false => {
+                    child.kill()?;
+                    child.wait()?;
+                }
+            }
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("command timed out after {timeout:?}"),
+            ));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let join = |reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>| {
+        reader.map_or(Ok(vec![]), |reader| {
+            reader
+                .join()
+                .map_err(|_| io::Error::other("reading command output panicked"))?
+        })
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Reads a stream to its end on another thread, so a chatty command can't
+/// block on a full pipe while it's being waited on.
+fn drain(mut stream: impl Read + Send + 'static) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    // SAFETY: kill has no memory safety preconditions; the child leads its
+    // own process group, so this can't reach ponto itself
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        child.kill()?;
+    }
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[test]
+    fn should_capture_output_within_timeout() -> io::Result<()> {
+        let output = output(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo hello")
+                .stdout(Stdio::piped()),
+            Some(Duration::from_secs(5)),
+        )?;
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_kill_command_after_timeout() {
+        let start = Instant::now();
+        let result = output(
+            Command::new("sh").arg("-c").arg("sleep 5; echo late"),
+            Some(Duration::from_millis(100)),
+        );
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn should_only_detach_when_asked() -> io::Result<()> {
+        // SAFETY: getpgrp has no preconditions
+        let own_group = unsafe { libc::getpgrp() }.to_string();
+        let group = |detach| {
+            let output = output_in(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg("cut -d' ' -f5 /proc/self/stat")
+                    .stdout(Stdio::piped()),
+                Some(Duration::from_secs(5)),
+                detach,
+            )?;
+            Ok::<_, io::Error>(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        };
+
+        assert_eq!(group(false)?, own_group);
+        assert_ne!(group(true)?, own_group);
+
+        let start = Instant::now();
+        let result = output_in(
+            Command::new("sh").arg("-c").arg("sleep 5"),
+            Some(Duration::from_millis(100)),
+            false,
+        );
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Created,
+    Updated,
+    Skipped,
+    /// Whatever was at the target was moved aside to deploy there
+    BackedUp,
+    Failed,
+    /// Deployed, then undone after the post hook failed
+    RolledBack,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Outcome::Created => "created",
+            Outcome::Updated => "updated",
+            Outcome::Skipped => "skipped",
+            Outcome::BackedUp => "backed up",
+            Outcome::Failed => "failed",
+            Outcome::RolledBack => "rolled back",
+        }
+        .fmt(f)
+    }
+}
+
+/// What a deploy primitive found at the target and what it did about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub state: String,
+    pub outcome: Outcome,
+    /// Where whatever was at the target was moved to
+    pub backup: Option<PathBuf>,
+    /// Why the target needs attention, also logged
+    pub warning: Option<String>,
+}
+
+impl Change {
+    pub fn new(state: impl ToString, outcome: Outcome) -> Self {
+        Self {
+            state: state.to_string(),
+            outcome,
+            backup: None,
+            warning: None,
+        }
+    }
+
+    /// Logs `warning` and keeps it for the report.
+    pub fn with_warning(self, warning: impl ToString) -> Self {
+        let warning = warning.to_string();
+        warn!("{warning}");
+        Self {
+            warning: Some(warning),
+            ..self
+        }
+    }
+
+    pub fn with_backup(self, backup: PathBuf) -> Self {
+        Self {
+            backup: Some(backup),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileAction {
+    pub package: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub state: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// How long deploying the target took
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+}
+
+fn as_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+impl FileAction {
+    pub fn new(package: &str, source: PathBuf, target: PathBuf, change: Change) -> Self {
+        Self {
+            package: package.to_owned(),
+            source,
+            target,
+            state: change.state,
+            outcome: change.outcome,
+            backup: change.backup,
+            warning: change.warning,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn with_duration(self, duration: Duration) -> Self {
+        Self { duration, ..self }
+    }
+}
+
+impl Display for FileAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{}: {:?} -> {:?} {} ({})",
+            self.package, self.source, self.target, self.outcome, self.state
+        )
+    }
+}
+
+/// How long deploying a package's files took.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageTiming {
+    pub package: String,
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+}
+
+/// Every action a deploy took, one per target file.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub actions: Vec<FileAction>,
+    /// Packages in the order they were deployed
+    pub packages: Vec<PackageTiming>,
+    /// How long deploying every package's files took
+    #[serde(rename = "total_ms", serialize_with = "as_millis")]
+    pub total: Duration,
+}
+
+impl Report {
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| action.outcome == outcome)
+            .count()
+    }
+
+    /// Every warning deploying a target raised, prefixed with its package.
+    pub fn warnings(&self) -> Vec<String> {
+        self.actions
+            .iter()
+            .filter_map(|action| {
+                let warning = action.warning.as_ref()?;
+                Some(format!("{}: {warning}", action.package))
+            })
+            .collect()
+    }
+
+    /// Lists the skipped targets grouped by why they were skipped, each group
+    /// sorted by target.
+    pub fn skipped(&self) -> String {
+        let mut reasons = BTreeMap::<_, Vec<_>>::new();
+        for action in &self.actions {
+            if action.outcome == Outcome::Skipped {
+                reasons.entry(&action.state).or_default().push(action);
+            }
+        }
+
+        let mut listing = String::new();
+        for (reason, mut actions) in reasons {
+            actions.sort_by(|a, b| a.target.cmp(&b.target));
+            listing.push_str(&format!("skipped, {reason}:\n"));
+            for action in actions {
+                listing.push_str(&format!(
+                    "  {}: {}\n",
+                    action.package,
+                    action.target.display()
+                ));
+            }
+        }
+        listing
+    }
+
+    /// Writes the report as JSON to `path`, or to stdout when it's `-`.
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context("serialize report")?;
+        if path == Path::new("-") {
+            println!("{json}");
+        } else {
+            fs::write(path, json).with_context(|| format!("write {path:?}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let counts = [
+            Outcome::Created,
+            Outcome::Updated,
+            Outcome::Skipped,
+            Outcome::BackedUp,
+            Outcome::Failed,
+        ]
+        .into_iter()
+        .map(|outcome| format!("{} {outcome}", self.count(outcome)))
+        .collect::<Vec<_>>();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_summarize_outcomes() {
+        let action = |outcome| {
+            FileAction::new(
+                "app",
+                "source".into(),
+                "target".into(),
+                Change::new("target missing", outcome),
+            )
+        };
+        let report = Report {
+            actions: vec![
+                action(Outcome::Created),
+                action(Outcome::Created),
+                action(Outcome::Skipped),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "2 created, 0 updated, 1 skipped, 0 backed up, 0 failed"
+        );
+    }
+
+    #[test]
+    fn should_list_skipped_targets_by_reason() {
+        let action = |target: &str, state, outcome| {
+            FileAction::new(
+                "app",
+                "source".into(),
+                target.into(),
+                Change::new(state, outcome),
+            )
+        };
+        let report = Report {
+            actions: vec![
+                action("b", "target points at source", Outcome::Skipped),
+                action("c", "target missing", Outcome::Created),
+                action("a", "target points at source", Outcome::Skipped),
+                action("d", "condition doesn't hold", Outcome::Skipped),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            report.skipped(),
+            "skipped, condition doesn't hold:\n  app: d\nskipped, target points at source:\n  app: a\n  app: b\n"
+        );
+    }
+}
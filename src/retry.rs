@@ -0,0 +1,87 @@
+use log::debug;
+use std::thread;
+use std::time::Duration;
+
+/// How often a failing command is retried before giving up. The delay
+/// doubles after each failed attempt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+    /// How long each attempt may run before it's killed, if at all
+    pub timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self {
+            retries,
+            delay,
+            timeout: None,
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Runs `attempt` until `succeeded` accepts its result or the retries are
+    /// exhausted, returning the last result. Errors are returned immediately.
+    pub fn run<T, E>(
+        &self,
+        mut attempt: impl FnMut() -> Result<T, E>,
+        succeeded: impl Fn(&T) -> bool,
+    ) -> Result<T, E> {
+        let mut delay = self.delay;
+        let mut result = attempt()?;
+        for retry in 1..=self.retries {
+            if succeeded(&result) {
+                break;
+            }
+            debug!(
+                "attempt failed, retrying in {delay:?} ({retry}/{})",
+                self.retries
+            );
+            thread::sleep(delay);
+            delay = delay.saturating_mul(2);
+            result = attempt()?;
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_not_retry_by_default() {
+        let mut attempts = 0;
+
+        let result: Result<_, ()> = RetryPolicy::default().run(
+            || {
+                attempts += 1;
+                Ok(attempts)
+            },
+            |_| false,
+        );
+
+        assert_eq!(result, Ok(1));
+    }
+
+    #[test]
+    fn should_retry_until_success() {
+        let mut attempts = 0;
+
+        let result: Result<_, ()> = RetryPolicy::new(5, Duration::ZERO).run(
+            || {
+                attempts += 1;
+                Ok(attempts)
+            },
+            |attempts| *attempts == 3,
+        );
+
+        assert_eq!(result, Ok(3));
+    }
+}
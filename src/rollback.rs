@@ -0,0 +1,116 @@
+use crate::fs_trace;
+use crate::report::{FileAction, Outcome, Report};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// What was at a target before deploying to it.
+enum Prior {
+    Missing,
+    File {
+        contents: Vec<u8>,
+        permissions: fs::Permissions,
+    },
+    Symlink(PathBuf),
+    /// Replaced by a link when forced
+    EmptyDirectory,
+    /// Directories and the like, which are only restored from a backup
+    Other,
+}
+
+/// Remembers what was at each target of a deploy so its changes can be
+/// undone afterwards.
+#[derive(Default)]
+pub struct Journal {
+    prior: HashMap<PathBuf, Prior>,
+}
+
+impl Journal {
+    /// Records what's at `path`, unless it was already recorded.
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        if self.prior.contains_key(path) {
+            return Ok(());
+        }
+        let prior = match fs::symlink_metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Prior::Missing,
+            Err(e) => return Err(e).with_context(|| format!("reading {path:?}")),
+            Ok(metadata) if metadata.is_symlink() => {
+                Prior::Symlink(fs::read_link(path).context("read link")?)
+            }
+            Ok(metadata) if metadata.is_file() => Prior::File {
+                contents: fs::read(path).with_context(|| format!("reading {path:?}"))?,
+                permissions: metadata.permissions(),
+            },
+            Ok(metadata) if metadata.is_dir() && fs::read_dir(path)?.next().is_none() => {
+                Prior::EmptyDirectory
+            }
+            Ok(_) => Prior::Other,
+        };
+        self.prior.insert(path.to_owned(), prior);
+
+        Ok(())
+    }
+
+    /// Reverts, last first, every target `report` shows was changed, carrying
+    /// on past the ones that can't be and reporting them at the end. The
+    /// reverted targets are marked as rolled back in `report`.
+    pub fn roll_back(&self, report: &mut Report) -> Result<()> {
+        let mut failures = vec![];
+        for action in report.actions.iter_mut().rev() {
+            if matches!(action.outcome, Outcome::Skipped | Outcome::Failed) {
+                continue;
+            }
+            match self.revert(action) {
+                Ok(()) => action.outcome = Outcome::RolledBack,
+                Err(e) => {
+                    warn!("{e:#}");
+                    failures.push(format!("  {e:#}"));
+                }
+            }
+        }
+        anyhow::ensure!(
+            failures.is_empty(),
+            "{} target(s) couldn't be rolled back:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+
+        Ok(())
+    }
+
+    fn revert(&self, action: &FileAction) -> Result<()> {
+        let to = &action.target;
+        let prior = self
+            .prior
+            .get(to)
+            .with_context(|| format!("{to:?} wasn't recorded before deploying it"))?;
+        info!("rolling back {to:?}");
+        let context = || format!("rolling back {to:?}");
+        if to.is_symlink() || to.is_file() {
+            fs_trace::remove_file(to).with_context(context)?;
+        }
+        if let Some(backup) = &action.backup {
+            return fs_trace::rename(backup, to).with_context(context);
+        }
+        match prior {
+            Prior::Missing => {}
+            Prior::File {
+                contents,
+                permissions,
+            } => {
+                fs_trace::write(to, contents).with_context(context)?;
+                fs::set_permissions(to, permissions.clone()).with_context(context)?;
+            }
+            Prior::Symlink(link) => {
+                fs_trace::symlink(link, to).with_context(context)?;
+            }
+            Prior::EmptyDirectory => fs::create_dir(to).with_context(context)?,
+            Prior::Other => anyhow::bail!("{to:?} wasn't a file, can't restore it"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use serde_json::{json, Value};
+
+/// JSON Schema describing the config file, for editor completion and
+/// validation. Keep it in sync with the types in `config.rs`.
+pub fn config_schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "ponto configuration",
+        "type": "object",
+        "properties": {
+            "variables": { "$ref": "#/definitions/Variables" },
+            "files": {
+                "description": "Files that belong to no package, deployed before every package",
+                "$ref": "#/definitions/Package/properties/files"
+            },
+            "secret_variables": {
+                "description": "Variables that are never logged nor passed on to hooks",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "template_extension": {
+                "description": "Detect templates by this extension and strip it from targets",
+                "type": "string"
+            },
+            "partials_dir": {
+                "description": "Directory whose files are registered as partials named after their file stem",
+                "type": "string"
+            },
+            "helpers": {
+                "description": "Handlebars helpers run as scripts, by name, given the helper's parameters as arguments. Needs --allow-command-helpers",
+                "type": "object",
+                "additionalProperties": { "type": "string" }
+            },
+            "defaults": {
+                "description": "Options for every deploy of this config, overridden by the command line",
+                "type": "object",
+                "properties": {
+                    "conflict_policy": {
+                        "description": "Skip targets in the way, or back them up like --force",
+                        "enum": ["skip", "backup"]
+                    },
+                    "force": { "type": "boolean" }
+                },
+                "additionalProperties": false
+            }
+        },
+        "additionalProperties": { "$ref": "#/definitions/Package" },
+        "definitions": {
+            "Variables": {
+                "description": "Template variables. A --data file is overridden by them, and they by PONTO_VAR_<KEY> environment variables and --set",
+                "type": "object"
+            },
+            "Package": {
+                "type": "object",
+                "properties": {
+                    "root": {
+                        "description": "Directory relative sources of this package are resolved against",
+                        "type": "string"
+                    },
+                    "depends": {
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "after": {
+                        "description": "Packages to deploy before this one when present",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "before": {
+                        "description": "Packages to deploy after this one when present",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "files": {
+                        "description": "Sources mapped to targets, or a list of entries to deploy in order",
+                        "anyOf": [
+                            {
+                                "type": "object",
+                                "additionalProperties": { "$ref": "#/definitions/FileTarget" }
+                            },
+                            {
+                                "type": "array",
+                                "items": { "$ref": "#/definitions/FileEntry" }
+                            }
+                        ]
+                    },
+                    "variables": { "$ref": "#/definitions/Variables" }
+                },
+                "additionalProperties": false
+            },
+            "FileTarget": {
+                "anyOf": [
+                    { "type": "string" },
+                    { "$ref": "#/definitions/TargetSpec" }
+                ]
+            },
+            "FileEntry": {
+                "anyOf": [
+                    {
+                        "type": "object",
+                        "properties": {
+                            "from": { "type": "string" },
+                            "to": { "type": "string" }
+                        },
+                        "required": ["from", "to"],
+                        "additionalProperties": false
+                    },
+                    {
+                        "allOf": [
+                            { "$ref": "#/definitions/TargetSpec" },
+                            { "required": ["from"] }
+                        ]
+                    }
+                ]
+            },
+            "TargetSpec": {
+                "type": "object",
+                "properties": {
+                    "to": {
+                        "anyOf": [
+                            { "type": "string" },
+                            { "type": "array", "items": { "type": "string" } }
+                        ]
+                    },
+                    "symlink": { "type": "boolean" },
+                    "strict": {
+                        "description": "Overrides handlebars strict mode for this template",
+                        "type": "boolean"
+                    },
+                    "link_contents": {
+                        "description": "Symlink each file of a source directory instead of the directory",
+                        "type": "boolean"
+                    },
+                    "sudo": {
+                        "description": "Deploy through the privilege escalation command",
+                        "type": "boolean"
+                    },
+                    "binary_template": {
+                        "description": "Render a template that contains bytes which aren't valid UTF-8",
+                        "type": "boolean"
+                    },
+                    "read_only": {
+                        "description": "Clear the write bits of copied and rendered targets",
+                        "type": "boolean"
+                    },
+                    "owner": {
+                        "description": "User, by name or id, to own copied and rendered targets",
+                        "type": "string"
+                    },
+                    "group": {
+                        "description": "Group, by name or id, to own copied and rendered targets",
+                        "type": "string"
+                    },
+                    "when": {
+                        "description": "Condition, rendered as a template, that must hold to deploy the target",
+                        "type": "string"
+                    },
+                    "extract": {
+                        "description": "Extract a tar archive, gzipped or not, into the target directory",
+                        "type": "boolean"
+                    },
+                    "after": {
+                        "description": "Targets of other files of the package to deploy before this one",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "max_depth": {
+                        "description": "How many directory levels linking a directory's contents descends, 1 being only the files right in it",
+                        "type": "integer",
+                        "minimum": 1
+                    },
+                    "hardlink": {
+                        "description": "Hard link the target to the source, which have to be on the same filesystem",
+                        "type": "boolean"
+                    },
+                    "encrypted": {
+                        "description": "Decrypt the source with age into a target only its owner can read, as sources ending in .age are",
+                        "type": "boolean"
+                    },
+                    "on_change": {
+                        "description": "Shell command run after the target's contents change",
+                        "type": "string"
+                    },
+                    "ignore": {
+                        "description": "Globs of files left out when a directory's contents are deployed, on top of .gitkeep, .gitignore and .git",
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "no_default_ignore": {
+                        "description": "Deploy the .gitkeep, .gitignore and .git files left out by default",
+                        "type": "boolean"
+                    },
+                    "template_names": {
+                        "description": "Render the names of a directory's files as templates when linking its contents; needs link_contents",
+                        "type": "boolean"
+                    }
+                },
+                "required": ["to", "symlink"]
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Package, TargetSpec};
+    use std::collections::BTreeSet;
+
+    fn properties(definition: &str) -> BTreeSet<String> {
+        config_schema()["definitions"][definition]["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn fields(value: impl serde::Serialize) -> BTreeSet<String> {
+        serde_json::to_value(value)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn should_describe_every_package_field() {
+        assert_eq!(properties("Package"), fields(Package::default()));
+    }
+
+    #[test]
+    fn should_describe_every_target_spec_field() {
+        let spec = TargetSpec {
+            to: "target".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(properties("TargetSpec"), fields(spec));
+    }
+}
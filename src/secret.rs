@@ -0,0 +1,188 @@
+use crate::filesystem::Filesystem;
+use crate::fs_trace;
+use crate::process;
+use crate::report::{Change, Outcome};
+use anyhow::{Context, Result};
+use log::trace;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Extension marking a source as encrypted with age.
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+/// Environment variable naming the identity file when `--identity` isn't given.
+pub const IDENTITY_VAR: &str = "PONTO_AGE_IDENTITY";
+
+/// Mode decrypted targets are written with, whatever the umask.
+const SECRET_MODE: u32 = 0o600;
+
+/// Decrypts age encrypted sources with an external `age` compatible command.
+pub struct Decrypter {
+    pub command: String,
+    pub identity: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+}
+
+impl Decrypter {
+    /// Decrypts `from` into memory, so the plaintext only ever lands in the
+    /// target.
+    pub fn decrypt(&self, from: &Path) -> Result<Vec<u8>> {
+        let identity = self.identity.as_ref().with_context(|| {
+            format!("decrypting {from:?} needs an identity, pass --identity or set {IDENTITY_VAR}")
+        })?;
+        let mut command = Command::new(&self.command);
+        command
+            .arg("--decrypt")
+            .arg("--identity")
+            .arg(identity)
+            .arg(from)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = process::output(&mut command, self.timeout)
+            .with_context(|| format!("running {:?} to decrypt {from:?}", self.command))?;
+        anyhow::ensure!(
+            output.status.success(),
+            "decrypting {from:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+
+        Ok(output.stdout)
+    }
+
+    /// Writes `plaintext`, decrypted with [`Decrypter::decrypt`], into `to`,
+    /// readable only by its owner. A target that differs is only overwritten
+    /// when `force` is set.
+    pub fn deploy(plaintext: &[u8], to: &Path, force: bool) -> Result<Change> {
+        anyhow::ensure!(to.is_symlink() || !to.is_dir(), "{to:?} is a directory");
+        let exists = to.exists() || to.is_symlink();
+        let differs = "target differs from decrypted source";
+        let change = match fs::read(to) {
+            _ if !exists => Change::new("target missing", Outcome::Created),
+            Ok(current) if current == plaintext && !to.is_symlink() => {
+                trace!("{to:?} equals the decrypted source");
+                return Ok(Change::new(
+                    "target equals decrypted source",
+                    Outcome::Skipped,
+                ));
+            }
+            _ if force => Change::new(differs, Outcome::Updated),
+            _ => {
+                return Ok(Change::new(differs, Outcome::Skipped).with_warning(format!(
+                    "{to:?} differs from the decrypted source, use --force to overwrite it"
+                )));
+            }
+        };
+
+        fs_trace::create_dir_all(to.parent().unwrap()).context("create dir all")?;
+        if to.is_symlink() {
+            fs_trace::remove_file(to).context("remove symlink")?;
+        } else if exists {
+            Filesystem::set_read_only(to, false)?;
+            // tighten before writing, an existing file may be readable by others
+            fs::set_permissions(to, fs::Permissions::from_mode(SECRET_MODE))
+                .context("set permissions")?;
+        }
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(SECRET_MODE)
+            .open(to)
+            .and_then(|mut file| file.write_all(plaintext));
+        fs_trace::record("write", None, to, &written);
+        written.context("write decrypted file")?;
+
+        Ok(change)
+    }
+}
+
+/// Whether `path` is marked as encrypted by its extension.
+pub fn has_encrypted_extension(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == ENCRYPTED_EXTENSION)
+}
+
+/// `path` without its `.age` extension, if it has one.
+pub fn strip_encrypted_extension(path: &Path) -> PathBuf {
+    match has_encrypted_extension(path) {
+        true => path.with_extension(""),
+        false => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// Writes a stand-in for `age` that checks it's called the way age is and
+    /// "decrypts" by rot13, since age itself isn't around in tests.
+    fn fake_age(dir: &Path) -> Result<PathBuf> {
+        let script = dir.join("age");
+        fs::write(
+            &script,
+            "#!/bin/sh\n[ \"$1\" = --decrypt ] && [ \"$2\" = --identity ] && [ -f \"$3\" ] || exit 1\ntr 'a-zA-Z' 'n-za-mN-ZA-M' < \"$4\"\n",
+        )?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        Ok(script)
+    }
+
+    #[test]
+    fn should_decrypt_into_private_target() -> Result<()> {
+        let dir = TempDir::new("secret")?;
+        let identity = dir.path().join("key.txt");
+        fs::write(&identity, "AGE-SECRET-KEY-1")?;
+        let source = dir.path().join("token.age");
+        fs::write(&source, "uhagre2\n")?;
+        let target = dir.path().join("out/token");
+        let decrypter = Decrypter {
+            command: fake_age(dir.path())?.display().to_string(),
+            identity: Some(identity),
+            timeout: None,
+        };
+
+        let change = Decrypter::deploy(&decrypter.decrypt(&source)?, &target, false)?;
+
+        assert_eq!(change.outcome, Outcome::Created);
+        assert_eq!(fs::read_to_string(&target)?, "hunter2\n");
+        assert_eq!(target.metadata()?.permissions().mode() & 0o777, 0o600);
+        let change = Decrypter::deploy(&decrypter.decrypt(&source)?, &target, false)?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+
+        fs::write(&target, "stale")?;
+        assert_eq!(
+            Decrypter::deploy(&decrypter.decrypt(&source)?, &target, false)?.outcome,
+            Outcome::Skipped
+        );
+        assert_eq!(
+            Decrypter::deploy(&decrypter.decrypt(&source)?, &target, true)?.outcome,
+            Outcome::Updated
+        );
+        assert_eq!(fs::read_to_string(&target)?, "hunter2\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_need_an_identity() -> Result<()> {
+        let dir = TempDir::new("secret")?;
+        let source = dir.path().join("token.age");
+        fs::write(&source, "uhagre2")?;
+        let decrypter = Decrypter {
+            command: fake_age(dir.path())?.display().to_string(),
+            identity: None,
+            timeout: None,
+        };
+
+        let error = decrypter.decrypt(&source).unwrap_err();
+
+        assert!(error.to_string().contains(IDENTITY_VAR));
+        Ok(())
+    }
+}
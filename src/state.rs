@@ -0,0 +1,91 @@
+use crate::config::Configuration;
+use crate::filesystem::Filesystem;
+use crate::options::Options;
+use crate::symlink::normalize;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Hashes everything a deploy depends on: the resolved packages and
+/// variables, and when each source, hook and partial was last modified.
+/// Targets aren't looked at, so changes made to them directly go unnoticed.
+pub fn plan_hash(config: &Configuration, opts: &Options) -> Result<String> {
+    // serde_json sorts object keys, so maps hash the same whatever their order
+    let resolved = serde_json::json!({
+        "packages": config.packages,
+        "variables": config.variables,
+        "template_extension": config.template_extension,
+        "partials_dir": config.partials_dir,
+        "force": opts.force,
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(resolved.to_string());
+
+    let mut sources = config
+        .packages
+        .values()
+        .flat_map(|package| package.files.keys().cloned())
+        .chain([opts.pre_hook(), opts.post_hook()])
+        .chain(config.partials_dir.clone())
+        .collect::<Vec<_>>();
+    sources.sort();
+    sources.dedup();
+    for source in sources {
+        for path in with_contents(&source)? {
+            hasher.update(format!("{path:?}: {}\n", modified(&path)));
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Where the plan hash of the last deploy of `configs` is kept, one file per
+/// set of configs and home below `state_dir`.
+pub fn hash_file(state_dir: &Path, configs: &[PathBuf], home: Option<&Path>) -> Result<PathBuf> {
+    let mut hasher = Sha256::new();
+    for config in configs {
+        hasher.update(normalize(config)?.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+    }
+    if let Some(home) = home {
+        hasher.update(home.as_os_str().as_encoded_bytes());
+    }
+    let key = format!("{:x}", hasher.finalize());
+    Ok(state_dir.join(format!("plan-{}", &key[..16])))
+}
+
+/// The plan hash stored by the last deploy, if any.
+pub fn stored_hash(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|hash| hash.trim().to_owned())
+}
+
+pub fn store_hash(path: &Path, hash: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating state directory {dir:?}"))?;
+    }
+    fs::write(path, hash).with_context(|| format!("storing plan hash in {path:?}"))
+}
+
+/// `path`, followed by the files below it when it's a directory.
+fn with_contents(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![path.to_owned()];
+    if path.is_dir() {
+        let files = Filesystem::files_in(path, &[]).with_context(|| format!("listing {path:?}"))?;
+        paths.extend(files.into_iter().map(|file| path.join(file)));
+    }
+    Ok(paths)
+}
+
+/// When `path` was last modified in nanoseconds since the epoch, 0 when it's
+/// missing.
+fn modified(path: &Path) -> u128 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos())
+}
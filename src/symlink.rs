@@ -1,47 +1,78 @@
 use super::file_type::FileType;
 use crate::filesystem::FilesystemExt;
+use crate::fs_trace;
+use crate::report::{Change, Outcome};
 use anyhow::{Context, Result};
-use log::trace;
-use std::{fmt::Display, fs, path::Path};
+use log::{info, trace};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 pub struct Symlink;
 
 impl Symlink {
-    pub fn create(from: &Path, to: &Path, force: bool) -> Result<()> {
-        let result = SymlinkState::from(from, FileType::try_from(from)?, FileType::try_from(to)?)
-            .context("get symlink state")?;
+    /// Links `to` to `from`, resolving symlinks in the source path unless
+    /// `canonicalize` is off, in which case it's only made absolute.
+    pub fn create(from: &Path, to: &Path, force: bool, canonicalize: bool) -> Result<Change> {
+        let result = SymlinkState::from(
+            from,
+            FileType::try_from(from)?,
+            to,
+            FileType::try_from(to)?,
+            canonicalize,
+        )
+        .context("get symlink state")?;
         trace!("{result}");
 
         // TODO warn if source is missing
-        let should_continue = match result {
-            SymlinkState::Changed
-            | SymlinkState::BothMissing
-            | SymlinkState::OnlyTargetExists
-            | SymlinkState::TargetNotSymlink => false,
-            SymlinkState::OnlySourceExists => true,
+        let outcome = match result {
+            SymlinkState::Changed | SymlinkState::BothMissing | SymlinkState::OnlyTargetExists => {
+                Outcome::Skipped
+            }
+            SymlinkState::TargetNotSymlink if force => Outcome::BackedUp,
+            SymlinkState::TargetNotSymlink => Outcome::Skipped,
+            SymlinkState::TargetEmptyDirectory if force => Outcome::Updated,
+            SymlinkState::TargetEmptyDirectory => Outcome::Skipped,
+            SymlinkState::OnlySourceExists => Outcome::Created,
             SymlinkState::Identical if force => {
                 trace!("forcing symlink creation");
-                true
+                Outcome::Updated
             }
-            SymlinkState::Identical => false,
+            SymlinkState::Identical => Outcome::Skipped,
         };
 
-        if should_continue {
-            fs::create_dir_all(to.parent().unwrap()).context("create dir all")?;
-            if force && to.exists() {
+        let mut backup = None;
+        if outcome == Outcome::BackedUp {
+            let path = backup_path(to);
+            info!("moving {to:?} to {path:?} to link it to {from:?}");
+            fs_trace::rename(to, &path).context("back up target")?;
+            backup = Some(path);
+        }
+        if outcome != Outcome::Skipped {
+            if let SymlinkState::TargetEmptyDirectory = result {
+                trace!("removing empty directory in place of the link");
+                fs_trace::remove_dir(to).context("remove empty directory")?;
+            }
+            fs_trace::create_dir_all(to.parent().unwrap()).context("create dir all")?;
+            if force && to.is_symlink() {
                 trace!("removing existing symlink");
-                fs::remove_file(to).context("remove file")?;
+                fs_trace::remove_file(to).context("remove file")?;
             }
-            std::os::unix::fs::symlink(
-                from.to_path_buf()
-                    .real_path()
-                    .context("get real path of source file")?,
-                to,
-            )
-            .context("create symlink")?;
+            fs_trace::symlink(&link_source(from, canonicalize)?, to).context("create symlink")?;
         }
 
-        Ok(())
+        let kept_directory =
+            matches!(result, SymlinkState::TargetEmptyDirectory) && outcome == Outcome::Skipped;
+        let mut change = Change::new(result, outcome);
+        if kept_directory {
+            change = change.with_warning(format!(
+                "{to:?} is an empty directory, use --force to replace it with a link"
+            ));
+        }
+        Ok(match backup {
+            Some(backup) => change.with_backup(backup),
+            None => change,
+        })
     }
 }
 
@@ -50,6 +81,8 @@ pub enum SymlinkState {
     OnlySourceExists,
     OnlyTargetExists,
     TargetNotSymlink,
+    /// e.g. left behind by linking a directory's contents before
+    TargetEmptyDirectory,
     Changed,
     BothMissing,
 }
@@ -58,28 +91,91 @@ impl SymlinkState {
     pub fn from(
         source_path: &Path,
         source_type: FileType,
+        link_path: &Path,
         link_type: FileType,
+        canonicalize: bool,
     ) -> Result<SymlinkState> {
         Ok(match (source_type, link_type) {
             (FileType::Missing, FileType::SymbolicLink(_)) => SymlinkState::OnlyTargetExists,
             (_, FileType::SymbolicLink(t)) => {
-                if t == source_path
-                    .to_path_buf()
-                    .real_path()
-                    .context("get real path of source")?
-                {
+                // relative links are relative to the directory holding them
+                let t = link_path
+                    .parent()
+                    .map_or(t.clone(), |parent| parent.join(&t));
+                let t = if canonicalize {
+                    t.real_path().unwrap_or(t)
+                } else {
+                    normalize(&t)?
+                };
+                if t == link_source(source_path, canonicalize)? {
                     SymlinkState::Identical
                 } else {
                     SymlinkState::Changed
                 }
             }
             (FileType::Missing, FileType::Missing) => SymlinkState::BothMissing,
+            (source, FileType::Directory)
+                if source != FileType::Missing && is_empty_dir(link_path)? =>
+            {
+                SymlinkState::TargetEmptyDirectory
+            }
+            // sources may be regular files or whole directories
             (_, FileType::Missing) => SymlinkState::OnlySourceExists,
             _ => SymlinkState::TargetNotSymlink,
         })
     }
 }
 
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    Ok(fs::read_dir(path)
+        .context("read target directory")?
+        .next()
+        .is_none())
+}
+
+/// A sibling of `path` nothing exists at yet, to move it out of the way.
+fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    (0..)
+        .map(|n| match n {
+            0 => path.with_file_name(format!("{name}.ponto.bak")),
+            n => path.with_file_name(format!("{name}.ponto.bak.{n}")),
+        })
+        .find(|backup| !backup.exists() && !backup.is_symlink())
+        .unwrap()
+}
+
+/// The path a link to `source` points at.
+pub fn link_source(source: &Path, canonicalize: bool) -> Result<PathBuf> {
+    if canonicalize {
+        source
+            .to_path_buf()
+            .real_path()
+            .context("get real path of source file")
+    } else {
+        normalize(source)
+    }
+}
+
+/// Makes `path` absolute and drops `.` and `..` components without touching
+/// the filesystem, so symlinks along the way are kept.
+pub fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)
+        .context("make path absolute")?
+        .components()
+    {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
 impl Display for SymlinkState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
@@ -87,6 +183,7 @@ impl Display for SymlinkState {
             SymlinkState::OnlySourceExists => "target missing",
             SymlinkState::OnlyTargetExists => "source is missing",
             SymlinkState::TargetNotSymlink => "target already exists and isn't a symlink",
+            SymlinkState::TargetEmptyDirectory => "target is an empty directory",
             SymlinkState::Changed => "target already exists and doesn't point at source",
             SymlinkState::BothMissing => "source and target are missing",
         }
@@ -112,7 +209,7 @@ mod tests {
 
         let link_path = dir.path().join("link.txt");
 
-        Symlink::create(&source_path, &link_path, false)?;
+        Symlink::create(&source_path, &link_path, false, true)?;
 
         assert!(link_path.exists());
         assert_eq!(
@@ -127,4 +224,165 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn should_detect_missing_target_for_directory_source() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let source_path = dir.path().join("source");
+        fs::create_dir(&source_path)?;
+        let link_path = dir.path().join("missing").join("link");
+
+        let state = SymlinkState::from(
+            &source_path,
+            FileType::try_from(source_path.as_path())?,
+            &link_path,
+            FileType::try_from(link_path.as_path())?,
+            true,
+        )?;
+
+        assert!(matches!(state, SymlinkState::OnlySourceExists));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_create_directory_symlink() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let source_path = dir.path().join("source");
+        fs::create_dir(&source_path)?;
+        File::create(source_path.join("file.txt"))?.write_all(b"Hello, world!")?;
+
+        let link_path = dir.path().join("missing").join("link");
+
+        Symlink::create(&source_path, &link_path, false, true)?;
+
+        assert!(link_path.is_dir());
+        assert_eq!(
+            link_path.read_link()?,
+            source_path.real_path().context("get real path")?
+        );
+        assert_eq!(
+            fs::read_to_string(link_path.join("file.txt"))?,
+            "Hello, world!"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_relative_symlink_as_identical() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let source_path = dir.path().join("repo").join("source.txt");
+        fs::create_dir_all(source_path.parent().unwrap())?;
+        File::create(&source_path)?.write_all(b"Hello, world!")?;
+        let link_path = dir.path().join("home").join("link.txt");
+        fs::create_dir_all(link_path.parent().unwrap())?;
+        std::os::unix::fs::symlink("../repo/source.txt", &link_path)?;
+
+        let state = SymlinkState::from(
+            &source_path,
+            FileType::try_from(source_path.as_path())?,
+            &link_path,
+            FileType::try_from(link_path.as_path())?,
+            true,
+        )?;
+        assert!(matches!(state, SymlinkState::Identical));
+
+        let change = Symlink::create(&source_path, &link_path, false, true)?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+        assert_eq!(link_path.read_link()?, Path::new("../repo/source.txt"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_symlinked_source_path_when_not_canonicalizing() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let repo = dir.path().join("repo");
+        fs::create_dir(&repo)?;
+        File::create(repo.join("source.txt"))?.write_all(b"Hello, world!")?;
+        let via = dir.path().join("via");
+        std::os::unix::fs::symlink(&repo, &via)?;
+        let source_path = via.join("source.txt");
+        let link_path = dir.path().join("link.txt");
+
+        Symlink::create(&source_path, &link_path, false, false)?;
+        assert_eq!(link_path.read_link()?, source_path);
+
+        let state = |canonicalize| {
+            SymlinkState::from(
+                &source_path,
+                FileType::try_from(source_path.as_path())?,
+                &link_path,
+                FileType::try_from(link_path.as_path())?,
+                canonicalize,
+            )
+        };
+        assert!(matches!(state(false)?, SymlinkState::Identical));
+        assert!(matches!(state(true)?, SymlinkState::Identical));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_replace_directory_with_directory_symlink() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let source_path = dir.path().join("source");
+        fs::create_dir(&source_path)?;
+        fs::write(source_path.join("config"), "Hello, world!")?;
+        let link_path = dir.path().join("link");
+        fs::create_dir(&link_path)?;
+
+        let change = Symlink::create(&source_path, &link_path, false, true)?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+        assert!(link_path.is_dir() && !link_path.is_symlink());
+
+        let change = Symlink::create(&source_path, &link_path, true, true)?;
+        assert_eq!(change.outcome, Outcome::Updated);
+        assert_eq!(link_path.read_link()?, source_path.real_path()?);
+
+        // a directory with contents is moved aside instead
+        fs::remove_file(&link_path)?;
+        fs::create_dir(&link_path)?;
+        fs::write(link_path.join("local"), "local edits")?;
+        let change = Symlink::create(&source_path, &link_path, true, true)?;
+        assert_eq!(change.outcome, Outcome::BackedUp);
+        assert_eq!(link_path.read_link()?, source_path.real_path()?);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("link.ponto.bak").join("local"))?,
+            "local edits"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_back_up_regular_file_when_forced() -> Result<()> {
+        let dir = TempDir::new("symlink")?;
+
+        let source_path = dir.path().join("source.txt");
+        fs::write(&source_path, "Hello, world!")?;
+        let link_path = dir.path().join("link.txt");
+        fs::write(&link_path, "local edits")?;
+
+        let change = Symlink::create(&source_path, &link_path, false, true)?;
+        assert_eq!(change.outcome, Outcome::Skipped);
+        assert!(!link_path.is_symlink());
+
+        let change = Symlink::create(&source_path, &link_path, true, true)?;
+        assert_eq!(change.outcome, Outcome::BackedUp);
+        assert_eq!(change.backup, Some(dir.path().join("link.txt.ponto.bak")));
+        assert_eq!(link_path.read_link()?, source_path.real_path()?);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("link.txt.ponto.bak"))?,
+            "local edits"
+        );
+
+        Ok(())
+    }
 }
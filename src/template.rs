@@ -1,52 +1,175 @@
-use crate::{config::Variables, file_type::FileType};
+use crate::config::Variables;
+use crate::file_type::FileType;
+use crate::filesystem::Filesystem;
+use crate::fs_trace;
+use crate::report::{Change, Outcome};
 use anyhow::{Context, Result};
 use handlebars::Handlebars;
 use log::trace;
+use std::borrow::Cow;
 use std::fmt::Display;
-use std::fs::{self, File};
-use std::io::Write;
+use std::fs;
 use std::path::Path;
 
 pub struct Template;
 
 impl Template {
+    /// Writes what `render` produces for `from` into `to`, only calling it
+    /// when the target is to be written.
     pub fn render(
         from: &Path,
         to: &Path,
-        handlebars: &Handlebars<'_>,
-        variables: &Variables,
         force: bool,
-    ) -> Result<()> {
-        let template_type = TemplateState::from(FileType::try_from(from)?, FileType::try_from(to)?);
+        normalize_eol: bool,
+        render: impl FnOnce() -> Result<String>,
+    ) -> Result<Change> {
+        let template_type = TemplateState::from(
+            FileType::try_from(from)?,
+            FileType::try_from(to)?,
+            normalize_eol,
+        );
         trace!("{template_type}");
 
-        let should_continue = match template_type {
-            TemplateState::TargetNotRegularFile | TemplateState::BothMissing => false,
-            TemplateState::OnlySourceExists | TemplateState::Changed => true,
+        let outcome = match template_type {
+            TemplateState::TargetNotRegularFile | TemplateState::BothMissing => Outcome::Skipped,
+            TemplateState::OnlySourceExists => Outcome::Created,
+            TemplateState::Changed => Outcome::Updated,
             TemplateState::Identical if force => {
                 trace!("forcing template rendering");
-                true
+                Outcome::Updated
             }
-            TemplateState::Identical => false,
+            TemplateState::Identical => Outcome::Skipped,
+            TemplateState::TargetIsSymlink if force => Outcome::Updated,
+            TemplateState::TargetIsSymlink => Outcome::Skipped,
         };
+        let warning =
+            matches!(template_type, TemplateState::TargetIsSymlink if !force).then(|| {
+                format!("{to:?} is a symlink, use --force to replace it with the rendered template")
+            });
 
-        if should_continue {
+        if outcome != Outcome::Skipped {
+            if let TemplateState::TargetIsSymlink = template_type {
+                trace!("removing symlink in place of the template");
+                fs_trace::remove_file(to).context("remove symlink")?;
+            } else if to.exists() {
+                Filesystem::set_read_only(to, false)?;
+            }
             if force && to.exists() {
                 trace!("removing existing file");
-                fs::remove_file(to).context("remove file")?;
+                fs_trace::remove_file(to).context("remove file")?;
             }
 
-            let content = fs::read_to_string(from).context("read to string")?;
-            let rendered = handlebars
-                .render_template(&content, variables)
-                .context("render template")?;
+            let rendered = render()?;
 
-            fs::create_dir_all(to.parent().unwrap()).context("create dir all")?;
-            let mut file = File::create(to).context("create file")?;
-            file.write_all(rendered.as_bytes()).context("write all")?;
+            fs_trace::create_dir_all(to.parent().unwrap()).context("create dir all")?;
+            fs_trace::write(to, rendered).context("write rendered template")?;
         }
 
-        Ok(())
+        let change = Change::new(template_type, outcome);
+        Ok(match warning {
+            Some(warning) => change.with_warning(warning),
+            None => change,
+        })
+    }
+
+    /// Renders the file, first dropping newlines after block tags when
+    /// `trim_blocks` is set (see `handlebars::trim_blocks`).
+    pub fn render_to_string(
+        from: &Path,
+        handlebars: &Handlebars<'_>,
+        variables: &Variables,
+        trim_blocks: bool,
+    ) -> Result<String> {
+        let mut content = fs::read_to_string(from).context("read to string")?;
+        if trim_blocks {
+            content = crate::handlebars::trim_blocks(&content);
+        }
+        handlebars
+            .render_template(&content, variables)
+            .context("render template")
+    }
+
+    /// Writes what `render` produces, see [`Template::render_bytes_to_vec`],
+    /// into `to`. The target is only written when its bytes differ.
+    pub fn render_bytes(
+        to: &Path,
+        force: bool,
+        render: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Change> {
+        let rendered = render()?;
+        if to.is_symlink() {
+            if !force {
+                return Ok(
+                    Change::new(TemplateState::TargetIsSymlink, Outcome::Skipped).with_warning(
+                        format!(
+                        "{to:?} is a symlink, use --force to replace it with the rendered template"
+                    ),
+                    ),
+                );
+            }
+            fs_trace::remove_file(to).context("remove symlink")?;
+        }
+        let current = fs::read(to).ok();
+        let change = match &current {
+            None => Change::new("target missing", Outcome::Created),
+            Some(current) if *current != rendered => {
+                Change::new("rendered bytes differ from the target", Outcome::Updated)
+            }
+            Some(_) if force => Change::new("rendered bytes equal the target", Outcome::Updated),
+            Some(_) => {
+                trace!("rendered bytes are equal to the target");
+                return Ok(Change::new(
+                    "rendered bytes equal the target",
+                    Outcome::Skipped,
+                ));
+            }
+        };
+
+        fs_trace::create_dir_all(to.parent().unwrap()).context("create dir all")?;
+        if to.exists() {
+            Filesystem::set_read_only(to, false)?;
+        }
+        fs_trace::write(to, rendered).context("write rendered bytes")?;
+
+        Ok(change)
+    }
+
+    /// Splits the file into runs of valid UTF-8 and renders each one on its
+    /// own, so a placeholder or block can't span a non-UTF-8 byte.
+    pub fn render_bytes_to_vec(
+        from: &Path,
+        handlebars: &Handlebars<'_>,
+        variables: &Variables,
+        trim_blocks: bool,
+    ) -> Result<Vec<u8>> {
+        let content = fs::read(from).context("read")?;
+        let mut rendered = Vec::with_capacity(content.len());
+        let mut rest = content.as_slice();
+        while !rest.is_empty() {
+            let (text, invalid) = match std::str::from_utf8(rest) {
+                Ok(text) => (text, 0),
+                Err(e) => (
+                    // checked by from_utf8 above
+                    std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap(),
+                    e.error_len().unwrap_or(rest.len() - e.valid_up_to()),
+                ),
+            };
+            if !text.is_empty() {
+                let text = match trim_blocks {
+                    true => crate::handlebars::trim_blocks(text),
+                    false => text.to_owned(),
+                };
+                let text = handlebars
+                    .render_template(&text, variables)
+                    .context("render template")?;
+                rendered.extend_from_slice(text.as_bytes());
+            }
+            let consumed = rest.len().min(text.len() + invalid);
+            rendered.extend_from_slice(&rest[text.len()..consumed]);
+            rest = &rest[consumed..];
+        }
+
+        Ok(rendered)
     }
 }
 
@@ -54,33 +177,51 @@ pub enum TemplateState {
     Identical,
     OnlySourceExists,
     Changed,
+    TargetIsSymlink,
     TargetNotRegularFile,
     BothMissing,
 }
 
 impl TemplateState {
-    pub fn from(source_type: FileType, templated: FileType) -> TemplateState {
+    /// Compares the source with the templated file. With `normalize_eol`,
+    /// contents that only differ in CRLF and LF line endings are identical.
+    pub fn from(source_type: FileType, templated: FileType, normalize_eol: bool) -> TemplateState {
         match (source_type, templated) {
             (FileType::File(t), FileType::File(c)) => {
-                if t == c {
+                let same_lines = || match (&t, &c) {
+                    (Some(t), Some(c)) => normalize_eols(t) == normalize_eols(c),
+                    _ => false,
+                };
+                if t == c || normalize_eol && same_lines() {
                     TemplateState::Identical
                 } else {
                     TemplateState::Changed
                 }
             }
             (FileType::File(_), FileType::Missing) => TemplateState::OnlySourceExists,
+            // e.g. a target that was symlinked before being made a template
+            (FileType::File(_), FileType::SymbolicLink(_)) => TemplateState::TargetIsSymlink,
             (FileType::Missing, FileType::Missing) => TemplateState::BothMissing,
             _ => TemplateState::TargetNotRegularFile,
         }
     }
 }
 
+/// `text` with CRLF line endings turned into LF.
+pub fn normalize_eols(text: &str) -> Cow<'_, str> {
+    match text.contains("\r\n") {
+        true => Cow::Owned(text.replace("\r\n", "\n")),
+        false => Cow::Borrowed(text),
+    }
+}
+
 impl Display for TemplateState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             TemplateState::Identical => "source and templated file contents are equal",
             TemplateState::OnlySourceExists => "templated file doesn't exist",
             TemplateState::Changed => "source contents were changed",
+            TemplateState::TargetIsSymlink => "target is a symlink",
             TemplateState::TargetNotRegularFile => "target already exists and isn't a regular file",
             TemplateState::BothMissing => "templated file and source are missing",
         }
@@ -109,15 +250,112 @@ mod tests {
         let mut handlebars = Handlebars::new();
         handlebars.register_template_string("template", "{{ name }}")?;
 
-        let variables = vec![("name".to_string(), "world".to_string())]
+        let variables = vec![("name".to_string(), "world".into())]
             .into_iter()
             .collect::<Variables>();
 
-        Template::render(&source_path, &target_path, &handlebars, &variables, false)?;
+        Template::render(&source_path, &target_path, false, false, || {
+            Template::render_to_string(&source_path, &handlebars, &variables, false)
+        })?;
 
         let target = fs::read_to_string(&target_path)?;
         assert_eq!(target, "Hello, world!");
 
         Ok(())
     }
+
+    #[test]
+    fn should_render_list_variables() -> Result<()> {
+        let dir = TempDir::new("template")?;
+
+        let source_path = dir.path().join("source.txt");
+        fs::write(&source_path, "{{#each plugins}}plugin {{this}}\n{{/each}}")?;
+        let target_path = dir.path().join("target.txt");
+
+        let plugins = serde_yaml::from_str("[fzf, zoxide]")?;
+        let variables = vec![("plugins".to_string(), plugins)]
+            .into_iter()
+            .collect::<Variables>();
+
+        Template::render(&source_path, &target_path, false, false, || {
+            Template::render_to_string(&source_path, &Handlebars::new(), &variables, false)
+        })?;
+
+        assert_eq!(
+            fs::read_to_string(&target_path)?,
+            "plugin fzf\nplugin zoxide\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_render_bytes_around_invalid_utf8() -> Result<()> {
+        let dir = TempDir::new("template")?;
+
+        let source_path = dir.path().join("source.bin");
+        fs::write(&source_path, b"Hello, {{ name }}!\xff\x00{{ name }}")?;
+        let target_path = dir.path().join("target.bin");
+
+        let variables = vec![("name".to_string(), "world".into())]
+            .into_iter()
+            .collect::<Variables>();
+
+        Template::render_bytes(&target_path, false, || {
+            Template::render_bytes_to_vec(&source_path, &Handlebars::new(), &variables, false)
+        })?;
+
+        assert_eq!(fs::read(&target_path)?, b"Hello, world!\xff\x00world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_ignore_line_endings_when_normalizing() {
+        let source = || FileType::File(Some("a = 1\nb = 2\n".to_owned()));
+        let target = || FileType::File(Some("a = 1\r\nb = 2\r\n".to_owned()));
+
+        assert!(matches!(
+            TemplateState::from(source(), target(), true),
+            TemplateState::Identical
+        ));
+        assert!(matches!(
+            TemplateState::from(source(), target(), false),
+            TemplateState::Changed
+        ));
+        let other = FileType::File(Some("a = 1\r\nb = 3\r\n".to_owned()));
+        assert!(matches!(
+            TemplateState::from(source(), other, true),
+            TemplateState::Changed
+        ));
+    }
+
+    #[test]
+    fn should_replace_symlink_target_when_forced() -> Result<()> {
+        let dir = TempDir::new("template")?;
+
+        let source_path = dir.path().join("source.txt");
+        fs::write(&source_path, "Hello, {{ name }}!")?;
+        let target_path = dir.path().join("target.txt");
+        std::os::unix::fs::symlink(&source_path, &target_path)?;
+
+        let variables = vec![("name".to_string(), "world".into())]
+            .into_iter()
+            .collect::<Variables>();
+        let render = |force| {
+            Template::render(&source_path, &target_path, force, false, || {
+                Template::render_to_string(&source_path, &Handlebars::new(), &variables, false)
+            })
+        };
+
+        assert_eq!(render(false)?.outcome, Outcome::Skipped);
+        assert!(target_path.is_symlink());
+
+        assert_eq!(render(true)?.outcome, Outcome::Updated);
+        assert!(!target_path.is_symlink());
+        assert_eq!(fs::read_to_string(&target_path)?, "Hello, world!");
+        assert_eq!(fs::read_to_string(&source_path)?, "Hello, {{ name }}!");
+
+        Ok(())
+    }
 }